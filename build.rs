@@ -1,31 +1,62 @@
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use reqwest::blocking::get;
+use sha2::{Digest, Sha256};
+
+/// Directory with the binaries (named like the release files, e.g. `ttyd.x86_64`) to embed
+/// instead of downloading them.
+const BINARIES_DIR_VAR: &str = "KITTENGRID_BINARIES_DIR";
+
+/// Binary embedded in the agent, `url` is where the release `{file}` is downloaded from.
+struct Binary {
+    name: &'static str,
+    url: &'static str,
+}
+
+const BINARIES: &[Binary] = &[Binary {
+    name: "ttyd",
+    url: "https://github.com/tsl0922/ttyd/releases/download/1.7.7/{file}",
+}];
 
 fn main() {
     let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
-    // Define the download URLs for each binary
-    let binaries = [(
-        "ttyd",
-        format!(
-            "https://github.com/tsl0922/ttyd/releases/download/1.7.7/ttyd.{}",
-            arch_to_file_arch(&arch)
-        ),
-    )];
+    for binary in BINARIES {
+        let file = format!("{}.{}", binary.name, arch_to_file_arch(&arch));
+
+        // A local copy of the binary can be given with KITTENGRID_<NAME>_PATH, or all of them
+        // with KITTENGRID_BINARIES_DIR, so builds work offline.
+        let path_var = format!("KITTENGRID_{}_PATH", binary.name.to_uppercase());
+        println!("cargo:rerun-if-env-changed={}", path_var);
+        let local_path = env::var(&path_var).map(PathBuf::from).ok().or_else(|| {
+            env::var(BINARIES_DIR_VAR)
+                .ok()
+                .map(|dir| Path::new(&dir).join(&file))
+        });
+        let data = match local_path {
+            Some(path) => {
+                println!("cargo:rerun-if-changed={}", path.display());
+                fs::read(&path)
+                    .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e))
+            }
+            None => {
+                let url = binary.url.replace("{file}", &file);
+                let response = get(&url).expect("Failed to download file");
+                response.bytes().expect("Failed to read file").to_vec()
+            }
+        };
 
-    for (bin_name, url) in binaries.iter() {
-        let response = get(url).expect("Failed to download file");
-        let response_data = response.bytes().expect("Failed to read file");
-        let dest_bin_path = out_dir.join(bin_name);
+        let checksum = sha256(&data);
+
+        let dest_bin_path = out_dir.join(binary.name);
 
         // write the binary to a temporary file
         fs::create_dir_all(&out_dir).expect("Failed to create output directory");
-        fs::write(&dest_bin_path, &response_data).expect("Failed to write binary");
+        fs::write(&dest_bin_path, &data).expect("Failed to write binary");
 
         // Ensure it's executable (only needed on Unix)
         #[cfg(unix)]
@@ -37,20 +68,36 @@ fn main() {
                 .expect("Failed to make binary executable");
         }
 
-        // Set environment variables to reference the extracted binary
+        // Set environment variables to reference the extracted binary and its checksum,
+        // verified again when it is installed.
         println!(
             "cargo:rustc-env={}={}",
-            bin_name.to_uppercase(),
+            binary.name.to_uppercase(),
             dest_bin_path.display()
         );
+        println!(
+            "cargo:rustc-env={}_SHA256={}",
+            binary.name.to_uppercase(),
+            checksum
+        );
     }
 
+    println!("cargo:rerun-if-env-changed={}", BINARIES_DIR_VAR);
     println!("cargo:rerun-if-changed=build.rs");
 }
 
+/// Names of the release files of each architecture.
 fn arch_to_file_arch(arch: &str) -> &str {
     match arch {
-        "x86_64" => "i686",
+        "x86" => "i686",
+        "arm" => "armhf",
         other => other,
     }
 }
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
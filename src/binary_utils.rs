@@ -1,21 +1,142 @@
-use std::env;
+use sha2::{Digest, Sha256};
 use std::fs;
+use std::io;
 use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
 
-pub fn install_binary(binary_bytes: &[u8]) -> String {
-    // Get a temporary file path
-    let temp_dir = env::temp_dir();
+/// Binary embedded in the agent at build time, with the checksum build.rs computed for it.
+#[derive(Debug)]
+pub struct EmbeddedBinary {
+    pub name: &'static str,
+    pub bytes: &'static [u8],
+    pub sha256: &'static str,
+}
+
+pub const TTYD: EmbeddedBinary = EmbeddedBinary {
+    name: "ttyd",
+    bytes: include_bytes!(env!("TTYD")),
+    sha256: env!("TTYD_SHA256"),
+};
+
+#[derive(Debug, Error)]
+pub enum BinaryError {
+    #[error("Data dir error: {0}")]
+    DataDirError(#[from] crate::data_dir::DataDirError),
+
+    #[error("IoError: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("Checksum mismatch for {name}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// Installs the binary into the bin directory of the data dir, unless it is already
+/// there, and returns its path. The checksum of the installed binary is always verified.
+pub fn install_binary(binary: &EmbeddedBinary) -> Result<PathBuf, BinaryError> {
+    install_binary_into(binary, &crate::data_dir::get_data_dir().bin_path()?)
+}
+
+fn install_binary_into(binary: &EmbeddedBinary, dir: &Path) -> Result<PathBuf, BinaryError> {
+    verify(binary.name, binary.bytes, binary.sha256)?;
+
+    let bin_path = dir.join(binary.name);
+    let installed = match fs::read(&bin_path) {
+        Ok(bytes) => verify(binary.name, &bytes, binary.sha256).is_ok(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e.into()),
+    };
+
+    if !installed {
+        // Written next to it and renamed, so it is never seen half written (it may be running).
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        io::Write::write_all(&mut file, binary.bytes)?;
+        file.as_file()
+            .set_permissions(fs::Permissions::from_mode(0o755))?;
+        file.persist(&bin_path).map_err(|e| e.error)?;
+    }
+
+    // Catches changes made to the file between the install and now.
+    verify(binary.name, &fs::read(&bin_path)?, binary.sha256)?;
+    Ok(bin_path)
+}
+
+fn verify(name: &str, bytes: &[u8], expected: &str) -> Result<(), BinaryError> {
+    let actual = sha256(bytes);
+    if actual != expected {
+        return Err(BinaryError::ChecksumMismatch {
+            name: name.to_string(),
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HELLO_SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn install() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = EmbeddedBinary {
+            name: "hello",
+            bytes: b"hello\n",
+            sha256: HELLO_SHA256,
+        };
+
+        let path = install_binary_into(&binary, dir.path()).unwrap();
+        assert_eq!(path, dir.path().join("hello"));
+        assert_eq!(fs::read(&path).unwrap(), b"hello\n");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+
+        // Installed once.
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        install_binary_into(&binary, dir.path()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+
+        // Modified binaries are replaced.
+        fs::write(&path, b"tampered\n").unwrap();
+        install_binary_into(&binary, dir.path()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"hello\n");
+    }
 
-    // random file name
-    let bin_path = temp_dir.join(format!("agent_bin_{}", uuid::Uuid::new_v4()));
+    #[test]
+    fn checksum_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = EmbeddedBinary {
+            name: "hello",
+            bytes: b"bye\n",
+            sha256: HELLO_SHA256,
+        };
 
-    // Write bytes to file
-    fs::write(&bin_path, binary_bytes).expect("Failed to write binary file");
+        assert!(matches!(
+            install_binary_into(&binary, dir.path()),
+            Err(BinaryError::ChecksumMismatch { .. })
+        ));
+        assert!(!dir.path().join("hello").exists());
+    }
 
-    let mut perms = fs::metadata(&bin_path)
-        .expect("Failed to read metadata")
-        .permissions();
-    perms.set_mode(0o755);
-    fs::set_permissions(&bin_path, perms).expect("Failed to set execute permissions");
-    bin_path.to_str().unwrap().to_string()
+    #[test]
+    fn embedded_binaries() {
+        verify(TTYD.name, TTYD.bytes, TTYD.sha256).unwrap();
+    }
 }
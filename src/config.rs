@@ -8,6 +8,15 @@ use serde::{Deserialize, Serialize};
 use once_cell::sync::Lazy;
 use std::{collections::HashMap, fs::File, io::BufReader};
 
+/// Shell of the terminal when neither `terminal.command` nor `$SHELL` are set.
+const DEFAULT_SHELL: &str = "/bin/bash";
+
+const DEFAULT_MTU: u32 = 1384;
+const DEFAULT_LISTEN_PORT: u16 = 51820;
+const DEFAULT_PERSISTENT_KEEPALIVE: u16 = 5;
+const DEFAULT_INTERFACE_PREFIX: &str = "kgwg";
+const DEFAULT_STARTUP_ATTEMPTS: u32 = 20;
+
 // Returns a reference to a lazily created Config object.
 // TODO: FIX TESTS ARGUMENTS
 static CONFIG: Lazy<Config> = Lazy::new(|| {
@@ -57,6 +66,15 @@ fn process_args(args: &mut Args) -> Config {
     config
 }
 
+/// Reads the config file at `path` the same way the agent does, without arguments.
+#[cfg(test)]
+pub fn load(path: Option<std::path::PathBuf>) -> Config {
+    process_args(&mut Args {
+        config_path: path,
+        config: <Config as ClapSerde>::Opt::default(),
+    })
+}
+
 pub fn get_config() -> &'static Config {
     &CONFIG
 }
@@ -83,7 +101,13 @@ impl Config {
             self.work_directory = "/var/lib/kittengrid-agent".to_string();
         }
         if self.bind_address.is_empty() {
-            self.bind_address = "0.0.0.0".to_string();
+            self.bind_address = "::".to_string();
+        }
+        if self.tunnel.is_empty() {
+            self.tunnel = "kernel".to_string();
+        }
+        if self.jwks_url.is_empty() && !self.api_url.is_empty() {
+            self.jwks_url = format!("{}/.well-known/jwks.json", self.api_url);
         }
         self
     }
@@ -102,7 +126,8 @@ pub struct Config {
     #[arg(short, long, env("KITTENGRID_WORK_DIR"))]
     pub work_directory: String,
 
-    /// Bind address for the agent. [default: 0.0.0.0]
+    /// Bind address for the agent, the default one accepts IPv4 and IPv6 connections (IPv4
+    /// only on hosts without IPv6). [default: ::]
     #[arg(long, env("KITTENGRID_BIND_ADDRESS"))]
     pub bind_address: String,
 
@@ -139,8 +164,188 @@ pub struct Config {
     #[arg(long, env("KITTENGRID_START_TERMINAL"))]
     pub start_terminal: bool,
 
+    /// URL of the JWKS (JSON Web Key Set) used to verify tokens signed with asymmetric keys
+    /// (RS256, ES256, EdDSA...) [default: <api_url>/.well-known/jwks.json]
+    #[arg(long, env("KITTENGRID_JWKS_URL"))]
+    pub jwks_url: String,
+
+    /// Public keys (PEM files or PEM contents) accepted to verify tokens, apart from the ones in the JWKS.
+    #[arg(long, env("KITTENGRID_JWT_PUBLIC_KEYS"), value_delimiter = ',')]
+    pub jwt_public_keys: Vec<String>,
+
+    /// Accept tokens signed (HS256) with the api key [default: false]
+    #[arg(long, env("KITTENGRID_JWT_ALLOW_HS256"))]
+    pub jwt_allow_hs256: bool,
+
+    /// Source networks (CIDRs) allowed to call the /sys endpoints, any source when empty.
+    #[arg(long, env("KITTENGRID_SYS_ALLOWED_CIDRS"), value_delimiter = ',')]
+    pub sys_allowed_cidrs: Vec<String>,
+
+    /// Backend of the tunnels to kittengrid: kernel (WireGuard kernel module), userspace
+    /// (WireGuard over a TUN device) or none (local development) [default: kernel]
+    #[arg(long, env("KITTENGRID_TUNNEL"))]
+    pub tunnel: String,
+
     #[clap(skip)]
     pub services: Vec<ServiceConfig>,
+
+    #[clap(skip)]
+    pub terminal: TerminalConfig,
+
+    #[clap(skip)]
+    pub network: NetworkConfig,
+
+    #[clap(skip)]
+    pub proxy: ProxyConfig,
+
+    #[clap(skip)]
+    pub api_client: ApiClientConfig,
+}
+
+/// Timeouts and retries of the calls to the kittengrid api, see `kittengrid_api::RetryPolicy`.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ApiClientConfig {
+    /// Seconds to wait for the response of a call, defaults to 30.
+    pub timeout: Option<u64>,
+    /// Seconds to wait for the connection to the api, defaults to 10.
+    pub connect_timeout: Option<u64>,
+    /// Attempts of a call before giving up, defaults to 5.
+    pub max_attempts: Option<u32>,
+    /// Milliseconds to wait before the first retry, doubled on every retry. Defaults to 500.
+    pub initial_backoff_ms: Option<u64>,
+    /// Maximum milliseconds to wait between attempts, `Retry-After` included. Defaults to
+    /// 30000.
+    pub max_backoff_ms: Option<u64>,
+    /// Attempts to register the agent and publish its services at startup while the api
+    /// is unavailable, before exiting. Defaults to 20.
+    pub startup_attempts: Option<u32>,
+}
+
+impl ApiClientConfig {
+    pub fn startup_attempts(&self) -> u32 {
+        self.startup_attempts
+            .unwrap_or(DEFAULT_STARTUP_ATTEMPTS)
+            .max(1)
+    }
+}
+
+/// Reverse proxy of the agent, it routes the requests that don't match an agent endpoint
+/// to the services, so only the agent port needs to be reachable through the tunnels.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ProxyConfig {
+    /// Defaults to false, services are registered with their own port.
+    pub enabled: Option<bool>,
+    /// Seconds without proxied requests after which a service is stopped until the next
+    /// request for it comes in, services can override it. Disabled by default.
+    pub idle_timeout: Option<u64>,
+    /// Seconds a request for a sleeping service waits for it to be healthy again, defaults
+    /// to 120.
+    pub wake_timeout: Option<u64>,
+}
+
+impl ProxyConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(false)
+    }
+
+    pub fn wake_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.wake_timeout.unwrap_or(120))
+    }
+}
+
+/// Parameters of the WireGuard interfaces of the tunnels, the api can override them (but
+/// the prefix) for each peer.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct NetworkConfig {
+    /// Defaults to 1384.
+    pub mtu: Option<u32>,
+    /// Listen port of the first interface, the next ones use the following ports. Defaults
+    /// to 51820.
+    pub listen_port: Option<u16>,
+    /// Seconds between keepalive packets to the peer, 0 disables them. Defaults to 5.
+    pub persistent_keepalive: Option<u16>,
+    /// Mark of the tunnel traffic, defguard picks a free routing table (and mark) when
+    /// it is not set.
+    pub fwmark: Option<u32>,
+    /// Interfaces are named `<prefix><index>`, defaults to `kgwg`. Prefixes of other tools
+    /// (e.g. `wg`) are refused. The interfaces created by the agent are recorded in the data
+    /// dir, and the ones left behind are removed on start.
+    pub interface_prefix: Option<String>,
+    /// Saves the private key generated by the agent in the data dir, so it is kept across
+    /// restarts. Defaults to false, it is only kept in memory.
+    pub persist_private_key: Option<bool>,
+}
+
+impl NetworkConfig {
+    pub fn mtu(&self) -> u32 {
+        self.mtu.unwrap_or(DEFAULT_MTU)
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port.unwrap_or(DEFAULT_LISTEN_PORT)
+    }
+
+    pub fn persistent_keepalive(&self) -> u16 {
+        self.persistent_keepalive
+            .unwrap_or(DEFAULT_PERSISTENT_KEEPALIVE)
+    }
+
+    pub fn persist_private_key(&self) -> bool {
+        self.persist_private_key.unwrap_or(false)
+    }
+
+    pub fn interface_prefix(&self) -> String {
+        self.interface_prefix
+            .clone()
+            .unwrap_or_else(|| DEFAULT_INTERFACE_PREFIX.to_string())
+    }
+}
+
+/// Shell started for every session of the native terminal (`GET /public/terminal`) and by
+/// ttyd (`--start-terminal`).
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct TerminalConfig {
+    /// Defaults to `$SHELL`, or `/bin/bash` if it is not set.
+    pub command: Option<String>,
+    pub args: Option<Vec<String>>,
+    /// When false clients can only watch the terminal, their input is ignored. Defaults to true.
+    pub writable: Option<bool>,
+    /// Directory the shell is started in, defaults to the repositories directory of the work directory.
+    pub cwd: Option<String>,
+    /// Added to the environment of the agent.
+    pub env: Option<HashMap<String, String>>,
+    /// Seconds without input after which sessions are closed, they are never closed by default.
+    pub idle_timeout: Option<u64>,
+    /// `user:password` required by ttyd (basic authentication), none by default.
+    pub credential: Option<String>,
+    /// Days the recordings of the sessions are kept for, defaults to 30.
+    pub recordings_retention_days: Option<u64>,
+    /// Maximum number of recordings kept, the oldest ones are deleted first. Defaults to 100.
+    pub max_recordings: Option<usize>,
+}
+
+impl TerminalConfig {
+    pub fn shell(&self) -> String {
+        self.command
+            .clone()
+            .or_else(|| std::env::var("SHELL").ok())
+            .unwrap_or_else(|| DEFAULT_SHELL.to_string())
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable.unwrap_or(true)
+    }
+
+    pub fn cwd(&self) -> Option<std::path::PathBuf> {
+        match &self.cwd {
+            Some(cwd) => Some(cwd.into()),
+            None => crate::data_dir::get_data_dir().repos_path().ok(),
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Option<std::time::Duration> {
+        self.idle_timeout.map(std::time::Duration::from_secs)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -151,6 +356,46 @@ pub struct ServiceConfig {
     pub env: Option<HashMap<String, String>>,
     pub args: Option<Vec<String>>,
     pub health_check: Option<HealthCheck>,
+    pub watch: Option<WatchConfig>,
+    /// Seconds to wait for the service to exit after sending it a TERM signal before killing it.
+    pub stop_grace_period: Option<u64>,
+    /// Pipes the stdin of the service so clients can write to it (see the attach endpoint).
+    #[serde(default)]
+    pub stdin: bool,
+    /// Runs the service in a pseudo terminal, stdout and stderr are merged into stdout.
+    #[serde(default)]
+    pub tty: bool,
+    /// How the reverse proxy routes requests to the service, when it is enabled.
+    pub proxy: Option<ServiceProxyConfig>,
+}
+
+/// Requests are routed to the service by `Host` header and/or path prefix. Services
+/// without any are routed by the `/<name>` prefix.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ServiceProxyConfig {
+    /// Host (without port) of the requests routed to the service.
+    pub host: Option<String>,
+    /// Path under which requests are routed to the service.
+    pub path_prefix: Option<String>,
+    /// Removes the prefix from the path of the requests sent to the service, defaults to true.
+    pub strip_prefix: Option<bool>,
+    /// Overrides `proxy.idle_timeout` for the service, 0 keeps it always running.
+    pub idle_timeout: Option<u64>,
+    /// Captures the requests proxied to the service, so they can be inspected and replayed.
+    pub capture: Option<CaptureConfig>,
+}
+
+/// Captured requests are kept in the data dir, the oldest ones are deleted.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct CaptureConfig {
+    /// Requests kept, defaults to 100.
+    pub max_requests: Option<usize>,
+    /// Bytes of the body kept for every request, defaults to 65536.
+    pub max_body_size: Option<usize>,
+    /// Headers and query parameters whose values are not kept, in addition to the
+    /// `authorization`, `proxy-authorization` and `cookie` headers and the parameters
+    /// usually carrying credentials (`token`, `access_token`, `api_key`...).
+    pub redact_headers: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -161,6 +406,16 @@ pub struct HealthCheck {
     pub path: String,
 }
 
+/// Restarts the service whenever a file under `paths` changes.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct WatchConfig {
+    pub paths: Vec<String>,
+    /// Glob patterns for paths that should not trigger a restart.
+    pub ignore: Option<Vec<String>>,
+    /// Time to wait for the changes to settle before restarting, defaults to 500ms.
+    pub debounce_ms: Option<u64>,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -176,7 +431,7 @@ mod test {
         let mut args = test_args();
         let config = process_args(&mut args);
         assert_eq!(config.log_level, "info");
-        assert_eq!(config.bind_address, "0.0.0.0");
+        assert_eq!(config.bind_address, "::");
     }
 
     #[test]
@@ -185,7 +440,7 @@ mod test {
         args.config.log_level = Some("debug".to_string());
         let config = process_args(&mut args);
         assert_eq!(config.log_level, "debug");
-        assert_eq!(config.bind_address, "0.0.0.0");
+        assert_eq!(config.bind_address, "::");
     }
 
     #[test]
@@ -196,6 +451,19 @@ mod test {
         assert_eq!(config.project_vcs_path, "123445");
     }
 
+    #[test]
+    fn jwt_defaults() {
+        let mut args = test_args();
+        let config = process_args(&mut args);
+        assert_eq!(config.jwks_url, "http://web:3000/.well-known/jwks.json");
+        assert!(config.jwt_public_keys.is_empty());
+
+        let mut args = test_args();
+        args.config.jwks_url = Some("http://keys/jwks.json".to_string());
+        let config = process_args(&mut args);
+        assert_eq!(config.jwks_url, "http://keys/jwks.json");
+    }
+
     #[test]
     fn overwrite_test_file_over_defaults() {
         let mut args = test_args();
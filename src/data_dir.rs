@@ -121,10 +121,56 @@ impl DataDir {
 
         Ok(self.path.join("repos"))
     }
+
+    /// Returns the directory of the state dir where terminal sessions are recorded
+    pub fn recordings_path(&self) -> Result<std::path::PathBuf, DataDirError> {
+        if !self.initialized {
+            return Err(DataDirError::DirectoryNotInitialized);
+        }
+
+        Ok(self.path.join("recordings"))
+    }
+
+    /// Returns the directory of the state dir where the keys of the agent are saved
+    pub fn keys_path(&self) -> Result<std::path::PathBuf, DataDirError> {
+        if !self.initialized {
+            return Err(DataDirError::DirectoryNotInitialized);
+        }
+
+        Ok(self.path.join("keys"))
+    }
+
+    /// Returns the directory of the state dir where the requests proxied to the services
+    /// are captured
+    pub fn captures_path(&self) -> Result<std::path::PathBuf, DataDirError> {
+        if !self.initialized {
+            return Err(DataDirError::DirectoryNotInitialized);
+        }
+
+        Ok(self.path.join("captures"))
+    }
+
+    /// Returns the directory of the state dir where the network interfaces created by the
+    /// agent are recorded
+    pub fn interfaces_path(&self) -> Result<std::path::PathBuf, DataDirError> {
+        if !self.initialized {
+            return Err(DataDirError::DirectoryNotInitialized);
+        }
+
+        Ok(self.path.join("interfaces"))
+    }
 }
 
 fn build_directory_structure(path: &Path) -> Result<(), DataDirInitError> {
-    let paths = vec!["bin", "repos", "work"];
+    let paths = vec![
+        "bin",
+        "repos",
+        "work",
+        "recordings",
+        "keys",
+        "captures",
+        "interfaces",
+    ];
     let mut temp_builder = fs::DirBuilder::new();
     let builder = temp_builder.recursive(true);
 
@@ -192,4 +238,20 @@ mod test {
             dir.path().join("bin").to_str().unwrap()
         );
     }
+
+    #[test]
+    fn recordings() {
+        let dir = tempdir().unwrap();
+        let mut data_dir = DataDir::new(dir.path().to_path_buf());
+        assert_eq!(
+            data_dir.recordings_path(),
+            Err(DataDirError::DirectoryNotInitialized)
+        );
+        data_dir.init().unwrap();
+        assert_eq!(
+            data_dir.recordings_path().unwrap(),
+            dir.path().join("recordings")
+        );
+        assert!(dir.path().join("recordings").is_dir());
+    }
 }
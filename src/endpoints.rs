@@ -1,2 +1,5 @@
+pub mod auth;
+pub mod proxy;
 pub mod public;
 pub mod sys;
+pub mod tickets;
@@ -0,0 +1,44 @@
+use crate::AxumState;
+
+use axum::{
+    extract::{connect_info::ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Fallback of the router
+///
+/// Description: Requests that don't match an endpoint of the agent are sent to the services
+/// by the reverse proxy, when it is enabled (see `proxy` in the configuration). 404 if it
+/// is disabled or no service matches.
+pub async fn proxy(
+    State(state): State<Arc<AxumState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+) -> Response {
+    match &state.proxy {
+        Some(routes) => routes.handle(addr, request).await,
+        None => (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response(),
+    }
+}
+
+/// Middleware of the router
+///
+/// Description: Requests for the host of a service (see `proxy.host` of the services) are
+/// sent to it before the endpoints of the agent are matched, so its `/public/` and `/sys/`
+/// paths reach the service too.
+pub async fn route_by_host(
+    State(state): State<Arc<AxumState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match &state.proxy {
+        Some(routes) if routes.routes_host(&request) => routes.handle(addr, request).await,
+        _ => next.run(request).await,
+    }
+}
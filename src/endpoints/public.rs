@@ -1 +1,3 @@
+pub mod events;
 pub mod services;
+pub mod terminal;
@@ -0,0 +1,180 @@
+use crate::endpoints::auth::{validate_token, AuthError, Scope};
+use crate::endpoints::public::services::{
+    close_frame, stream_credentials, OutputStreamParams, StreamCredentials,
+};
+use crate::events::Events;
+use crate::shutdown::Shutdown;
+use crate::AxumState;
+
+use axum::extract::connect_info::ConnectInfo;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Debug, Deserialize)]
+pub struct EventsParams {
+    pub token: Option<String>,
+}
+
+/// GET /public/events
+///
+/// Description: Streams the events of the agent as JSON text frames, from the moment the
+/// websocket is opened. The token needs the `services:read` scope and is sent in the
+/// `token` query param or as a `kittengrid.token.<token>` websocket subprotocol.
+///
+/// Event example:
+/// {
+///    "type" : "tunnel_state_changed",
+///    "tunnel" : "kgwg0",
+///    "network" : "10.0.0.0/24",
+///    "state" : "stale"
+/// }
+///
+/// Tunnel states are `connecting`, `up` and `stale`.
+pub async fn events(
+    Query(params): Query<EventsParams>,
+    headers: HeaderMap,
+    State(state): State<Arc<AxumState>>,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Response {
+    let credentials = stream_credentials(
+        OutputStreamParams {
+            ticket: None,
+            token: params.token,
+        },
+        &headers,
+    );
+    let (token, protocol) = match credentials {
+        Some((StreamCredentials::Token(token), protocol)) => (token, protocol),
+        // Tickets are bound to services.
+        Some((StreamCredentials::Ticket(_), _)) => return AuthError::InvalidTicket.into_response(),
+        None => return AuthError::InvalidToken.into_response(),
+    };
+    let claims = match validate_token(&token).await {
+        Ok(claims) => claims,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = claims.require_scope(Scope::ServicesRead) {
+        return e.into_response();
+    }
+
+    let ws = match protocol {
+        Some(protocol) => ws.protocols([protocol]),
+        None => ws,
+    };
+
+    let events = state.events.clone();
+    let shutdown = state.shutdown.clone();
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, events, shutdown))
+        .into_response()
+}
+
+/// Actual websocket statemachine (one will be spawned per connection)
+async fn handle_socket(
+    mut socket: WebSocket,
+    address: SocketAddr,
+    events: Events,
+    shutdown: Shutdown,
+) {
+    info!("Events stream opened from {address}");
+    let mut receiver = events.subscribe();
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Events stream of {address} lagging, {skipped} events skipped");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let message = match serde_json::to_string(&event) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        error!("Could not serialize event {:?}: {e}", event);
+                        continue;
+                    }
+                };
+                if socket.send(Message::text(message)).await.is_err() {
+                    debug!("Could not send event to {address}!");
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                match message {
+                    // Pings are answered by axum, anything else sent by the client is ignored.
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                }
+            }
+            _ = shutdown.triggered() => break,
+        }
+    }
+
+    info!("Events stream of {address} closed.");
+    if let Err(e) = socket
+        .send(Message::Close(Some(close_frame(&shutdown))))
+        .await
+    {
+        debug!("Could not send close to {address}! {e}");
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::events::Event;
+    use crate::test_utils::*;
+    use crate::tunnel::TunnelState;
+
+    use futures_util::StreamExt;
+    use tokio_tungstenite::connect_async;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn events() {
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        let url = |token: &str| {
+            server_test.url_for_with_protocol("ws", &format!("/public/events?token={token}"))
+        };
+
+        let (mut ws_stream, _) = connect_async(url(&server_test.valid_token()))
+            .await
+            .unwrap();
+        // The subscription happens once upgraded, give it a moment.
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        server_test.events().send(Event::TunnelStateChanged {
+            tunnel: "kgwg0".to_string(),
+            network: "10.0.0.0/24".to_string(),
+            state: TunnelState::Stale,
+        });
+
+        let message = ws_stream.next().await.unwrap().unwrap();
+        let event: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "type": "tunnel_state_changed",
+                "tunnel": "kgwg0",
+                "network": "10.0.0.0/24",
+                "state": "stale",
+            })
+        );
+
+        // Requires the services:read scope.
+        let token = server_test.token(ServerTest::an_hour_from_now(), &["logs:read"], None);
+        assert!(connect_async(url(&token)).await.is_err());
+    }
+}
@@ -1,27 +1,24 @@
-use crate::service::{ServiceStream, Services};
+use crate::endpoints::auth::{validate_token, AuthError, Claims, Scope};
+use crate::endpoints::tickets::{OutputStream, STREAM_TICKET_TTL};
+use crate::pty::WindowSize;
+use crate::service::{AttachError, ServiceInfo, ServiceStream, Services, StdinWriter};
+use crate::shutdown::Shutdown;
 use crate::AxumState;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use axum::RequestPartsExt;
-
 use axum::{
     body::Body,
     extract::{
         rejection::PathRejection,
         ws::{Message, WebSocket, WebSocketUpgrade},
-        FromRequestParts, Path, Query, State,
+        Path, Query, State,
     },
-    http::{request::Parts, StatusCode},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Json, Response},
 };
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
-};
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use axum_extra::extract::WithRejection;
 use log::{debug, error, info};
-use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::json;
 use std::sync::Arc;
 
@@ -30,9 +27,16 @@ use std::net::SocketAddr;
 //allows to extract the IP of connecting user
 use axum::extract::connect_info::ConnectInfo;
 use axum::extract::ws::CloseFrame;
+#[derive(Debug, Deserialize)]
+pub struct IndexParams {
+    pub name: Option<String>,
+    pub status: Option<String>,
+}
+
 /// GET /services
 ///
-/// Description: Shows all services
+/// Description: Shows all services, optionally filtered by `name` and/or `status`
+/// (`Running`, `Stopped` or `Sleeping`, case insensitive) query parameters.
 ///
 /// Response example:
 /// [
@@ -48,49 +52,113 @@ use axum::extract::ws::CloseFrame;
 ///          "env" : {},
 ///          "health_check" : null,
 ///          "name" : "test",
-///          "port" : 8080
+///          "port" : 8080,
+///          "watch" : null
 ///       },
+///       "health" : "healthy",
 ///       "id" : "bbfc62db-eae5-4d8f-ae3a-20e267ac4e76",
-///       "status" : "Stopped"
+///       "last_exit_code" : null,
+///       "log_history_bytes" : {
+///          "stderr" : 4221,
+///          "stdout" : 4221
+///       },
+///       "pid" : 4242,
+///       "public_url" : "https://test.kittengrid.com",
+///       "restart_count" : 0,
+///       "status" : "Running",
+///       "subscribers" : {
+///          "stderr" : 0,
+///          "stdout" : 1
+///       },
+///       "uptime" : 42
 ///    }
 /// ]
-pub async fn index(_claims: Claims, State(state): State<Arc<AxumState>>) -> impl IntoResponse {
+pub async fn index(
+    claims: Claims,
+    Query(params): Query<IndexParams>,
+    State(state): State<Arc<AxumState>>,
+) -> Response {
+    if let Err(e) = claims.require_scope(Scope::ServicesRead) {
+        return e.into_response();
+    }
+
+    let services = state.services.clone();
+    let infos: Vec<ServiceInfo> = services
+        .infos()
+        .await
+        .into_iter()
+        .filter(|info| claims.allows_service(info.id, &info.description.name()))
+        .filter(|info| match &params.name {
+            Some(name) => info.description.name() == *name,
+            None => true,
+        })
+        .filter(|info| match &params.status {
+            Some(status) => info.status.to_string().eq_ignore_ascii_case(status),
+            None => true,
+        })
+        .collect();
+
+    Json(infos).into_response()
+}
+
+/// GET /public/services/:id
+///
+/// Description: Shows a single service by its id or name (404  if not found),
+/// using the same format as the index.
+pub async fn show(
+    claims: Claims,
+    path: Result<Path<String>, PathRejection>,
+    State(state): State<Arc<AxumState>>,
+) -> Response {
     let services = state.services.clone();
-    Json(services.to_json().await["services"].clone())
+    let id = match find_authorized_service(path, &services, &claims, Scope::ServicesRead).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    match services.info(id).await {
+        Some(info) => Json(info).into_response(),
+        None => not_found_response(),
+    }
 }
 
 /// POST /public/services/:id/start
 ///
-/// Description: Starts the service by its id (404  if not found)
+/// Description: Starts the service by its id or name (404  if not found)
 pub async fn start(
-    _claims: Claims,
-    path: Result<Path<uuid::Uuid>, PathRejection>,
+    claims: Claims,
+    path: Result<Path<String>, PathRejection>,
     State(state): State<Arc<AxumState>>,
 ) -> Response {
     let services = state.services.clone();
-    let id = match find_service(path, &services).await {
+    let id = match find_authorized_service(path, &services, &claims, Scope::ServicesControl).await {
         Ok(id) => id,
         Err(response) => return response,
     };
 
     match services.start_service(id).await {
-        Ok(_) => ok_response(),
+        Ok(_) => {
+            if let Some(proxy) = &state.proxy {
+                proxy.started(id).await;
+            }
+            ok_response()
+        }
         Err(e) => error_response(Box::new(e)),
     }
 }
 
 /// POST /public/services/:id/stop
 ///
-/// Description: Stops the service by its id (404  if not found)
+/// Description: Stops the service by its id or name (404  if not found)
 #[axum::debug_handler]
 pub async fn stop(
-    _claims: Claims,
-    path: Result<Path<uuid::Uuid>, PathRejection>,
+    claims: Claims,
+    path: Result<Path<String>, PathRejection>,
     State(state): State<Arc<AxumState>>,
 ) -> Response {
     let services = state.services.clone();
 
-    let id = match find_service(path, &services).await {
+    let id = match find_authorized_service(path, &services, &claims, Scope::ServicesControl).await {
         Ok(id) => id,
         Err(response) => return response,
     };
@@ -101,35 +169,159 @@ pub async fn stop(
     }
 }
 
-/// GET /public/services/:id/stdout
+/// GET /public/services/:id/requests
 ///
-/// Description: Connects to the stdout of the service by its id (404  if not found)
-pub async fn stdout(
-    Query(params): Query<OutputStreamParams>,
-    path: Result<Path<uuid::Uuid>, PathRejection>,
+/// Description: Lists the requests the reverse proxy captured for the service by its id or
+/// name, newest first (404 if not found or if its requests are not captured, see
+/// `capture` in the proxy configuration of the service):
+/// [
+///    {
+///       "id" : "0b7a3c9e-2f4d-4e61-9a8b-5c1d2e3f4a5b",
+///       "timestamp" : 1700000000000,
+///       "client" : "10.0.0.1",
+///       "method" : "POST",
+///       "path" : "/app/users",
+///       "upstream_path" : "/users",
+///       "headers" : [["content-type", "application/json"], ["authorization", "[redacted]"]],
+///       "body" : "{\"name\": \"meow\"}",
+///       "body_encoding" : "utf8",
+///       "body_size" : 16,
+///       "body_truncated" : false,
+///       "status" : 201,
+///       "duration_ms" : 12
+///    }
+/// ]
+pub async fn requests(
+    claims: Claims,
+    path: Result<Path<String>, PathRejection>,
+    State(state): State<Arc<AxumState>>,
+) -> Response {
+    let services = state.services.clone();
+    let id = match find_authorized_service(path, &services, &claims, Scope::ServicesRead).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    match state.proxy.as_ref().and_then(|proxy| proxy.captures(id)) {
+        Some(captures) => Json(captures.list()).into_response(),
+        None => capture_disabled_response(),
+    }
+}
+
+/// POST /public/services/:id/requests/:request_id/replay
+///
+/// Description: Sends a captured request again to the service by its id or name, and
+/// responds with the response of the service (404 if either is not found, 409 if the
+/// body of the request was truncated). Redacted headers are not sent.
+pub async fn replay(
+    claims: Claims,
+    path: Result<Path<(String, String)>, PathRejection>,
     State(state): State<Arc<AxumState>>,
-    ws: WebSocketUpgrade,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
 ) -> Response {
+    let (path, request_id) = match path {
+        Ok(Path((id_or_name, request_id))) => (Ok(Path(id_or_name)), request_id),
+        Err(e) => (Err(e), String::new()),
+    };
     let services = state.services.clone();
+    let id = match find_authorized_service(path, &services, &claims, Scope::ServicesControl).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
 
-    match validate_token(&params.token) {
-        Ok(_) => (),
-        Err(response) => return response.into_response(),
+    let proxy = match state
+        .proxy
+        .as_ref()
+        .filter(|proxy| proxy.captures(id).is_some())
+    {
+        Some(proxy) => proxy,
+        None => return capture_disabled_response(),
+    };
+    let request_id = match uuid::Uuid::parse_str(&request_id) {
+        Ok(request_id) => request_id,
+        Err(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "Request not found"})),
+            )
+                .into_response()
+        }
+    };
+    match proxy.replay(id, request_id).await {
+        Ok(response) => response,
+        Err(e) => {
+            info!("Failed to replay request {}: {}", request_id, e);
+            e.into_response()
+        }
     }
+}
 
-    let id = match find_service(path, &services).await {
+/// POST /public/services/:id/stream_tickets
+///
+/// Description: Issues a one-time ticket to connect to an output stream of the service
+/// (`{"stream": "stdout" | "stderr" | "combined_output"}`). Tickets expire after a few
+/// seconds, they are meant to be used right away instead of the token when opening the
+/// websocket, either in the `ticket` query param or as a `kittengrid.ticket.<ticket>`
+/// websocket subprotocol.
+pub async fn stream_tickets(
+    claims: Claims,
+    path: Result<Path<String>, PathRejection>,
+    State(state): State<Arc<AxumState>>,
+    params: WithRejection<Json<StreamTicketParams>, crate::api_error::ApiError>,
+) -> Response {
+    let services = state.services.clone();
+    let stream = params.0.stream;
+    let id = match find_stream_service(path, &services, &claims, stream).await {
         Ok(id) => id,
         Err(response) => return response,
     };
 
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, id, services, ServiceStream::Stdout))
+    let ticket = state.tickets.issue(id, stream);
+    (
+        StatusCode::CREATED,
+        Json(json!({
+            "ticket": ticket,
+            "service_id": id,
+            "stream": stream,
+            "expires_in": STREAM_TICKET_TTL.as_secs(),
+        })),
+    )
         .into_response()
 }
 
+#[derive(Debug, Deserialize)]
+pub struct StreamTicketParams {
+    pub stream: OutputStream,
+}
+
+/// GET /public/services/:id/stdout
+///
+/// Description: Connects to the stdout of the service by its id or name (404  if not found)
+pub async fn stdout(
+    Query(params): Query<OutputStreamParams>,
+    headers: HeaderMap,
+    path: Result<Path<String>, PathRejection>,
+    State(state): State<Arc<AxumState>>,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Response {
+    let services = state.services.clone();
+
+    let (id, ws) =
+        match authorize_stream(params, &headers, path, &state, OutputStream::Stdout, ws).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+
+    let shutdown = state.shutdown.clone();
+    ws.on_upgrade(move |socket| {
+        handle_socket(socket, addr, id, services, ServiceStream::Stdout, shutdown)
+    })
+    .into_response()
+}
+
 /// GET /public/services/:id/combined_output
 ///
-/// Description: Connects to the stdout and stderr of the service by its id (404  if not found)
+/// Description: Connects to the stdout and stderr of the service by its id or name (404  if not found)
 /// it will stream the stdout and stderr to the client using a json structure of:
 /// {
 ///     "type": "stdout" | "stderr",
@@ -138,55 +330,245 @@ pub async fn stdout(
 /// }
 pub async fn combined_output(
     Query(params): Query<OutputStreamParams>,
-    path: Result<Path<uuid::Uuid>, PathRejection>,
+    headers: HeaderMap,
+    path: Result<Path<String>, PathRejection>,
     State(state): State<Arc<AxumState>>,
     ws: WebSocketUpgrade,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
 ) -> Response {
     let services = state.services.clone();
 
-    match validate_token(&params.token) {
-        Ok(_) => (),
-        Err(response) => return response.into_response(),
-    }
-
-    let id = match find_service(path, &services).await {
-        Ok(id) => id,
+    let (id, ws) = match authorize_stream(
+        params,
+        &headers,
+        path,
+        &state,
+        OutputStream::CombinedOutput,
+        ws,
+    )
+    .await
+    {
+        Ok(authorized) => authorized,
         Err(response) => return response,
     };
 
-    ws.on_upgrade(move |socket| handle_socket_combined(socket, addr, id, services))
+    let shutdown = state.shutdown.clone();
+    ws.on_upgrade(move |socket| handle_socket_combined(socket, addr, id, services, shutdown))
         .into_response()
 }
+
+/// GET /public/services/:id/access_log
+///
+/// Description: Connects to the access log of the service by its id or name (404  if not
+/// found), a JSON line per request the reverse proxy sent to the service:
+/// {
+///     "timestamp": 1700000000,
+///     "client": "10.0.0.1",
+///     "method": "GET",
+///     "path": "/users?page=2",
+///     "status": 200,
+///     "duration_ms": 12
+/// }
+pub async fn access_log(
+    Query(params): Query<OutputStreamParams>,
+    headers: HeaderMap,
+    path: Result<Path<String>, PathRejection>,
+    State(state): State<Arc<AxumState>>,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Response {
+    let services = state.services.clone();
+
+    let (id, ws) =
+        match authorize_stream(params, &headers, path, &state, OutputStream::AccessLog, ws).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+
+    let shutdown = state.shutdown.clone();
+    ws.on_upgrade(move |socket| {
+        handle_socket(
+            socket,
+            addr,
+            id,
+            services,
+            ServiceStream::AccessLog,
+            shutdown,
+        )
+    })
+    .into_response()
+}
+
+/// Credentials for the output websockets, either a stream ticket (preferred) or a token.
 #[derive(Debug, Deserialize)]
 pub struct OutputStreamParams {
-    pub token: String,
+    pub ticket: Option<String>,
+    pub token: Option<String>,
 }
 
 /// GET /public/services/:id/stderr
 ///
-/// Description: Connects to the stderr of the service by its id (404  if not found)
+/// Description: Connects to the stderr of the service by its id or name (404  if not found)
 pub async fn stderr(
     Query(params): Query<OutputStreamParams>,
-    path: Result<Path<uuid::Uuid>, PathRejection>,
+    headers: HeaderMap,
+    path: Result<Path<String>, PathRejection>,
     State(state): State<Arc<AxumState>>,
     ws: WebSocketUpgrade,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
 ) -> Response {
     let services = state.services.clone();
-    match validate_token(&params.token) {
-        Ok(_) => (),
-        Err(response) => return response.into_response(),
-    }
-    let id = match find_service(path, &services).await {
-        Ok(id) => id,
-        Err(response) => return response,
+
+    let (id, ws) =
+        match authorize_stream(params, &headers, path, &state, OutputStream::Stderr, ws).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+
+    let shutdown = state.shutdown.clone();
+    ws.on_upgrade(move |socket| {
+        handle_socket(socket, addr, id, services, ServiceStream::Stderr, shutdown)
+    })
+    .into_response()
+}
+
+/// GET /public/services/:id/attach
+///
+/// Description: Attaches to the service by its id or name (404  if not found). Frames sent by
+/// the client are written into the stdin of the service and its output is sent back using the
+/// combined_output format. The service must have `stdin` or `tty` enabled (400 otherwise) and
+/// only one client can be attached at a time (409 otherwise).
+///
+/// The terminal of services running in a tty is resized with text frames like:
+/// {"type": "resize", "cols": 120, "rows": 40}
+pub async fn attach(
+    Query(params): Query<OutputStreamParams>,
+    headers: HeaderMap,
+    path: Result<Path<String>, PathRejection>,
+    State(state): State<Arc<AxumState>>,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Response {
+    let services = state.services.clone();
+
+    let (id, ws) =
+        match authorize_stream(params, &headers, path, &state, OutputStream::Attach, ws).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+
+    let writer = match services.fetch(id).await {
+        Some(service) => service.lock().await.attach_stdin(),
+        None => return not_found_response(),
+    };
+    let writer = match writer {
+        Ok(writer) => writer,
+        Err(e) => {
+            let status = match e {
+                AttachError::StdinDisabled(_) => StatusCode::BAD_REQUEST,
+                AttachError::AlreadyAttached(_) => StatusCode::CONFLICT,
+            };
+            return (status, Json(json!({"error": e.to_string()}))).into_response();
+        }
     };
 
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, id, services, ServiceStream::Stderr))
+    let shutdown = state.shutdown.clone();
+    ws.on_upgrade(move |socket| handle_socket_attach(socket, addr, id, services, writer, shutdown))
         .into_response()
 }
 
+// Control messages clients can send on the attach (and terminal) websockets, any other
+// frame is input.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum AttachCommand {
+    Resize(WindowSize),
+}
+
+// Websocket subprotocols used to send the credentials. Browsers can't set headers
+// when opening websockets, so this is the only way to keep them out of the url.
+const TICKET_PROTOCOL_PREFIX: &str = "kittengrid.ticket.";
+const TOKEN_PROTOCOL_PREFIX: &str = "kittengrid.token.";
+
+pub(crate) enum StreamCredentials {
+    Ticket(String),
+    Token(String),
+}
+
+// Looks for the credentials in the query params and then in the requested subprotocols,
+// returning the subprotocol they came in (it has to be echoed back to the client).
+pub(crate) fn stream_credentials(
+    params: OutputStreamParams,
+    headers: &HeaderMap,
+) -> Option<(StreamCredentials, Option<String>)> {
+    if let Some(ticket) = params.ticket {
+        return Some((StreamCredentials::Ticket(ticket), None));
+    }
+    if let Some(token) = params.token {
+        return Some((StreamCredentials::Token(token), None));
+    }
+
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .find_map(|protocol| {
+            if let Some(ticket) = protocol.strip_prefix(TICKET_PROTOCOL_PREFIX) {
+                Some((
+                    StreamCredentials::Ticket(ticket.to_string()),
+                    Some(protocol.to_string()),
+                ))
+            } else {
+                protocol.strip_prefix(TOKEN_PROTOCOL_PREFIX).map(|token| {
+                    (
+                        StreamCredentials::Token(token.to_string()),
+                        Some(protocol.to_string()),
+                    )
+                })
+            }
+        })
+}
+
+// Checks the credentials of an output websocket request, returning the service id and
+// the upgrade to use (with the subprotocol set if the credentials were sent in one).
+async fn authorize_stream(
+    params: OutputStreamParams,
+    headers: &HeaderMap,
+    path: Result<Path<String>, PathRejection>,
+    state: &AxumState,
+    stream: OutputStream,
+    ws: WebSocketUpgrade,
+) -> Result<(uuid::Uuid, WebSocketUpgrade), Response> {
+    let (credentials, protocol) = match stream_credentials(params, headers) {
+        Some(credentials) => credentials,
+        None => return Err(AuthError::InvalidToken.into_response()),
+    };
+
+    let id = match credentials {
+        StreamCredentials::Ticket(ticket) => {
+            let id = find_service(path, &state.services).await?;
+            if !state.tickets.redeem(&ticket, id, stream) {
+                return Err(AuthError::InvalidTicket.into_response());
+            }
+            id
+        }
+        StreamCredentials::Token(token) => {
+            let claims = validate_token(&token)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            find_stream_service(path, &state.services, &claims, stream).await?
+        }
+    };
+
+    let ws = match protocol {
+        Some(protocol) => ws.protocols([protocol]),
+        None => ws,
+    };
+    Ok((id, ws))
+}
+
 /// Actual websocket statemachine (one will be spawned per connection)
 async fn handle_socket(
     mut socket: WebSocket,
@@ -194,6 +576,7 @@ async fn handle_socket(
     id: uuid::Uuid,
     services: Arc<crate::service::Services>,
     stream: ServiceStream,
+    shutdown: Shutdown,
 ) {
     let mut stream_channel_receiver = match services.subscribe_to_stream(id, stream).await {
         Some(receiver) => receiver,
@@ -203,7 +586,10 @@ async fn handle_socket(
         }
     };
 
-    while let Some(data) = stream_channel_receiver.recv().await {
+    while let Some(data) = tokio::select! {
+        data = stream_channel_receiver.recv() => data,
+        _ = shutdown.triggered() => None,
+    } {
         info!("Received data from {id}:");
 
         if socket.send(Message::Binary(data)).await.is_err() {
@@ -221,10 +607,7 @@ async fn handle_socket(
     }
 
     if let Err(e) = socket
-        .send(Message::Close(Some(CloseFrame {
-            code: axum::extract::ws::close_code::NORMAL,
-            reason: "Closed by server".into(),
-        })))
+        .send(Message::Close(Some(close_frame(&shutdown))))
         .await
     {
         error!("Could not send close to {address}! {e}");
@@ -237,6 +620,7 @@ async fn handle_socket_combined(
     address: SocketAddr,
     id: uuid::Uuid,
     services: Arc<crate::service::Services>,
+    shutdown: Shutdown,
 ) {
     let mut stdout_stream_channel_receiver = match services
         .subscribe_to_stream(id, ServiceStream::Stdout)
@@ -263,6 +647,7 @@ async fn handle_socket_combined(
     while let (Some(data), source) = tokio::select! {
         data = stdout_stream_channel_receiver.recv() => (data, ServiceStream::Stdout),
         data = stderr_stream_channel_receiver.recv() => (data, ServiceStream::Stderr),
+        _ = shutdown.triggered() => (None, ServiceStream::Stdout),
     } {
         debug!("Received data from {id}:");
         let data = create_stream_output_json(&source, &data);
@@ -293,45 +678,225 @@ async fn handle_socket_combined(
     }
 
     if let Err(e) = socket
-        .send(Message::Close(Some(CloseFrame {
+        .send(Message::Close(Some(close_frame(&shutdown))))
+        .await
+    {
+        error!("Could not send close to {address}! {e}");
+    };
+}
+
+// Frame sent when the server closes a websocket, clients are told to come back
+// later when the agent is shutting down.
+pub(crate) fn close_frame(shutdown: &Shutdown) -> CloseFrame {
+    if shutdown.is_triggered() {
+        CloseFrame {
+            code: axum::extract::ws::close_code::AWAY,
+            reason: "Agent shutting down".into(),
+        }
+    } else {
+        CloseFrame {
             code: axum::extract::ws::close_code::NORMAL,
             reason: "Closed by server".into(),
-        })))
+        }
+    }
+}
+
+/// Actual websocket statemachine for attached clients (one will be spawned per connection)
+async fn handle_socket_attach(
+    mut socket: WebSocket,
+    address: SocketAddr,
+    id: uuid::Uuid,
+    services: Arc<crate::service::Services>,
+    writer: StdinWriter,
+    shutdown: Shutdown,
+) {
+    let (mut stdout_receiver, mut stderr_receiver) = match (
+        services
+            .subscribe_to_stream(id, ServiceStream::Stdout)
+            .await,
+        services
+            .subscribe_to_stream(id, ServiceStream::Stderr)
+            .await,
+    ) {
+        (Some(stdout), Some(stderr)) => (stdout, stderr),
+        _ => {
+            error!("Could not subscribe to {id} output channels");
+            return;
+        }
+    };
+
+    loop {
+        let (data, source) = tokio::select! {
+            data = stdout_receiver.recv() => (data, ServiceStream::Stdout),
+            data = stderr_receiver.recv() => (data, ServiceStream::Stderr),
+            message = socket.recv() => {
+                let input = match message {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Text(text))) => {
+                        if let Ok(AttachCommand::Resize(size)) = serde_json::from_str(&text) {
+                            if let Err(e) = writer.resize(size).await {
+                                debug!("Could not resize {id} terminal: {e}");
+                            }
+                            continue;
+                        }
+                        bytes::Bytes::from(text)
+                    }
+                    // Pings are answered by axum
+                    Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                    _ => break,
+                };
+                if let Err(e) = writer.write(&input).await {
+                    debug!("Could not write into {id} stdin: {e}");
+                }
+                continue;
+            }
+            _ = shutdown.triggered() => break,
+        };
+        let data = match data {
+            Some(data) => create_stream_output_json(&source, &data),
+            None => break,
+        };
+
+        if socket
+            .send(Message::Text(data.to_string().into()))
+            .await
+            .is_err()
+        {
+            error!("Could not send data to {address}!");
+            break;
+        }
+    }
+
+    info!("Client detached from {id}, dropping internal streams.");
+    drop(writer);
+    for (stream, receiver) in [
+        (ServiceStream::Stdout, stdout_receiver),
+        (ServiceStream::Stderr, stderr_receiver),
+    ] {
+        if let Err(e) = services.unsubscribe_from_stream(id, stream, receiver).await {
+            error!("Could not unsubscribe from {id} {stream} channel! {e}");
+        }
+    }
+
+    if let Err(e) = socket
+        .send(Message::Close(Some(close_frame(&shutdown))))
         .await
     {
         error!("Could not send close to {address}! {e}");
     };
 }
 
-// Process the path and return the service id if ok, or a response error if not
+// Process the path and return the service id if ok, or a response error if not.
+// The path can hold either the service id or its name, names matching more
+// than one service are rejected.
 async fn find_service(
-    path: Result<Path<uuid::Uuid>, PathRejection>,
+    path: Result<Path<String>, PathRejection>,
     services: &Arc<Services>,
 ) -> Result<uuid::Uuid, Response> {
-    let id = match path {
-        Ok(id) => id.0,
+    let id_or_name = match path {
+        Ok(id_or_name) => id_or_name.0,
         Err(_) => {
             return Err(Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .header("content-type", "application/json")
-                .body(Body::from(json!({"error": "Invalid UUID"}).to_string()))
+                .body(Body::from(json!({"error": "Invalid path"}).to_string()))
                 .unwrap());
         }
     };
 
-    if services.fetch(id).await.is_none() {
-        return Err(Response::builder()
-            .status(StatusCode::NOT_FOUND)
+    if let Ok(id) = uuid::Uuid::parse_str(&id_or_name) {
+        if services.fetch(id).await.is_some() {
+            return Ok(id);
+        }
+    }
+
+    let ids = services.ids_by_name(&id_or_name).await;
+    match ids.as_slice() {
+        [id] => Ok(*id),
+        [] => Err(not_found_response()),
+        _ => Err(Response::builder()
+            .status(StatusCode::CONFLICT)
             .header("content-type", "application/json")
             .body(Body::from(
-                json!({"error": "Service not found"}).to_string(),
+                json!({"error": "Ambiguous service name, use the service id instead"}).to_string(),
             ))
-            .unwrap());
+            .unwrap()),
+    }
+}
+
+// Finds the service for an output stream (or a ticket for it), checking the token
+// scopes. Attaching writes into the service stdin, so it is also a control action.
+async fn find_stream_service(
+    path: Result<Path<String>, PathRejection>,
+    services: &Arc<Services>,
+    claims: &Claims,
+    stream: OutputStream,
+) -> Result<uuid::Uuid, Response> {
+    if stream == OutputStream::Attach {
+        claims
+            .require_scope(Scope::ServicesControl)
+            .map_err(IntoResponse::into_response)?;
+    }
+    find_authorized_service(path, services, claims, Scope::LogsRead).await
+}
+
+// Like `find_service`, but also checks the token is allowed to perform the action
+// (`scope`) on the service. The scope is checked before looking the service up so
+// tokens lacking it can't be used to discover which services exist. For the same
+// reason, tokens restricted to some services get the same response for the services
+// they can't access and the ones that don't exist.
+async fn find_authorized_service(
+    path: Result<Path<String>, PathRejection>,
+    services: &Arc<Services>,
+    claims: &Claims,
+    scope: Scope,
+) -> Result<uuid::Uuid, Response> {
+    claims
+        .require_scope(scope)
+        .map_err(IntoResponse::into_response)?;
+
+    let requested = match &path {
+        Ok(id_or_name) => id_or_name.0.clone(),
+        Err(_) => String::new(),
+    };
+    let not_allowed = || AuthError::ServiceNotAllowed(requested.clone()).into_response();
+
+    let id = match find_service(path, services).await {
+        Ok(id) => id,
+        Err(_) if claims.services.is_some() => return Err(not_allowed()),
+        Err(response) => return Err(response),
+    };
+    let name = match services.description(id).await {
+        Some(description) => description.name(),
+        None if claims.services.is_some() => return Err(not_allowed()),
+        None => return Err(not_found_response()),
+    };
+
+    if !claims.allows_service(id, &name) {
+        return Err(not_allowed());
     }
 
     Ok(id)
 }
 
+fn not_found_response() -> Response {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({"error": "Service not found"}).to_string(),
+        ))
+        .unwrap()
+}
+
+fn capture_disabled_response() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": "Requests of the service are not captured"})),
+    )
+        .into_response()
+}
+
 fn ok_response() -> Response {
     (StatusCode::OK, Json(json!({"status": "ok"}))).into_response()
 }
@@ -358,74 +923,6 @@ fn create_stream_output_json(
     })
 }
 
-// For auth using jwt
-
-static KEY: Lazy<DecodingKey> = Lazy::new(|| {
-    let secret = crate::config::get_config().clone().api_key;
-    DecodingKey::from_secret(secret.as_bytes())
-});
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub bearer_id: String,
-    pub bearer_type: String,
-    pub exp: u64,
-}
-
-impl<S> FromRequestParts<S> for Claims
-where
-    S: Send + Sync,
-{
-    type Rejection = AuthError;
-
-    async fn from_request_parts(
-        parts: &mut Parts,
-        _state: &S,
-    ) -> Result<Self, Self::Rejection> {
-        // Extract the token from the authorization header
-        let TypedHeader(Authorization(bearer)) = parts
-            .extract::<TypedHeader<Authorization<Bearer>>>()
-            .await
-            .map_err(|_| AuthError::InvalidToken)?;
-
-        validate_token(bearer.token())
-    }
-}
-
-pub fn validate_token(token: &str) -> Result<Claims, AuthError> {
-    let token_data = decode::<Claims>(token, &KEY, &Validation::default())
-        .map_err(|_| AuthError::InvalidToken)?;
-
-    let current_time_in_seconds = match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(n) => n.as_secs(),
-        Err(_) => return Err(AuthError::InvalidToken),
-    };
-
-    if current_time_in_seconds > token_data.claims.exp {
-        return Err(AuthError::ExpiredToken);
-    }
-
-    Ok(token_data.claims)
-}
-
-pub enum AuthError {
-    ExpiredToken,
-    InvalidToken,
-}
-
-impl IntoResponse for AuthError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AuthError::ExpiredToken => (StatusCode::FORBIDDEN, "Token expired"),
-            AuthError::InvalidToken => (StatusCode::FORBIDDEN, "Invalid token"),
-        };
-        let body = Json(json!({
-            "error": error_message,
-        }));
-        (status, body).into_response()
-    }
-}
-
 #[cfg(test)]
 mod test {
     use crate::test_utils::*;
@@ -543,30 +1040,74 @@ mod test {
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
-    async fn test_combined_output() {
+    async fn close_on_shutdown() {
         initialize_tests();
         let server_test = ServerTest::new(true).await;
         let service_id = first_service_id(&server_test.services()).await;
-        let ws_stream = match connect_async(server_test.url_for_with_protocol(
+        let (ws_stream, _) = connect_async(server_test.url_for_with_protocol(
             "ws",
             &format!(
-                "/public/services/{service_id}/combined_output?token={}",
+                "/public/services/{service_id}/stdout?token={}",
                 server_test.valid_token()
             ),
         ))
         .await
-        {
-            Ok((stream, _)) => stream,
-            Err(err) => {
-                eprintln!("Error: {:?}", err);
-
-                panic!("Could not connect to server");
-            }
-        };
-
+        .expect("Could not connect to server");
         let (_, mut receiver) = ws_stream.split();
-        let data_received = receiver
-            .next()
+        assert!(receiver.next().await.is_some());
+
+        let response = server_test
+            .client
+            .post(server_test.url_for("/sys/shutdown"))
+            .bearer_auth(server_test.valid_token())
+            .json(&serde_json::json!({"message": "test"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Pending output may arrive before the close frame.
+        let frame = loop {
+            match receiver.next().await {
+                Some(Ok(tokio_tungstenite::tungstenite::Message::Close(frame))) => break frame,
+                Some(Ok(_)) => continue,
+                other => panic!("Expected a close frame, got {:?}", other),
+            }
+        };
+        let frame = frame.expect("Close frame without code");
+        assert_eq!(
+            frame.code,
+            tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Away
+        );
+
+        server_test.services().stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn test_combined_output() {
+        initialize_tests();
+        let server_test = ServerTest::new(true).await;
+        let service_id = first_service_id(&server_test.services()).await;
+        let ws_stream = match connect_async(server_test.url_for_with_protocol(
+            "ws",
+            &format!(
+                "/public/services/{service_id}/combined_output?token={}",
+                server_test.valid_token()
+            ),
+        ))
+        .await
+        {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                eprintln!("Error: {:?}", err);
+
+                panic!("Could not connect to server");
+            }
+        };
+
+        let (_, mut receiver) = ws_stream.split();
+        let data_received = receiver
+            .next()
             .await
             .unwrap()
             .expect("Failed to receive data");
@@ -642,7 +1183,7 @@ mod test {
             .send()
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
         assert_eq!(
             response.headers().get("content-type").unwrap(),
             "application/json"
@@ -730,6 +1271,53 @@ mod test {
         server_test.services().stop().await.unwrap();
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn requests() {
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        let service_id = first_service_id(&server_test.services()).await;
+        let request_id = uuid::Uuid::new_v4();
+
+        // The proxy is disabled in the test configuration, nothing is captured.
+        let response = server_test
+            .client
+            .get(server_test.url_for(&format!("/public/services/{service_id}/requests")))
+            .header(
+                "Authorization",
+                format!("Bearer {}", server_test.valid_token()),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = server_test
+            .client
+            .post(server_test.url_for(&format!(
+                "/public/services/{service_id}/requests/{request_id}/replay"
+            )))
+            .header(
+                "Authorization",
+                format!("Bearer {}", server_test.valid_token()),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Replaying needs the control scope.
+        let token = server_test.token(ServerTest::an_hour_from_now(), &["services:read"], None);
+        let response = server_test
+            .client
+            .post(server_test.url_for(&format!(
+                "/public/services/{service_id}/requests/{request_id}/replay"
+            )))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
     async fn index() {
         initialize_tests();
@@ -785,6 +1373,501 @@ mod test {
         server_test.services().stop().await.unwrap();
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn index_filters() {
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        for (query, expected) in [
+            ("?name=test", 1),
+            ("?name=unknown", 0),
+            ("?status=stopped", 1),
+            ("?status=Running", 0),
+            ("?name=test&status=Stopped", 1),
+        ] {
+            let response = server_test
+                .client
+                .get(server_test.url_for(&format!("/public/services{query}")))
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", server_test.valid_token()),
+                )
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            let data = response.json::<serde_json::Value>().await.unwrap();
+            assert_eq!(data.as_array().unwrap().len(), expected, "{query}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn show() {
+        initialize_tests();
+        let server_test = ServerTest::new(true).await;
+        let service_id = first_service_id(&server_test.services()).await;
+        let response = server_test
+            .client
+            .get(server_test.url_for(&format!("/public/services/{service_id}")))
+            .header(
+                "Authorization",
+                format!("Bearer {}", server_test.valid_token()),
+            )
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let data = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(data["id"].as_str().unwrap(), service_id.to_string());
+        assert_eq!(data["description"]["name"].as_str().unwrap(), "test");
+        assert_eq!(data["status"].as_str().unwrap(), "Running");
+        assert!(data["pid"].is_number());
+        assert!(data["uptime"].is_number());
+        assert_eq!(data["restart_count"].as_u64().unwrap(), 0);
+        assert!(data["last_exit_code"].is_null());
+        assert!(data["log_history_bytes"]["stdout"].is_number());
+        assert_eq!(data["subscribers"]["stdout"].as_u64().unwrap(), 0);
+
+        let response = server_test
+            .client
+            .get(server_test.url_for("/public/services/f4d916f7-1fcd-4dcd-8d08-f66f82c0735b"))
+            .header(
+                "Authorization",
+                format!("Bearer {}", server_test.valid_token()),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        server_test.services().stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn by_name() {
+        initialize_tests();
+        let server_test = ServerTest::new(true).await;
+        let service_id = first_service_id(&server_test.services()).await;
+        let response = server_test
+            .client
+            .get(server_test.url_for("/public/services/test"))
+            .header(
+                "Authorization",
+                format!("Bearer {}", server_test.valid_token()),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let data = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(data["id"].as_str().unwrap(), service_id.to_string());
+
+        let response = server_test
+            .client
+            .post(server_test.url_for("/public/services/test/stop"))
+            .header(
+                "Authorization",
+                format!("Bearer {}", server_test.valid_token()),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let ws_stream = connect_async(server_test.url_for_with_protocol(
+            "ws",
+            &format!(
+                "/public/services/test/stdout?token={}",
+                server_test.valid_token()
+            ),
+        ))
+        .await;
+        assert!(ws_stream.is_ok());
+
+        server_test.services().stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn ambiguous_name() {
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        server_test
+            .services()
+            .insert(crate::service::Service::from(
+                crate::config::ServiceConfig {
+                    name: "test".to_string(),
+                    ..Default::default()
+                },
+            ))
+            .await;
+
+        let response = server_test
+            .client
+            .get(server_test.url_for("/public/services/test"))
+            .header(
+                "Authorization",
+                format!("Bearer {}", server_test.valid_token()),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let service_id = server_test.services().ids_by_name("test").await[0];
+        let response = server_test
+            .client
+            .get(server_test.url_for(&format!("/public/services/{service_id}")))
+            .header(
+                "Authorization",
+                format!("Bearer {}", server_test.valid_token()),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn missing_scope() {
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        let token = server_test.token(ServerTest::an_hour_from_now(), &["services:read"], None);
+
+        let response = server_test
+            .client
+            .get(server_test.url_for("/public/services"))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = server_test
+            .client
+            .post(server_test.url_for("/public/services/test/start"))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let data = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(
+            data["error"].as_str().unwrap(),
+            "Token lacks the 'services:control' scope"
+        );
+
+        // Tokens without scopes can't even list services
+        let token = server_test.token(ServerTest::an_hour_from_now(), &[], None);
+        let response = server_test
+            .client
+            .get(server_test.url_for("/public/services"))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn logs_scope() {
+        initialize_tests();
+        let server_test = ServerTest::new(true).await;
+        let token = server_test.token(ServerTest::an_hour_from_now(), &["services:read"], None);
+        let ws_stream =
+            connect_async(server_test.url_for_with_protocol(
+                "ws",
+                &format!("/public/services/test/stdout?token={token}"),
+            ))
+            .await;
+        assert!(ws_stream.is_err());
+
+        let token = server_test.token(ServerTest::an_hour_from_now(), &["logs:read"], None);
+        let ws_stream = connect_async(server_test.url_for_with_protocol(
+            "ws",
+            &format!("/public/services/test/combined_output?token={token}"),
+        ))
+        .await;
+        assert!(ws_stream.is_ok());
+
+        server_test.services().stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn service_allowlist() {
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        let service_id = first_service_id(&server_test.services()).await;
+        let token = server_test.token(
+            ServerTest::an_hour_from_now(),
+            ALL_SCOPES,
+            Some(vec!["another-service".to_string()]),
+        );
+
+        let response = server_test
+            .client
+            .get(server_test.url_for("/public/services"))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let data = response.json::<serde_json::Value>().await.unwrap();
+        assert!(data.as_array().unwrap().is_empty());
+
+        let response = server_test
+            .client
+            .post(server_test.url_for(&format!("/public/services/{service_id}/stop")))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let data = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(
+            data["error"].as_str().unwrap(),
+            format!("Token is not allowed to access service '{service_id}'")
+        );
+
+        // Services that don't exist get the same response, the token can't tell them
+        // apart from the ones it is not allowed to access.
+        for path in ["test", "missing"] {
+            let response = server_test
+                .client
+                .get(server_test.url_for(&format!("/public/services/{path}")))
+                .header("Authorization", format!("Bearer {}", token))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let data = response.json::<serde_json::Value>().await.unwrap();
+            assert_eq!(
+                data["error"].as_str().unwrap(),
+                format!("Token is not allowed to access service '{path}'")
+            );
+        }
+
+        // Services can be allowed either by id or by name
+        for allowed in [service_id.to_string(), "test".to_string()] {
+            let token = server_test.token(
+                ServerTest::an_hour_from_now(),
+                ALL_SCOPES,
+                Some(vec![allowed]),
+            );
+            let response = server_test
+                .client
+                .get(server_test.url_for("/public/services/test"))
+                .header("Authorization", format!("Bearer {}", token))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    async fn issue_ticket(server_test: &ServerTest, token: &str, stream: &str) -> String {
+        let response = server_test
+            .client
+            .post(server_test.url_for("/public/services/test/stream_tickets"))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&serde_json::json!({"stream": stream}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let data = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(data["stream"], stream);
+        assert!(data["expires_in"].as_u64().unwrap() < 60);
+        data["ticket"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn stream_tickets() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        initialize_tests();
+        let server_test = ServerTest::new(true).await;
+        let token = server_test.valid_token();
+
+        // Tickets can only be redeemed once.
+        let ticket = issue_ticket(&server_test, &token, "stdout").await;
+        let url = server_test.url_for_with_protocol(
+            "ws",
+            &format!("/public/services/test/stdout?ticket={ticket}"),
+        );
+        let (ws_stream, _) = connect_async(url.clone()).await.unwrap();
+        let (_, mut receiver) = ws_stream.split();
+        assert!(receiver.next().await.is_some());
+        assert!(connect_async(url).await.is_err());
+
+        // And only for the stream they were issued for.
+        let ticket = issue_ticket(&server_test, &token, "stdout").await;
+        assert!(connect_async(server_test.url_for_with_protocol(
+            "ws",
+            &format!("/public/services/test/stderr?ticket={ticket}"),
+        ))
+        .await
+        .is_err());
+
+        // Tickets are also accepted as websocket subprotocol, which is echoed back.
+        let ticket = issue_ticket(&server_test, &token, "combined_output").await;
+        let protocol = format!("kittengrid.ticket.{ticket}");
+        let mut request = server_test
+            .url_for_with_protocol("ws", "/public/services/test/combined_output")
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+        let (_, response) = connect_async(request).await.unwrap();
+        assert_eq!(
+            response.headers()["Sec-WebSocket-Protocol"],
+            protocol.as_str()
+        );
+
+        // Issuing tickets requires the logs scope.
+        let token = server_test.token(ServerTest::an_hour_from_now(), &["services:read"], None);
+        let response = server_test
+            .client
+            .post(server_test.url_for("/public/services/test/stream_tickets"))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&serde_json::json!({"stream": "stdout"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        server_test.services().stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn attach() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        let service = crate::service::Service::from(crate::config::ServiceConfig {
+            name: "/bin/cat".to_string(),
+            stdin: true,
+            ..Default::default()
+        });
+        let id = service.id();
+        server_test.services().insert(service).await;
+        server_test.services().start_service(id).await.unwrap();
+
+        let url = server_test.url_for_with_protocol(
+            "ws",
+            &format!(
+                "/public/services/{id}/attach?token={}",
+                server_test.valid_token()
+            ),
+        );
+        let (ws_stream, _) = connect_async(url.clone()).await.unwrap();
+        let (mut sender, mut receiver) = ws_stream.split();
+
+        // Only one client at a time.
+        assert!(connect_async(url.clone()).await.is_err());
+
+        sender.send(Message::text("hello\n")).await.unwrap();
+        let message = receiver.next().await.unwrap().unwrap();
+        let data: serde_json::Value = serde_json::from_slice(&message.into_data()).unwrap();
+        assert_eq!(data["type"], "stdout");
+        assert_eq!(data["data"], serde_json::json!(b"hello\n".to_vec()));
+
+        // Once detached, others can attach.
+        sender.send(Message::Close(None)).await.unwrap();
+        drop(receiver);
+        let mut attached = false;
+        for _ in 0..20 {
+            if connect_async(url.clone()).await.is_ok() {
+                attached = true;
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        assert!(attached);
+
+        // Attaching requires the control scope and services with stdin enabled.
+        let token = server_test.token(ServerTest::an_hour_from_now(), &["logs:read"], None);
+        assert!(connect_async(
+            server_test.url_for_with_protocol(
+                "ws",
+                &format!("/public/services/{id}/attach?token={token}"),
+            )
+        )
+        .await
+        .is_err());
+        let response = server_test
+            .client
+            .get(server_test.url_for(&format!(
+                "/public/services/test/attach?token={}",
+                server_test.valid_token()
+            )))
+            .header("Connection", "upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        server_test.services().stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn attach_tty() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        let service = crate::service::Service::from(crate::config::ServiceConfig {
+            name: "/bin/bash".to_string(),
+            args: Some(vec![
+                "-c".to_string(),
+                "while read line; do stty size; done".to_string(),
+            ]),
+            tty: true,
+            ..Default::default()
+        });
+        let id = service.id();
+        server_test.services().insert(service).await;
+        server_test.services().start_service(id).await.unwrap();
+
+        let (ws_stream, _) = connect_async(server_test.url_for_with_protocol(
+            "ws",
+            &format!(
+                "/public/services/{id}/attach?token={}",
+                server_test.valid_token()
+            ),
+        ))
+        .await
+        .unwrap();
+        let (mut sender, mut receiver) = ws_stream.split();
+
+        sender
+            .send(Message::text(
+                r#"{"type": "resize", "cols": 100, "rows": 30}"#,
+            ))
+            .await
+            .unwrap();
+        sender.send(Message::text("\n")).await.unwrap();
+
+        // The input is echoed by the terminal, output comes in chunks.
+        let mut output = Vec::new();
+        while !String::from_utf8_lossy(&output).contains("30 100\r\n") {
+            let message = receiver.next().await.unwrap().unwrap();
+            let data: serde_json::Value = serde_json::from_slice(&message.into_data()).unwrap();
+            assert_eq!(data["type"], "stdout");
+            let data: Vec<u8> = serde_json::from_value(data["data"].clone()).unwrap();
+            output.extend(data);
+        }
+
+        server_test.services().stop().await.unwrap();
+    }
+
     async fn first_service_id(services: &crate::service::Services) -> uuid::Uuid {
         *services.descriptions().await.keys().next().unwrap()
     }
@@ -0,0 +1,487 @@
+use crate::endpoints::auth::{validate_token, AuthError, Claims, Scope};
+use crate::endpoints::public::services::{
+    close_frame, stream_credentials, AttachCommand, OutputStreamParams, StreamCredentials,
+};
+use crate::pty::WindowSize;
+use crate::shutdown::Shutdown;
+use crate::terminal::recording::Recordings;
+use crate::terminal::TerminalSession;
+use crate::AxumState;
+
+use axum::extract::connect_info::ConnectInfo;
+use axum::{
+    extract::{
+        rejection::PathRejection,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use log::{debug, error, info};
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct TerminalParams {
+    pub token: Option<String>,
+    /// Initial size of the terminal, 80x24 by default.
+    pub cols: Option<u16>,
+    pub rows: Option<u16>,
+}
+
+/// GET /public/terminal
+///
+/// Description: Opens a shell in a new pseudo terminal (see the `terminal` configuration),
+/// every connection gets its own session. The token needs the `terminal` scope and is sent
+/// in the `token` query param or as a `kittengrid.token.<token>` websocket subprotocol.
+///
+/// The protocol is the one expected by the xterm.js attach addon: frames sent by the client
+/// are written into the terminal and its output is sent back in binary frames. The terminal
+/// is resized with text frames like:
+/// {"type": "resize", "cols": 120, "rows": 40}
+///
+/// The websocket is closed when the shell exits, or after `idle_timeout` seconds without
+/// input, and the shell is hung up when the client disconnects. Input is ignored (resizes
+/// are not) when the terminal is not `writable`. Sessions are recorded, see
+/// `GET /public/terminal/sessions`.
+pub async fn terminal(
+    Query(params): Query<TerminalParams>,
+    headers: HeaderMap,
+    State(state): State<Arc<AxumState>>,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Response {
+    let credentials = stream_credentials(
+        OutputStreamParams {
+            ticket: None,
+            token: params.token,
+        },
+        &headers,
+    );
+    let (token, protocol) = match credentials {
+        Some((StreamCredentials::Token(token), protocol)) => (token, protocol),
+        // Tickets are bound to services.
+        Some((StreamCredentials::Ticket(_), _)) => return AuthError::InvalidTicket.into_response(),
+        None => return AuthError::InvalidToken.into_response(),
+    };
+    let claims = match validate_token(&token).await {
+        Ok(claims) => claims,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = claims.require_scope(Scope::Terminal) {
+        return e.into_response();
+    }
+
+    let default_size = WindowSize::default();
+    let size = WindowSize {
+        cols: params.cols.unwrap_or(default_size.cols),
+        rows: params.rows.unwrap_or(default_size.rows),
+    };
+    let ws = match protocol {
+        Some(protocol) => ws.protocols([protocol]),
+        None => ws,
+    };
+
+    let shutdown = state.shutdown.clone();
+    let recordings = state.recordings.clone();
+    ws.on_upgrade(move |socket| {
+        handle_socket(socket, addr, claims.bearer_id, size, recordings, shutdown)
+    })
+    .into_response()
+}
+
+/// GET /public/terminal/sessions
+///
+/// Description: Lists the recorded terminal sessions, newest first. Requires the `terminal` scope,
+/// only the sessions opened by the bearer of the token are listed without the
+/// `recordings:read` scope.
+///
+/// Response example:
+/// {
+///    "sessions" : [
+///       {
+///          "active" : false,
+///          "bearer_id" : "1234",
+///          "id" : "0a5f5d0e-8a5e-4a57-9d0b-3c5b1f1e4a2c",
+///          "size" : 5120,
+///          "started_at" : 1760000000
+///       }
+///    ]
+/// }
+pub async fn sessions(claims: Claims, State(state): State<Arc<AxumState>>) -> Response {
+    if let Err(e) = claims.require_scope(Scope::Terminal) {
+        return e.into_response();
+    }
+
+    // Listing reads the header of every recording, so it is done off the runtime.
+    let recordings = state.recordings.clone();
+    let sessions = tokio::task::spawn_blocking(move || recordings.list())
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    match sessions {
+        Ok(mut sessions) => {
+            if !claims.has_scope(Scope::RecordingsRead) {
+                sessions.retain(|session| session.bearer_id == claims.bearer_id);
+            }
+            (StatusCode::OK, Json(json!({"sessions": sessions}))).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+/// GET /public/terminal/sessions/:id/recording
+///
+/// Description: Downloads the recording of a terminal session (404 if not found) as an
+/// asciicast v2 file, it can be played with asciinema. Recordings of open sessions have
+/// the events up to now. Requires the `terminal` scope, and the `recordings:read` one for
+/// sessions opened by other bearers.
+pub async fn recording(
+    claims: Claims,
+    path: Result<Path<uuid::Uuid>, PathRejection>,
+    State(state): State<Arc<AxumState>>,
+) -> Response {
+    if let Err(e) = claims.require_scope(Scope::Terminal) {
+        return e.into_response();
+    }
+    let id = match path {
+        Ok(Path(id)) => id,
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid path"})),
+            )
+                .into_response()
+        }
+    };
+
+    // Recordings of other bearers are not found, so their ids can't be probed.
+    let recordings = state.recordings.clone();
+    let read_all = claims.has_scope(Scope::RecordingsRead);
+    let bearer_id = claims.bearer_id;
+    let found = tokio::task::spawn_blocking(move || {
+        let allowed = read_all
+            || recordings
+                .bearer_id(id)
+                .is_ok_and(|owner| owner == bearer_id);
+        recordings.find(id).filter(|_| allowed)
+    })
+    .await
+    .unwrap_or_default();
+    let contents = match found {
+        Some(path) => tokio::fs::read(path).await,
+        None => Err(std::io::ErrorKind::NotFound.into()),
+    };
+    match contents {
+        Ok(contents) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "application/x-asciicast".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{id}.cast\""),
+                ),
+            ],
+            contents,
+        )
+            .into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Session not found"})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+/// Actual websocket statemachine (one will be spawned per connection)
+async fn handle_socket(
+    mut socket: WebSocket,
+    address: SocketAddr,
+    bearer_id: String,
+    size: WindowSize,
+    recordings: Recordings,
+    shutdown: Shutdown,
+) {
+    // The shell is started once upgraded, so failed upgrades don't leave it behind.
+    // Starting it prunes the old recordings and opens the terminal, off the runtime.
+    let config = &crate::config::get_config().terminal;
+    let spawned = {
+        let bearer_id = bearer_id.clone();
+        tokio::task::spawn_blocking(move || {
+            TerminalSession::spawn(config, size, &bearer_id, &recordings)
+        })
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+    };
+    let mut session = match spawned {
+        Ok(session) => session,
+        Err(e) => {
+            error!("Could not start terminal session for {address}: {e}");
+            let frame = CloseFrame {
+                code: close_code::ERROR,
+                reason: "Could not start the shell".into(),
+            };
+            if let Err(e) = socket.send(Message::Close(Some(frame))).await {
+                error!("Could not send close to {address}! {e}");
+            }
+            return;
+        }
+    };
+    let id = session.id();
+    info!("Terminal session {id} opened by '{bearer_id}' from {address}");
+
+    let writable = config.writable();
+    let idle_timeout = config.idle_timeout();
+    let mut last_input = tokio::time::Instant::now();
+    loop {
+        let idle_deadline = last_input + idle_timeout.unwrap_or_default();
+        tokio::select! {
+            data = session.read() => {
+                let data = match data {
+                    Some(data) => data,
+                    None => break,
+                };
+                if socket.send(Message::Binary(data)).await.is_err() {
+                    error!("Could not send data to {address}!");
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                let input = match message {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Text(text))) => {
+                        if let Ok(AttachCommand::Resize(size)) = serde_json::from_str(&text) {
+                            if let Err(e) = session.resize(size) {
+                                debug!("Could not resize terminal session {id}: {e}");
+                            }
+                            continue;
+                        }
+                        bytes::Bytes::from(text)
+                    }
+                    // Pings are answered by axum
+                    Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                    _ => break,
+                };
+                if !writable {
+                    debug!("Ignoring input of read only terminal session {id}");
+                    continue;
+                }
+                last_input = tokio::time::Instant::now();
+                if let Err(e) = session.write(&input).await {
+                    debug!("Could not write into terminal session {id}: {e}");
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(idle_deadline), if idle_timeout.is_some() => {
+                info!("Closing idle terminal session {id}.");
+                break;
+            }
+            _ = shutdown.triggered() => break,
+        }
+    }
+
+    session.close().await;
+    info!("Terminal session {id} closed.");
+
+    if let Err(e) = socket
+        .send(Message::Close(Some(close_frame(&shutdown))))
+        .await
+    {
+        debug!("Could not send close to {address}! {e}");
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::*;
+
+    use futures_util::{SinkExt, StreamExt};
+    use reqwest::StatusCode;
+    use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn terminal() {
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        let url = |token: &str| {
+            server_test.url_for_with_protocol(
+                "ws",
+                &format!("/public/terminal?token={token}&cols=100&rows=30"),
+            )
+        };
+
+        // Sessions are independent from each other.
+        let (first, _) = connect_async(url(&server_test.valid_token()))
+            .await
+            .unwrap();
+        let (second, _) = connect_async(url(&server_test.valid_token()))
+            .await
+            .unwrap();
+        for (mut ws_stream, command, expected) in [
+            (first, "stty size\n", "30 100\r\n"),
+            (second, "export FOO=bar; echo $FOO\n", "bar\r\n"),
+        ] {
+            ws_stream.send(Message::text(command)).await.unwrap();
+            let mut output = String::new();
+            while !output.contains(expected) {
+                let message = ws_stream.next().await.unwrap().unwrap();
+                assert!(message.is_binary());
+                output.push_str(&String::from_utf8_lossy(&message.into_data()));
+            }
+
+            ws_stream
+                .send(Message::text(
+                    r#"{"type": "resize", "cols": 120, "rows": 40}"#,
+                ))
+                .await
+                .unwrap();
+            ws_stream.send(Message::text("stty size\n")).await.unwrap();
+            while !output.contains("40 120\r\n") {
+                let message = ws_stream.next().await.unwrap().unwrap();
+                output.push_str(&String::from_utf8_lossy(&message.into_data()));
+            }
+
+            // The websocket is closed once the shell exits.
+            ws_stream.send(Message::text("exit\n")).await.unwrap();
+            while let Some(Ok(message)) = ws_stream.next().await {
+                if message.is_close() {
+                    break;
+                }
+            }
+        }
+
+        // Requires the terminal scope.
+        let token = server_test.token(ServerTest::an_hour_from_now(), &["logs:read"], None);
+        assert!(connect_async(url(&token)).await.is_err());
+        assert!(
+            connect_async(server_test.url_for_with_protocol("ws", "/public/terminal"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn recordings() {
+        initialize_tests();
+        let server_test = ServerTest::new(false).await;
+        let (mut ws_stream, _) = connect_async(server_test.url_for_with_protocol(
+            "ws",
+            &format!("/public/terminal?token={}", server_test.valid_token()),
+        ))
+        .await
+        .unwrap();
+        let marker = uuid::Uuid::new_v4().to_string();
+        ws_stream
+            .send(Message::text(format!("echo {marker}; exit\n")))
+            .await
+            .unwrap();
+        while let Some(Ok(message)) = ws_stream.next().await {
+            if message.is_close() {
+                break;
+            }
+        }
+
+        let get = |path: &str, token: &str| {
+            server_test
+                .client
+                .get(server_test.url_for(path))
+                .header("Authorization", format!("Bearer {}", token))
+                .send()
+        };
+        let response = get("/public/terminal/sessions", &server_test.valid_token())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let sessions = response.json::<serde_json::Value>().await.unwrap();
+
+        // Other tests open sessions too, look for ours.
+        let mut recording = None;
+        for session in sessions["sessions"].as_array().unwrap() {
+            assert_eq!(session["bearer_id"], "test");
+            let response = get(
+                &format!(
+                    "/public/terminal/sessions/{}/recording",
+                    session["id"].as_str().unwrap()
+                ),
+                &server_test.valid_token(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()["content-type"],
+                "application/x-asciicast"
+            );
+            let contents = response.text().await.unwrap();
+            if contents.contains(&format!("{marker}\\r\\n")) {
+                assert!(!session["active"].as_bool().unwrap());
+                recording = Some((session["id"].as_str().unwrap().to_string(), contents));
+            }
+        }
+        let (session_id, recording) = recording.expect("Session not recorded");
+        let header: serde_json::Value =
+            serde_json::from_str(recording.lines().next().unwrap()).unwrap();
+        assert_eq!(header["version"], 2);
+        assert_eq!(header["bearer_id"], "test");
+
+        let response = get(
+            &format!(
+                "/public/terminal/sessions/{}/recording",
+                uuid::Uuid::new_v4()
+            ),
+            &server_test.valid_token(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Without the recordings scope, only the bearer's own sessions can be seen.
+        for (bearer_id, visible) in [("test", true), ("someone-else", false)] {
+            let token = server_test.bearer_token(
+                bearer_id,
+                ServerTest::an_hour_from_now(),
+                &["terminal"],
+                None,
+            );
+            let sessions = get("/public/terminal/sessions", &token)
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap();
+            let listed = sessions["sessions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|session| session["id"] == session_id.as_str());
+            assert_eq!(listed, visible);
+            let response = get(
+                &format!("/public/terminal/sessions/{session_id}/recording"),
+                &token,
+            )
+            .await
+            .unwrap();
+            let expected = match visible {
+                true => StatusCode::OK,
+                false => StatusCode::NOT_FOUND,
+            };
+            assert_eq!(response.status(), expected);
+        }
+
+        // Requires the terminal scope.
+        let token = server_test.token(ServerTest::an_hour_from_now(), &["logs:read"], None);
+        let response = get("/public/terminal/sessions", &token).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
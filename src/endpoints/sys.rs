@@ -1,9 +1,86 @@
-// GET /sys/shutdown
-//
-// Description: Shuts down the server
+use crate::endpoints::auth::{AuthError, Claims, Scope};
+use crate::AxumState;
+use axum::extract::{connect_info::ConnectInfo, FromRequestParts, State};
+use axum::http::request::Parts;
 use axum::Json;
 use axum_extra::extract::WithRejection;
+use ipnet::IpNet;
+use log::error;
+use once_cell::sync::Lazy;
 use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+static SYS_ALLOWED_NETS: Lazy<Option<Vec<IpNet>>> =
+    Lazy::new(|| parse_networks(&crate::config::get_config().sys_allowed_cidrs));
+
+/// Parses the networks allowed to use the /sys endpoints, `None` means any source is allowed.
+/// Plain addresses are accepted as single host networks, invalid entries are logged and skipped.
+fn parse_networks(cidrs: &[String]) -> Option<Vec<IpNet>> {
+    if cidrs.is_empty() {
+        return None;
+    }
+
+    let networks = cidrs
+        .iter()
+        .filter_map(|cidr| {
+            let cidr = cidr.trim();
+            match cidr
+                .parse::<IpNet>()
+                .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+            {
+                Ok(network) => Some(network),
+                Err(e) => {
+                    error!(
+                        "Ignoring invalid CIDR '{}' in sys_allowed_cidrs: {}",
+                        cidr, e
+                    );
+                    None
+                }
+            }
+        })
+        .collect();
+    Some(networks)
+}
+
+fn source_allowed(networks: &[IpNet], ip: IpAddr) -> bool {
+    // IPv4 clients connecting to a dual stack socket show up as IPv4-mapped IPv6 addresses.
+    let ip = ip.to_canonical();
+    networks.iter().any(|network| network.contains(&ip))
+}
+
+/// Extractor guarding the /sys endpoints: the request must come from one of the networks in
+/// `sys_allowed_cidrs` (when configured) and carry a token with the `sys:admin` scope.
+pub struct SysAdmin(pub Claims);
+
+impl<S> FromRequestParts<S> for SysAdmin
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(networks) = SYS_ALLOWED_NETS.as_ref() {
+            let ip = parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip());
+            match ip {
+                Some(ip) if source_allowed(networks, ip) => {}
+                ip => return Err(AuthError::SourceNotAllowed(ip)),
+            }
+        }
+
+        let claims = Claims::from_request_parts(parts, state).await?;
+        claims.require_scope(Scope::SysAdmin)?;
+        Ok(Self(claims))
+    }
+}
+
+// POST /sys/shutdown
+//
+// Description: Shuts down the agent gracefully, the response is sent before
+// services are stopped.
 
 #[derive(Deserialize)]
 pub struct ShutdownParams {
@@ -12,13 +89,14 @@ pub struct ShutdownParams {
 
 #[axum::debug_handler]
 pub async fn shutdown(
+    SysAdmin(claims): SysAdmin,
+    State(state): State<Arc<AxumState>>,
     params: WithRejection<Json<ShutdownParams>, crate::api_error::ApiError>,
 ) -> &'static str {
-    log::info!("Shutting down: {}", params.0.message);
-    tokio::spawn(async move {
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-        std::process::exit(0);
-    });
+    state.shutdown.trigger(&format!(
+        "requested by {} {}: {}",
+        claims.bearer_type, claims.bearer_id, params.0.message
+    ));
 
     "{}"
 }
@@ -26,12 +104,13 @@ pub async fn shutdown(
 // GET /sys/hello
 //
 // Description: Returns the cutiest Http response
-pub async fn hello() -> &'static str {
+pub async fn hello(_admin: SysAdmin) -> &'static str {
     "kitty"
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
     use crate::test_utils::*;
     use axum::http::StatusCode;
     use serde_json::json;
@@ -43,6 +122,7 @@ mod test {
         let response = server_test
             .client
             .get(server_test.url_for("/sys/hello"))
+            .bearer_auth(server_test.valid_token())
             .send()
             .await
             .unwrap();
@@ -59,6 +139,7 @@ mod test {
         let response = server_test
             .client
             .post(server_test.url_for("/sys/shutdown"))
+            .bearer_auth(server_test.valid_token())
             .json(&json!({"message": "shutting down"}))
             .send()
             .await
@@ -66,6 +147,79 @@ mod test {
 
         assert_eq!(response.status(), StatusCode::OK);
         assert_eq!(response.text().await.unwrap(), "{}");
+
+        // The server stops accepting requests.
+        let mut stopped = false;
+        for _ in 0..50 {
+            let client = reqwest::Client::new();
+            if client
+                .get(server_test.url_for("/sys/hello"))
+                .send()
+                .await
+                .is_err()
+            {
+                stopped = true;
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        assert!(stopped, "Server still running after shutdown");
+        assert!(server_test.services().stop().await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn unauthorized() {
+        let server_test = ServerTest::new(false).await;
+
+        let response = server_test
+            .client
+            .post(server_test.url_for("/sys/shutdown"))
+            .json(&json!({"message": "shutting down"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let token = server_test.token(
+            ServerTest::an_hour_from_now(),
+            &["services:read", "services:control", "logs:read"],
+            None,
+        );
+        let response = server_test
+            .client
+            .post(server_test.url_for("/sys/shutdown"))
+            .bearer_auth(token)
+            .json(&json!({"message": "shutting down"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "Token lacks the 'sys:admin' scope");
         assert!(server_test.services().stop().await.is_ok());
     }
+
+    #[test]
+    fn allowed_sources() {
+        assert!(parse_networks(&[]).is_none());
+
+        let networks = parse_networks(&[
+            "10.0.0.0/8".to_string(),
+            "192.168.1.10".to_string(),
+            "fd00::/8".to_string(),
+            "not a network".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(networks.len(), 3);
+
+        assert!(source_allowed(&networks, "10.1.2.3".parse().unwrap()));
+        assert!(source_allowed(&networks, "192.168.1.10".parse().unwrap()));
+        assert!(source_allowed(
+            &networks,
+            "::ffff:10.1.2.3".parse().unwrap()
+        ));
+        assert!(source_allowed(&networks, "fd12::1".parse().unwrap()));
+        assert!(!source_allowed(&networks, "192.168.1.11".parse().unwrap()));
+        assert!(!source_allowed(&networks, "127.0.0.1".parse().unwrap()));
+    }
 }
@@ -0,0 +1,119 @@
+use rand::{distr::Alphanumeric, RngExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Time a ticket can be used for after it is issued.
+pub const STREAM_TICKET_TTL: Duration = Duration::from_secs(30);
+const TICKET_LENGTH: usize = 43;
+
+/// Output streams a ticket can be issued for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+    CombinedOutput,
+    /// Combined output plus writing into stdin.
+    Attach,
+    AccessLog,
+}
+
+#[derive(Debug)]
+struct StreamTicket {
+    service_id: uuid::Uuid,
+    stream: OutputStream,
+    expires_at: Instant,
+}
+
+/// One-time tickets used to connect to the output websockets, so long lived tokens
+/// don't need to be sent in urls (and end up in access logs or browser history).
+///
+/// Tickets are bound to a service and a stream, expire after `STREAM_TICKET_TTL`
+/// and can be redeemed only once.
+#[derive(Debug, Default)]
+pub struct StreamTickets {
+    tickets: std::sync::Mutex<HashMap<String, StreamTicket>>,
+}
+
+impl StreamTickets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Issues a ticket for the given service and stream.
+    pub fn issue(&self, service_id: uuid::Uuid, stream: OutputStream) -> String {
+        let ticket: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(TICKET_LENGTH)
+            .map(char::from)
+            .collect();
+
+        let mut tickets = self.tickets.lock().unwrap();
+        // Expired tickets are dropped here, so unused ones don't pile up.
+        let now = Instant::now();
+        tickets.retain(|_, ticket| ticket.expires_at > now);
+        tickets.insert(
+            ticket.clone(),
+            StreamTicket {
+                service_id,
+                stream,
+                expires_at: now + STREAM_TICKET_TTL,
+            },
+        );
+        ticket
+    }
+
+    /// Consumes the ticket, returns whether it was valid for the service and stream.
+    pub fn redeem(&self, ticket: &str, service_id: uuid::Uuid, stream: OutputStream) -> bool {
+        match self.tickets.lock().unwrap().remove(ticket) {
+            Some(ticket) => {
+                ticket.service_id == service_id
+                    && ticket.stream == stream
+                    && ticket.expires_at > Instant::now()
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redeem() {
+        let tickets = StreamTickets::new();
+        let id = uuid::Uuid::new_v4();
+
+        let ticket = tickets.issue(id, OutputStream::Stdout);
+        assert_eq!(ticket.len(), TICKET_LENGTH);
+        assert!(tickets.redeem(&ticket, id, OutputStream::Stdout));
+        // Only once.
+        assert!(!tickets.redeem(&ticket, id, OutputStream::Stdout));
+
+        // Bound to the service and the stream, wrong attempts burn the ticket.
+        let ticket = tickets.issue(id, OutputStream::Stdout);
+        assert!(!tickets.redeem(&ticket, id, OutputStream::Stderr));
+        assert!(!tickets.redeem(&ticket, id, OutputStream::Stdout));
+        let ticket = tickets.issue(id, OutputStream::Stdout);
+        assert!(!tickets.redeem(&ticket, uuid::Uuid::new_v4(), OutputStream::Stdout));
+
+        assert!(!tickets.redeem("unknown", id, OutputStream::Stdout));
+    }
+
+    #[test]
+    fn expiration() {
+        let tickets = StreamTickets::new();
+        let id = uuid::Uuid::new_v4();
+        let ticket = tickets.issue(id, OutputStream::CombinedOutput);
+        tickets
+            .tickets
+            .lock()
+            .unwrap()
+            .get_mut(&ticket)
+            .unwrap()
+            .expires_at = Instant::now();
+        assert!(!tickets.redeem(&ticket, id, OutputStream::CombinedOutput));
+    }
+}
@@ -0,0 +1,49 @@
+use crate::tunnel::TunnelState;
+use log::debug;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+// Events are dropped for clients that fall further behind.
+const EVENTS_CHANNEL_SIZE: usize = 64;
+
+/// Something that happened in the agent, sent to the clients of `GET /public/events`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    TunnelStateChanged {
+        tunnel: String,
+        network: String,
+        state: TunnelState,
+    },
+}
+
+/// Local event stream of the agent. It is cheap to clone, every clone sends to (and
+/// subscribes to) the same stream.
+#[derive(Debug, Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CHANNEL_SIZE);
+        Self { sender }
+    }
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends the event to the current subscribers, if any.
+    pub fn send(&self, event: Event) {
+        debug!("Event: {:?}", event);
+        let _ = self.sender.send(event);
+    }
+
+    /// Returns a receiver of the events sent from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,248 @@
+use crate::config::WatchConfig;
+use crate::service::{ServiceStatus, Services};
+use bytes::Bytes;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, error, info};
+use notify::event::{EventKind, ModifyKind};
+use notify::{Event, RecursiveMode, Watcher};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::Duration;
+
+const DEFAULT_DEBOUNCE_MS: u64 = 500;
+
+#[derive(Debug, Error)]
+pub enum FileWatcherError {
+    #[error("Watch error: {0}")]
+    NotifyError(#[from] notify::Error),
+
+    #[error("Invalid ignore pattern: {0}")]
+    PatternError(#[from] globset::Error),
+}
+
+/// Watches (using inotify) the paths configured in the `watch` section of a service
+/// and restarts the service whenever something changes under them.
+///
+/// Changes are debounced, so saving several files at once results in a single restart,
+/// and a marker is written into the service stdout stream before restarting so
+/// clients following the output know what happened.
+///
+/// The watcher stops when dropped.
+pub struct FileWatcher {
+    _watcher: notify::RecommendedWatcher,
+    join_handle: tokio::task::JoinHandle<()>,
+}
+
+impl fmt::Debug for FileWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "FileWatcher")
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        self.join_handle.abort();
+    }
+}
+
+impl FileWatcher {
+    /// Starts watching the paths for the service with the given id.
+    ///
+    /// Arguments:
+    /// - `id`: The id of the service to restart.
+    /// - `config`: The watch configuration of the service.
+    /// - `services`: The services collection, used to stop and start the service.
+    pub fn new(
+        id: uuid::Uuid,
+        config: WatchConfig,
+        services: Arc<Services>,
+    ) -> Result<Self, FileWatcherError> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in config.ignore.unwrap_or_default() {
+            builder.add(Glob::new(&pattern)?);
+        }
+        let ignore = builder.build()?;
+
+        let (tx, rx) = unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+                Ok(event) => {
+                    // If the receiver is gone the watcher is being dropped.
+                    let _ = tx.send(event);
+                }
+                Err(e) => error!("Error watching files: {}", e),
+            })?;
+
+        let roots: Vec<PathBuf> = config.paths.iter().map(PathBuf::from).collect();
+        for root in roots.iter() {
+            watcher.watch(root, RecursiveMode::Recursive)?;
+        }
+
+        let debounce = Duration::from_millis(config.debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS));
+        let join_handle = tokio::spawn(Self::watch_task(id, services, rx, roots, ignore, debounce));
+
+        Ok(Self {
+            _watcher: watcher,
+            join_handle,
+        })
+    }
+
+    /// Waits for relevant changes and restarts the service once they settle.
+    async fn watch_task(
+        id: uuid::Uuid,
+        services: Arc<Services>,
+        mut rx: UnboundedReceiver<Event>,
+        roots: Vec<PathBuf>,
+        ignore: GlobSet,
+        debounce: Duration,
+    ) {
+        while let Some(event) = rx.recv().await {
+            let changed = match Self::relevant_path(&event, &roots, &ignore) {
+                Some(path) => path,
+                None => continue,
+            };
+            debug!(
+                "Change detected in {}, waiting for changes to settle.",
+                changed.display()
+            );
+
+            loop {
+                match tokio::time::timeout(debounce, rx.recv()).await {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => return,
+                    Err(_) => break,
+                }
+            }
+
+            Self::restart(id, &services, &changed).await;
+        }
+    }
+
+    /// Returns the first path of the event that should trigger a restart, if any.
+    /// Reads and metadata changes are discarded, as the service itself can cause them.
+    fn relevant_path(event: &Event, roots: &[PathBuf], ignore: &GlobSet) -> Option<PathBuf> {
+        match event.kind {
+            EventKind::Create(_) | EventKind::Remove(_) => {}
+            EventKind::Modify(ModifyKind::Metadata(_)) => return None,
+            EventKind::Modify(_) => {}
+            _ => return None,
+        }
+
+        event
+            .paths
+            .iter()
+            .find(|path| !Self::is_ignored(path, roots, ignore))
+            .cloned()
+    }
+
+    // Patterns are matched against the full path and against the path relative
+    // to the watched root, so both `**/*.log` and `tmp/**` do what you expect.
+    fn is_ignored(path: &Path, roots: &[PathBuf], ignore: &GlobSet) -> bool {
+        if ignore.is_match(path) {
+            return true;
+        }
+
+        roots.iter().any(|root| match path.strip_prefix(root) {
+            Ok(relative) => ignore.is_match(relative),
+            Err(_) => false,
+        })
+    }
+
+    async fn restart(id: uuid::Uuid, services: &Services, changed: &Path) {
+        let service = match services.fetch(id).await {
+            Some(service) => service,
+            None => {
+                error!("Service {} not found, cannot restart it.", id);
+                return;
+            }
+        };
+
+        let (name, status, stdout) = {
+            let service = service.lock().await;
+            (service.name(), service.status(), service.stdout())
+        };
+
+        // We don't want to bring back services that were explicitly stopped.
+        if !matches!(status, ServiceStatus::Running) {
+            debug!("Service '{}' is not running, skipping restart.", name);
+            return;
+        }
+
+        info!(
+            "Change detected in {}, restarting service '{}'.",
+            changed.display(),
+            name
+        );
+        stdout
+            .write(Bytes::from(format!(
+                "[kittengrid] Change detected in {}, restarting service.\n",
+                changed.display()
+            )))
+            .await;
+
+        if let Err(e) = services.stop_service(id).await {
+            error!("Error stopping service '{}' for restart: {}", name, e);
+            return;
+        }
+
+        if let Err(e) = services.start_service(id).await {
+            error!("Error starting service '{}' after restart: {}", name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ServiceConfig;
+    use crate::service::{Service, ServiceStream};
+    use crate::test_utils::initialize_tests;
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn restarts_on_change() {
+        initialize_tests();
+        let dir = tempdir().unwrap();
+        let watch = WatchConfig {
+            paths: vec![dir.path().to_str().unwrap().to_string()],
+            ignore: Some(vec!["*.log".to_string()]),
+            debounce_ms: Some(100),
+        };
+        let config = ServiceConfig {
+            name: "/bin/bash".to_string(),
+            args: Some(vec![
+                "-c".to_string(),
+                "echo started; exec sleep 100".to_string(),
+            ]),
+            watch: Some(watch.clone()),
+            ..Default::default()
+        };
+
+        let services = Arc::new(Services::new());
+        let service = Service::from(config);
+        let id = service.id();
+        services.insert(service).await;
+        services.start_service(id).await.unwrap();
+
+        let mut receiver = services
+            .subscribe_to_stream(id, ServiceStream::Stdout)
+            .await
+            .unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), "started\n");
+
+        let _watcher = FileWatcher::new(id, watch, services.clone()).unwrap();
+        std::fs::write(dir.path().join("output.log"), "ignored").unwrap();
+        std::fs::write(dir.path().join("main.rs"), "changed").unwrap();
+
+        let marker = receiver.recv().await.unwrap();
+        let marker = String::from_utf8_lossy(&marker);
+        assert!(marker.starts_with("[kittengrid] Change detected in"));
+        assert!(marker.contains("main.rs"), "unexpected marker: {}", marker);
+        assert_eq!(receiver.recv().await.unwrap(), "started\n");
+
+        services.stop().await.unwrap();
+    }
+}
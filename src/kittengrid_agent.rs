@@ -1,10 +1,14 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 
 // This is mainly to abstract the agent itself, so we can
 // use it more easily in tests.
 use super::config::Config;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+
+/// File of the keys dir where the WireGuard private key is saved.
+const WIREGUARD_KEY_FILE: &str = "wireguard.key";
 
 #[derive(Debug, Default)]
 pub struct KittengridAgent {
@@ -12,6 +16,15 @@ pub struct KittengridAgent {
     api: Option<crate::kittengrid_api::KittengridApi>,
     services: Arc<crate::service::Services>,
     local_addr: Option<std::net::SocketAddr>,
+    watchers: tokio::sync::Mutex<Vec<crate::file_watcher::FileWatcher>>,
+    tunnels: Vec<Arc<dyn crate::tunnel::Tunnel>>,
+    tunnel_monitor: Option<tokio::task::JoinHandle<()>>,
+    keypair: Option<Arc<crate::wireguard::keys::KeyPair>>,
+    events: crate::events::Events,
+    terminal: Option<crate::ttyd::Supervisor>,
+    shutdown: crate::shutdown::Shutdown,
+    // Services already published, so they are not published again when it is retried.
+    published: tokio::sync::Mutex<std::collections::HashSet<uuid::Uuid>>,
 }
 
 use thiserror::Error;
@@ -25,9 +38,29 @@ pub enum KittengridAgentError {
     #[error("Api Error: ({0})")]
     KittengridApiError(#[from] crate::kittengrid_api::KittengridApiError),
     #[error("Wireguard Error: ({0})")]
-    WireguardError(#[from] Box<dyn std::error::Error>),
+    WireguardError(#[from] crate::wireguard::WireguardError),
     #[error("Service Spawn Error: ({0})")]
     ServiceSpawnError(#[from] std::io::Error),
+    #[error("{0}")]
+    InvalidTunnelBackend(String),
+    #[error("Data Dir Error: ({0})")]
+    DataDirError(#[from] crate::data_dir::DataDirError),
+    #[error("Terminal Error: ({0})")]
+    TerminalError(#[from] crate::ttyd::Error),
+    #[error("Service {0} is defined more than once.")]
+    DuplicateServiceError(String),
+    #[error("Service {0} is proxied under {1}, which is used by the agent endpoints.")]
+    ReservedPathError(String, String),
+}
+
+impl KittengridAgentError {
+    /// Whether it comes from an api call that may succeed later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            KittengridAgentError::KittengridApiError(e) => e.is_transient(),
+            _ => false,
+        }
+    }
 }
 
 impl KittengridAgent {
@@ -35,6 +68,16 @@ impl KittengridAgent {
         self.services.clone()
     }
 
+    /// Returns the local event stream of the agent.
+    pub fn events(&self) -> crate::events::Events {
+        self.events.clone()
+    }
+
+    /// Returns the handle used to trigger the agent shutdown.
+    pub fn shutdown_handle(&self) -> crate::shutdown::Shutdown {
+        self.shutdown.clone()
+    }
+
     pub fn new(config: Config) -> Self {
         Self {
             config,
@@ -43,15 +86,48 @@ impl KittengridAgent {
     }
 
     /// Reads config and initializes log
-    pub async fn init(&self) {
+    ///
+    /// Service ids are derived from their names, so names must be unique.
+    pub async fn init(&self) -> Result<(), KittengridAgentError> {
         info!("Starting kittengrid with config: {:?}.", self.config);
         debug!("Config read: {:?}", self.config);
 
+        let mut names = std::collections::HashSet::new();
+        if let Some(service) = self
+            .config
+            .services
+            .iter()
+            .find(|service| !names.insert(&service.name))
+        {
+            return Err(KittengridAgentError::DuplicateServiceError(
+                service.name.clone(),
+            ));
+        }
+
+        // Requests for the host of a service reach it whatever their path, the others are
+        // matched against the agent endpoints first.
+        if self.config.proxy.enabled() {
+            for service in self.config.services.iter() {
+                let description = crate::service::ServiceDescription::from(service.clone());
+                if let Some(prefix) = description
+                    .proxy_path_prefix()
+                    .filter(|_| description.proxy_host().is_none())
+                    .filter(|prefix| is_agent_path(prefix))
+                {
+                    return Err(KittengridAgentError::ReservedPathError(
+                        service.name.clone(),
+                        prefix,
+                    ));
+                }
+            }
+        }
+
         debug!("Adding services to agent.");
         for service in self.config.services.iter() {
-            let service: super::service::Service = service.clone().into();
+            let service = super::service::Service::new(&self.config, service.clone());
             (*self.services).insert(service).await;
         }
+        Ok(())
     }
 
     /// Registers the agent with the kittengrid API and obtains a token to be
@@ -67,12 +143,19 @@ impl KittengridAgent {
         }
     }
 
-    /// Configures local network with wireguard tunnels.
+    /// Configures local network with tunnels (see the `tunnel` option).
     pub async fn configure_network(&mut self) -> Result<(), KittengridAgentError> {
         if self.api.is_none() {
             return Err(KittengridAgentError::NotRegisteredError);
         }
 
+        let backend: crate::tunnel::TunnelBackend = self
+            .config
+            .tunnel
+            .parse()
+            .map_err(KittengridAgentError::InvalidTunnelBackend)?;
+
+        let keypair = self.keypair()?;
         let kg_api = self.api.as_ref().unwrap();
 
         if self.local_addr.is_none() {
@@ -80,14 +163,42 @@ impl KittengridAgent {
         }
 
         // Fetch network configuration
-        let peers = match kg_api.peers_create(self.local_addr.unwrap().port()).await {
+        let peers = match kg_api
+            .peers_create(self.local_addr.unwrap().port(), &keypair.public_key())
+            .await
+        {
             Ok(peers) => peers,
             Err(e) => {
                 return Err(KittengridAgentError::KittengridApiError(e));
             }
         };
+        let peers: Vec<_> = peers
+            .into_iter()
+            .map(|mut peer| {
+                if peer.private_key().is_some() {
+                    // Older api versions generate the keys of the agent.
+                    info!(
+                        "Using the private key sent by the api for network {}.",
+                        peer.network()
+                    );
+                } else {
+                    peer.set_private_key(keypair.private_key());
+                }
+                peer
+            })
+            .collect();
+
+        let network = &self.config.network;
+        let settings = peers
+            .iter()
+            .enumerate()
+            .map(|(index, peer)| crate::wireguard::InterfaceSettings::new(network, peer, index))
+            .collect::<Result<Vec<_>, _>>()?;
+        backend.remove_stale(&network.interface_prefix());
+        backend.check_conflicts(&settings)?;
 
-        for (device_counter, peer) in peers.iter().enumerate() {
+        let mut monitored = Vec::new();
+        for (device_counter, (peer, settings)) in peers.iter().zip(&settings).enumerate() {
             let endpoint = match kg_api.peers_get_endpoint(peer.network()).await {
                 Ok(endpoint) => endpoint,
                 Err(e) => {
@@ -95,27 +206,38 @@ impl KittengridAgent {
                 }
             };
 
-            // Set up wireguard tunnel for the peer
-            let device = match super::wireguard::WireGuard::new(device_counter).await {
+            // Set up the tunnel for the peer
+            let device = match backend.create(device_counter, settings) {
                 Ok(device) => device,
                 Err(e) => {
                     return Err(KittengridAgentError::WireguardError(e));
                 }
             };
 
-            match device.set_config(peer, &endpoint).await {
+            let device: Arc<dyn crate::tunnel::Tunnel> = device.into();
+            self.tunnels.push(device.clone());
+            match device.set_config(peer, &endpoint) {
                 Ok(_) => {
-                    info!(
-                        "Successfully configured wireguard device {}.",
-                        device.name()
-                    );
+                    info!("Successfully configured tunnel {}.", device.name());
                 }
                 Err(e) => {
+                    // The agent exits, don't leave half configured tunnels behind.
+                    self.teardown_tunnels();
                     return Err(KittengridAgentError::WireguardError(e));
                 }
             }
+            monitored.push((device, peer.clone()));
         }
 
+        self.tunnel_monitor = Some(
+            crate::tunnel::monitor::TunnelMonitor::new(
+                monitored,
+                self.api.clone(),
+                self.events.clone(),
+            )
+            .spawn(self.shutdown.clone()),
+        );
+
         Ok(())
     }
 
@@ -136,13 +258,15 @@ impl KittengridAgent {
         };
     }
 
-    /// Starts services in the agent.
+    /// Starts services in the agent, in the order they are defined.
     pub async fn spawn_services(
         &self,
         show_services_output: bool,
     ) -> Result<(), KittengridAgentError> {
-        for (id, service) in self.services.descriptions().await {
-            let name = service.name();
+        let descriptions = self.services.descriptions().await;
+        for id in self.services.start_order().await {
+            let description = descriptions[&id].clone();
+            let name = description.name();
             info!("Spawning service: {} ({}).", id, name);
             let service = self.services.fetch(id).await;
             let service = service.unwrap();
@@ -155,17 +279,39 @@ impl KittengridAgent {
                 error!("Failed to spawn service: {}.", name);
                 return Err(KittengridAgentError::ServiceSpawnError(e));
             }
+            drop(service);
+
+            if let Some(watch) = description.watch() {
+                self.watch_service(id, watch).await;
+            }
         }
         Ok(())
     }
 
-    /// Publishes services to the kittengrid API.
+    /// Restarts the service whenever any of the watched paths change.
+    /// Errors are only logged, a broken watch config should not prevent the service from running.
+    async fn watch_service(&self, id: uuid::Uuid, watch: crate::config::WatchConfig) {
+        match crate::file_watcher::FileWatcher::new(id, watch, self.services()) {
+            Ok(watcher) => {
+                info!("Watching files for service {}.", id);
+                self.watchers.lock().await.push(watcher);
+            }
+            Err(e) => error!("Failed to watch files for service {}: {}.", id, e),
+        }
+    }
+
+    /// Publishes services to the kittengrid API, the ones published by a previous call are
+    /// skipped.
     pub async fn publish_services(&self) -> Result<(), KittengridAgentError> {
         if self.api.is_none() {
             return Err(KittengridAgentError::NotRegisteredError);
         }
         let services = self.services();
+        let mut published = self.published.lock().await;
         for (id, service) in services.descriptions().await {
+            if published.contains(&id) {
+                continue;
+            }
             // Register with API
             if let Err(e) = self
                 .api
@@ -177,6 +323,7 @@ impl KittengridAgent {
                 error!("Failed to publish service: {}.", service.name());
                 return Err(KittengridAgentError::KittengridApiError(e));
             };
+            published.insert(id);
         }
         Ok(())
     }
@@ -191,18 +338,28 @@ impl KittengridAgent {
             // Register with API
             let healthcheck_path = service.health_check().map(|hc| hc.path.clone());
 
+            // Behind the proxy services are reached through the agent port.
+            let (port, path, healthcheck_path) = if self.config.proxy.enabled() {
+                let port = self
+                    .local_addr
+                    .ok_or(KittengridAgentError::NotListeningError)?
+                    .port();
+                let prefix = service.proxy_path_prefix();
+                let healthcheck_path = match &prefix {
+                    Some(prefix) if service.proxy_strip_prefix() => healthcheck_path
+                        .map(|path| format!("{}{}", prefix.trim_end_matches('/'), path)),
+                    _ => healthcheck_path,
+                };
+                (port, prefix, healthcheck_path)
+            } else {
+                (service.port(), None, healthcheck_path)
+            };
+
             let public_url = self
                 .api
                 .as_ref()
                 .unwrap()
-                .peers_create_service(
-                    id,
-                    &service.name(),
-                    service.port(),
-                    healthcheck_path,
-                    None,
-                    false,
-                )
+                .peers_create_service(id, &service.name(), port, healthcheck_path, path, false)
                 .await?;
 
             info!(
@@ -219,6 +376,22 @@ impl KittengridAgent {
         Ok(())
     }
 
+    /// Starts ttyd (see the `terminal` configuration) under `/{id}`, it is restarted
+    /// whenever it exits until the agent shuts down. Returns the port it listens on.
+    pub async fn start_terminal(&mut self, id: uuid::Uuid) -> Result<u16, KittengridAgentError> {
+        let terminal = crate::ttyd::Supervisor::start(
+            crate::ttyd::Executable::install()?,
+            id,
+            format!("/{}", id),
+            self.config.terminal.clone(),
+            self.api.clone(),
+        )
+        .await?;
+        let port = terminal.port();
+        self.terminal = Some(terminal);
+        Ok(port)
+    }
+
     pub async fn register_service(
         &self,
         id: uuid::Uuid,
@@ -250,13 +423,23 @@ impl KittengridAgent {
         }
     }
 
-    /// Binds the agent to the network returining a listener.
+    /// Binds the agent to the network returining a listener. The unspecified IPv6 address
+    /// (the default) is dual stack, it falls back to `0.0.0.0` on hosts without IPv6.
     pub async fn bind(&mut self) -> tokio::net::TcpListener {
-        let listener = tokio::net::TcpListener::bind(format!(
-            "{}:{}",
-            self.config.bind_address, self.config.bind_port
-        ))
-        .await
+        let address = self.config.bind_address.as_str();
+        let port = self.config.bind_port;
+        let listener = match address.parse::<IpAddr>() {
+            Ok(ip) => match listen(SocketAddr::new(ip, port)) {
+                Err(e) if ip == IpAddr::V6(Ipv6Addr::UNSPECIFIED) => {
+                    warn!("Can't listen on IPv6 ({}), listening on IPv4 only.", e);
+                    listen(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+                }
+                result => result,
+            }
+            .and_then(tokio::net::TcpListener::from_std),
+            // A host name
+            Err(_) => tokio::net::TcpListener::bind((address, port)).await,
+        }
         .unwrap();
         let addr = listener.local_addr().unwrap();
         self.local_addr = Some(addr);
@@ -265,7 +448,172 @@ impl KittengridAgent {
         listener
     }
 
+    /// Serves requests until the shutdown is triggered.
     pub async fn wait(&self, listener: tokio::net::TcpListener) {
-        crate::launch(listener, Arc::clone(&self.services)).await;
+        crate::launch(
+            listener,
+            Arc::clone(&self.services),
+            self.events(),
+            self.shutdown_handle(),
+        )
+        .await;
+    }
+
+    /// Stops everything the agent started: file watchers, services (in the reverse order
+    /// they are defined, giving each its grace period), the terminal and tunnels.
+    pub async fn shutdown(&self) {
+        self.set_status(crate::kittengrid_api::PullRequestStatus::ShuttingDown)
+            .await;
+
+        self.watchers.lock().await.clear();
+
+        info!("Stopping services.");
+        if let Err(e) = self.services.stop().await {
+            error!("Failed to stop services: {}.", e);
+        }
+
+        if let Some(terminal) = &self.terminal {
+            info!("Stopping terminal.");
+            terminal.stop().await;
+        }
+
+        if let Some(monitor) = &self.tunnel_monitor {
+            monitor.abort();
+        }
+        self.teardown_tunnels();
+    }
+
+    /// WireGuard keypair of the agent, generated once. It is saved in the data dir (and
+    /// loaded from it) when `network.persist_private_key` is set.
+    fn keypair(&mut self) -> Result<Arc<crate::wireguard::keys::KeyPair>, KittengridAgentError> {
+        if let Some(keypair) = &self.keypair {
+            return Ok(keypair.clone());
+        }
+
+        let keypair = if self.config.network.persist_private_key() {
+            let path = crate::data_dir::get_data_dir()
+                .keys_path()?
+                .join(WIREGUARD_KEY_FILE);
+            crate::wireguard::keys::KeyPair::load_or_generate(&path)?
+        } else {
+            crate::wireguard::keys::KeyPair::generate()
+        };
+        debug!("WireGuard public key: {}", keypair.public_key());
+        let keypair = Arc::new(keypair);
+        self.keypair = Some(keypair.clone());
+        Ok(keypair)
+    }
+
+    fn teardown_tunnels(&self) {
+        for tunnel in self.tunnels.iter() {
+            match tunnel.teardown() {
+                Ok(()) => info!("Removed tunnel {}.", tunnel.name()),
+                Err(e) => error!("Failed to remove tunnel {}: {}.", tunnel.name(), e),
+            }
+        }
+    }
+}
+
+// Whether requests under the path prefix could match an endpoint of the agent.
+fn is_agent_path(prefix: &str) -> bool {
+    ["/public", "/sys"].iter().any(|agent| {
+        prefix
+            .strip_prefix(agent)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Listens on `addr`, the unspecified IPv6 address accepts IPv4 connections too (whatever
+/// `net.ipv6.bindv6only` says).
+fn listen(addr: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!addr.ip().is_unspecified())?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn dual_stack_listener() {
+        let listener =
+            tokio::net::TcpListener::from_std(listen("[::]:0".parse().unwrap()).unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for address in ["127.0.0.1", "::1"] {
+            let client = tokio::net::TcpStream::connect((address, port));
+            let (client, accepted) = tokio::join!(client, listener.accept());
+            let peer = accepted.unwrap().1.ip().to_canonical();
+            assert_eq!(peer, client.unwrap().local_addr().unwrap().ip());
+        }
+
+        // IPv4 only
+        let listener = listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        assert!(listener.local_addr().unwrap().is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn duplicate_service_names() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut file,
+            b"services:\n  - name: web\n    port: 3000\n  - name: web\n    port: 3001\n",
+        )
+        .unwrap();
+        let config = crate::config::load(Some(file.path().to_path_buf()));
+        assert_eq!(config.services.len(), 2);
+
+        let agent = KittengridAgent::new(config);
+        assert!(matches!(
+            agent.init().await,
+            Err(KittengridAgentError::DuplicateServiceError(name)) if name == "web"
+        ));
+        assert!(agent.services().ids_by_name("web").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn publish_services_once() {
+        use crate::kittengrid_api::test::{api, mock_api, reply};
+        let mut config = crate::config::get_config().clone();
+        config.services = ["web", "db"]
+            .iter()
+            .map(|name| crate::config::ServiceConfig {
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let mut agent = KittengridAgent::new(config);
+        agent.init().await.unwrap();
+
+        // The second service fails, only it is published again.
+        let (api_url, requests) =
+            mock_api(vec![reply(200, "{}"), reply(422, "Oops"), reply(200, "{}")]).await;
+        agent.api = Some(api(api_url));
+        assert!(agent.publish_services().await.is_err());
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+        agent.publish_services().await.unwrap();
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 3);
+        agent.publish_services().await.unwrap();
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn agent_paths() {
+        assert!(is_agent_path("/public"));
+        assert!(is_agent_path("/sys/hello"));
+        assert!(!is_agent_path("/"));
+        assert!(!is_agent_path("/publication"));
+        assert!(!is_agent_path("/app/public"));
     }
 }
@@ -3,12 +3,16 @@ use serde::Deserialize;
 use std::fmt;
 use uuid::Uuid;
 
+mod retry;
+pub use retry::RetryPolicy;
+
 #[derive(Debug, Clone)]
 pub struct KittengridApi {
     api_token: String,
     api_url: String,
     client: reqwest::Client,
     config: Config,
+    retry_policy: RetryPolicy,
 }
 
 use thiserror::Error;
@@ -27,27 +31,47 @@ pub enum KittengridApiError {
     #[error("ApiStatusError: {0}")]
     ApiStatusError(String),
 
+    #[error("Api unavailable ({0}): {1}")]
+    UnavailableError(u16, String),
+
     #[error("DeserializationError: {0}")]
     DeserializationError(String),
 }
 
+impl KittengridApiError {
+    /// Whether the call can be made again and may succeed: the api could not be reached, or
+    /// it asked for a retry (429 and 503, e.g. while it is deployed). Other server errors
+    /// and timeouts are not, a call that is not idempotent may have been processed (and the
+    /// idempotent ones were already retried).
+    pub fn is_transient(&self) -> bool {
+        match self {
+            KittengridApiError::RequestError(e) => e.is_connect(),
+            KittengridApiError::UnavailableError(status, _) => *status == 429 || *status == 503,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct RegisterAgentResponse {
     token: String,
 }
 
 pub async fn from_registration(config: &Config) -> Result<KittengridApi, KittengridApiError> {
-    let client = reqwest::Client::new();
-    let res = client
-        .post(format!("{}/api/agents/register", config.api_url))
-        .json(&serde_json::json!({
-            "vcs_provider": config.vcs_provider,
-            "pull_request_vcs_id": config.pull_request_vcs_id,
-            "project_vcs_path": config.project_vcs_path,
-            "workflow_run_id": config.workflow_run_id,
-        }))
-        .header("Authorization", format!("Bearer {}", config.api_key))
-        .send()
+    let retry_policy = RetryPolicy::from(&config.api_client);
+    let client = retry_policy.client()?;
+    let res = retry_policy
+        .send(
+            client
+                .post(format!("{}/api/agents/register", config.api_url))
+                .json(&serde_json::json!({
+                    "vcs_provider": config.vcs_provider,
+                    "pull_request_vcs_id": config.pull_request_vcs_id,
+                    "project_vcs_path": config.project_vcs_path,
+                    "workflow_run_id": config.workflow_run_id,
+                }))
+                .header("Authorization", format!("Bearer {}", config.api_key)),
+        )
         .await;
 
     match res {
@@ -61,6 +85,7 @@ pub async fn from_registration(config: &Config) -> Result<KittengridApi, Kitteng
                             config: config.clone(),
                             api_url: config.api_url.clone(),
                             client,
+                            retry_policy,
                         })
                     }
                     Err(e) => Err(KittengridApiError::DeserializationError(e.to_string())),
@@ -75,10 +100,22 @@ pub async fn from_registration(config: &Config) -> Result<KittengridApi, Kitteng
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Peer {
-    address: std::net::Ipv4Addr,
+    address: std::net::IpAddr,
+    // Addresses of the agent in the network apart from `address` (e.g. an IPv6 one).
+    #[serde(default)]
+    addresses: Vec<std::net::IpAddr>,
     public_key: String,
-    private_key: String,
+    // Only sent by api versions that generate the keys, the agent sets its own otherwise.
+    private_key: Option<String>,
     network: String,
+    // Networks routed through the tunnel apart from `network`.
+    #[serde(default)]
+    networks: Vec<String>,
+    // Overrides of the `network` config for this peer.
+    mtu: Option<u32>,
+    listen_port: Option<u16>,
+    persistent_keepalive: Option<u16>,
+    fwmark: Option<u32>,
 }
 
 impl Peer {
@@ -88,18 +125,53 @@ impl Peer {
     pub fn public_key(&self) -> String {
         self.public_key.clone()
     }
-    pub fn private_key(&self) -> String {
+    pub fn private_key(&self) -> Option<String> {
         self.private_key.clone()
     }
-    pub fn address(&self) -> std::net::Ipv4Addr {
+    pub fn set_private_key(&mut self, private_key: String) {
+        self.private_key = Some(private_key);
+    }
+    pub fn address(&self) -> std::net::IpAddr {
         self.address
     }
+    /// All the addresses of the agent in the network, `address` first.
+    pub fn addresses(&self) -> Vec<std::net::IpAddr> {
+        let mut addresses = vec![self.address];
+        for address in &self.addresses {
+            if !addresses.contains(address) {
+                addresses.push(*address);
+            }
+        }
+        addresses
+    }
+    /// All the networks routed through the tunnel, `network` first.
+    pub fn networks(&self) -> Vec<String> {
+        let mut networks = vec![self.network.clone()];
+        for network in &self.networks {
+            if !networks.contains(network) {
+                networks.push(network.clone());
+            }
+        }
+        networks
+    }
+    pub fn mtu(&self) -> Option<u32> {
+        self.mtu
+    }
+    pub fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+    }
+    pub fn persistent_keepalive(&self) -> Option<u16> {
+        self.persistent_keepalive
+    }
+    pub fn fwmark(&self) -> Option<u32> {
+        self.fwmark
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Endpoint {
     public_url: String,
-    address: std::net::Ipv4Addr,
+    address: std::net::IpAddr,
     public_key: String,
     network: String,
 }
@@ -111,7 +183,7 @@ impl Endpoint {
     pub fn public_key(&self) -> String {
         self.public_key.clone()
     }
-    pub fn address(&self) -> std::net::Ipv4Addr {
+    pub fn address(&self) -> std::net::IpAddr {
         self.address
     }
     pub fn public_url(&self) -> String {
@@ -180,11 +252,13 @@ impl KittengridApi {
         status: PullRequestStatus,
     ) -> Result<(), KittengridApiError> {
         let res = self
-            .put("api/agents/pull_request")
-            .json(&serde_json::json!({
-                "status": status.to_string(),
-            }))
-            .send()
+            .retry_policy
+            .send(
+                self.put("api/agents/pull_request")
+                    .json(&serde_json::json!({
+                        "status": status.to_string(),
+                    })),
+            )
             .await;
         match res {
             Ok(res) => {
@@ -205,13 +279,12 @@ impl KittengridApi {
         name: String,
     ) -> Result<(), KittengridApiError> {
         let res = self
-            .post("api/agents/service")
-            .json(&serde_json::json!({
+            .retry_policy
+            .send(self.post("api/agents/service").json(&serde_json::json!({
                 "name": name,
                 "id": id.to_string(),
                 "sha": self.config.last_commit_sha,
-            }))
-            .send()
+            })))
             .await;
         match res {
             Ok(res) => {
@@ -225,15 +298,20 @@ impl KittengridApi {
         }
     }
 
-    // Requests Kittengrid Api to create peers
-    // It returns a list of peers ready to be configured.
-    pub async fn peers_create(&self, bind_port: u16) -> Result<Vec<Peer>, KittengridApiError> {
+    // Requests Kittengrid Api to create peers with the public key of the agent.
+    // It returns a list of peers ready to be configured, but for the private key when the
+    // api does not generate it.
+    pub async fn peers_create(
+        &self,
+        bind_port: u16,
+        public_key: &str,
+    ) -> Result<Vec<Peer>, KittengridApiError> {
         let res = self
-            .post("api/peers")
-            .json(&serde_json::json!({
-                "bind_port": bind_port
-            }))
-            .send()
+            .retry_policy
+            .send(self.post("api/peers").json(&serde_json::json!({
+                "bind_port": bind_port,
+                "public_key": public_key,
+            })))
             .await;
         match res {
             Ok(res) => {
@@ -277,7 +355,10 @@ impl KittengridApi {
             data["path"] = serde_json::Value::String(path);
         }
 
-        let res = self.post("api/peers/service").json(&data).send().await;
+        let res = self
+            .retry_policy
+            .send(self.post("api/peers/service").json(&data))
+            .await;
         match res {
             Ok(res) => {
                 if !res.status().is_success() {
@@ -296,8 +377,8 @@ impl KittengridApi {
 
     pub async fn peers_get_endpoint(&self, cidr: String) -> Result<Endpoint, KittengridApiError> {
         let res = self
-            .get(format!("api/peers/endpoint?cidr={}", cidr).as_str())
-            .send()
+            .retry_policy
+            .send(self.get(format!("api/peers/endpoint?cidr={}", cidr).as_str()))
             .await;
 
         match res {
@@ -316,6 +397,39 @@ impl KittengridApi {
         }
     }
 
+    /// Reports the state of the tunnel to the given network (see `TunnelMonitor`).
+    pub async fn peers_update_status(
+        &self,
+        cidr: String,
+        state: crate::tunnel::TunnelState,
+        stats: &crate::tunnel::TunnelStats,
+    ) -> Result<(), KittengridApiError> {
+        let last_handshake = stats
+            .last_handshake
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|time| time.as_secs());
+        let res = self
+            .retry_policy
+            .send(self.put("api/peers/status").json(&serde_json::json!({
+                "cidr": cidr,
+                "status": state.to_string(),
+                "last_handshake": last_handshake,
+                "rx_bytes": stats.rx_bytes,
+                "tx_bytes": stats.tx_bytes,
+            })))
+            .await;
+        match res {
+            Ok(res) => {
+                if res.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(process_api_status_error_from_response(res).await)
+                }
+            }
+            Err(e) => Err(KittengridApiError::RequestError(e)),
+        }
+    }
+
     // Updates the status of a given service
     pub async fn services_update_status(
         &self,
@@ -348,9 +462,11 @@ impl KittengridApi {
         }
 
         let res = self
-            .put(&format!("api/services/{}", id))
-            .json(&serde_json::Value::Object(payload))
-            .send()
+            .retry_policy
+            .send(
+                self.put(&format!("api/services/{}", id))
+                    .json(&serde_json::Value::Object(payload)),
+            )
             .await;
         match res {
             Ok(res) => {
@@ -384,15 +500,18 @@ impl KittengridApi {
 }
 
 pub async fn process_api_status_error_from_response(res: reqwest::Response) -> KittengridApiError {
-    if res.status().as_u16() == 401 {
+    let status = res.status();
+    if status.as_u16() == 401 {
         KittengridApiError::UnauthorizedError(res.text().await.unwrap())
+    } else if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        KittengridApiError::UnavailableError(status.as_u16(), res.text().await.unwrap_or_default())
     } else {
         KittengridApiError::ApiStatusError(res.text().await.unwrap())
     }
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     // We need to stub the API calls
     #[ignore]
     #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
@@ -440,7 +559,8 @@ mod test {
         let kittengrid_api = crate::kittengrid_api::from_registration(crate::config::get_config())
             .await
             .unwrap();
-        let peers = kittengrid_api.peers_create(0).await.unwrap();
+        let public_key = crate::wireguard::keys::KeyPair::generate().public_key();
+        let peers = kittengrid_api.peers_create(0, &public_key).await.unwrap();
         assert!(!peers.is_empty());
     }
 
@@ -451,11 +571,170 @@ mod test {
         let kittengrid_api = crate::kittengrid_api::from_registration(crate::config::get_config())
             .await
             .unwrap();
-        let peers = kittengrid_api.peers_create(0).await.unwrap();
+        let public_key = crate::wireguard::keys::KeyPair::generate().public_key();
+        let peers = kittengrid_api.peers_create(0, &public_key).await.unwrap();
         let endpoint = kittengrid_api
             .peers_get_endpoint(peers[0].network.clone())
             .await
             .unwrap();
         assert!(!endpoint.public_url.is_empty());
     }
+
+    use super::*;
+    use axum::{body::Body, http::Response, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    pub(crate) struct Reply {
+        status: u16,
+        body: &'static str,
+        retry_after: Option<&'static str>,
+        delay: Duration,
+    }
+
+    pub(crate) fn reply(status: u16, body: &'static str) -> Reply {
+        Reply {
+            status,
+            body,
+            retry_after: None,
+            delay: Duration::ZERO,
+        }
+    }
+
+    // Api answering with the replies in order (the last one once they run out), it
+    // returns its url and the number of requests it got.
+    pub(crate) async fn mock_api(replies: Vec<Reply>) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().fallback({
+            let requests = requests.clone();
+            move || {
+                let reply = replies[requests
+                    .fetch_add(1, Ordering::SeqCst)
+                    .min(replies.len() - 1)]
+                .clone();
+                async move {
+                    tokio::time::sleep(reply.delay).await;
+                    let mut response = Response::builder()
+                        .status(reply.status)
+                        .header("content-type", "application/json");
+                    if let Some(retry_after) = reply.retry_after {
+                        response = response.header("retry-after", retry_after);
+                    }
+                    response.body(Body::from(reply.body)).unwrap()
+                }
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, requests)
+    }
+
+    pub(crate) fn api(api_url: String) -> KittengridApi {
+        let retry_policy = RetryPolicy {
+            timeout: Duration::from_millis(200),
+            connect_timeout: Duration::from_millis(200),
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        };
+        KittengridApi {
+            api_token: "token".to_string(),
+            api_url,
+            client: retry_policy.client().unwrap(),
+            config: crate::config::get_config().clone(),
+            retry_policy,
+        }
+    }
+
+    #[tokio::test]
+    async fn register_retries() {
+        let (api_url, requests) = mock_api(vec![
+            Reply {
+                retry_after: Some("0"),
+                ..reply(503, "Deploying")
+            },
+            reply(429, "Slow down"),
+            reply(200, r#"{"token": "meow"}"#),
+        ])
+        .await;
+        let mut config = crate::config::get_config().clone();
+        config.api_url = api_url;
+        config.api_client = crate::config::ApiClientConfig {
+            initial_backoff_ms: Some(10),
+            ..Default::default()
+        };
+
+        let api = from_registration(&config).await.unwrap();
+        assert_eq!(api.api_token, "meow");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        // Idempotent calls are retried on server errors.
+        let (api_url, requests) = mock_api(vec![
+            reply(500, "Oops"),
+            reply(502, "Oops"),
+            reply(200, "{}"),
+        ])
+        .await;
+        api(api_url)
+            .agents_update_pull_request(PullRequestStatus::Running)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Other calls are not, they may have been processed.
+        let (api_url, requests) = mock_api(vec![reply(500, "Oops"), reply(200, "[]")]).await;
+        let e = api(api_url).peers_create(0, "key").await.unwrap_err();
+        assert!(matches!(e, KittengridApiError::UnavailableError(500, _)));
+        assert!(!e.is_transient());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Client errors are not retried.
+        let (api_url, requests) = mock_api(vec![reply(401, "Access denied")]).await;
+        let e = api(api_url)
+            .peers_get_endpoint("10.0.0.0/24".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(e, KittengridApiError::UnauthorizedError(_)));
+        assert!(!e.is_transient());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up() {
+        let (api_url, requests) = mock_api(vec![reply(503, "Deploying")]).await;
+        let e = api(api_url)
+            .agents_create_service(Uuid::new_v4(), "web".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(e, KittengridApiError::UnavailableError(503, _)));
+        assert!(e.is_transient());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Timeouts
+        let (api_url, requests) = mock_api(vec![Reply {
+            delay: Duration::from_secs(1),
+            ..reply(200, "{}")
+        }])
+        .await;
+        let e = api(api_url)
+            .peers_get_endpoint("10.0.0.0/24".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(e, KittengridApiError::RequestError(ref e) if e.is_timeout()));
+        assert!(!e.is_transient());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Nothing listening
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let e = api(api_url).peers_create(0, "key").await.unwrap_err();
+        assert!(e.is_transient());
+    }
 }
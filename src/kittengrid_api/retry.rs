@@ -0,0 +1,221 @@
+use crate::config::ApiClientConfig;
+use headers::Header;
+use log::warn;
+use rand::RngExt;
+use reqwest::{header::RETRY_AFTER, Method, RequestBuilder, Response, StatusCode};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Timeouts and retries of the calls to the kittengrid api.
+///
+/// Calls are retried when the api could not be reached, or when it asks for it (429 and
+/// 503 responses). Idempotent calls (GET and PUT) are also retried on timeouts and server
+/// errors. Retries wait for the `Retry-After` of the response or an exponential backoff
+/// with jitter, up to `max_backoff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+impl From<&ApiClientConfig> for RetryPolicy {
+    fn from(config: &ApiClientConfig) -> Self {
+        let default = Self::default();
+        Self {
+            timeout: config
+                .timeout
+                .map(Duration::from_secs)
+                .unwrap_or(default.timeout),
+            connect_timeout: config
+                .connect_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(default.connect_timeout),
+            max_attempts: config.max_attempts.unwrap_or(default.max_attempts).max(1),
+            initial_backoff: config
+                .initial_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.initial_backoff),
+            max_backoff: config
+                .max_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_backoff),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns a client with the timeouts of the policy.
+    pub fn client(&self) -> reqwest::Result<reqwest::Client> {
+        reqwest::Client::builder()
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout)
+            .build()
+    }
+
+    /// Time to wait before the `retry`th retry (starting at 1): half of the exponential
+    /// backoff plus a random part up to the other half, so agents don't retry in sync.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        let half = exponential / 2;
+        half + half.mul_f64(rand::rng().random_range(0.0..=1.0))
+    }
+
+    /// Sends the request, retrying it as long as the policy allows. Returns the last
+    /// response (successful or not) or error.
+    pub async fn send(&self, mut request: RequestBuilder) -> reqwest::Result<Response> {
+        let mut attempt = 1;
+        loop {
+            // Requests with a streamed body can't be cloned, they are only sent once.
+            let retry_request = match attempt < self.max_attempts {
+                true => request.try_clone(),
+                false => None,
+            };
+            let (client, request_result) = request.build_split();
+            let sent = request_result?;
+            let idempotent = matches!(
+                *sent.method(),
+                Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+            );
+            let description = format!("{} {}", sent.method(), sent.url().path());
+            let result = client.execute(sent).await;
+
+            let retry_request = match retry_request {
+                Some(retry_request) => retry_request,
+                None => return result,
+            };
+            let delay = match &result {
+                Ok(response) => match retryable_status(response.status(), idempotent) {
+                    true => retry_after(response)
+                        .map(|delay| delay.min(self.max_backoff))
+                        .unwrap_or_else(|| self.backoff(attempt)),
+                    false => return result,
+                },
+                Err(e) if e.is_connect() || (idempotent && (e.is_timeout() || e.is_request())) => {
+                    self.backoff(attempt)
+                }
+                Err(_) => return result,
+            };
+
+            let reason = match &result {
+                Ok(response) => response.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            warn!(
+                "{} failed ({}), retrying in {}ms (attempt {}/{}).",
+                description,
+                reason,
+                delay.as_millis(),
+                attempt + 1,
+                self.max_attempts
+            );
+            tokio::time::sleep(delay).await;
+            request = retry_request;
+            attempt += 1;
+        }
+    }
+}
+
+// Whether a response asks for the call to be retried.
+fn retryable_status(status: StatusCode, idempotent: bool) -> bool {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+        status => idempotent && status.is_server_error(),
+    }
+}
+
+// Delay asked by the `Retry-After` header of the response, in seconds or as a date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?;
+    if let Some(seconds) = value.to_str().ok().and_then(|v| v.trim().parse().ok()) {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = headers::Date::decode(&mut std::iter::once(value)).ok()?;
+    Some(
+        SystemTime::from(date)
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::default();
+        for _ in 0..100 {
+            let delay = policy.backoff(1);
+            assert!(delay >= Duration::from_millis(250) && delay <= Duration::from_millis(500));
+            let delay = policy.backoff(3);
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+            let delay = policy.backoff(100);
+            assert!(delay >= Duration::from_secs(15) && delay <= Duration::from_secs(30));
+        }
+
+        let policy = RetryPolicy::from(&ApiClientConfig {
+            max_attempts: Some(0),
+            initial_backoff_ms: Some(100),
+            ..Default::default()
+        });
+        assert_eq!(policy.max_attempts, 1);
+        assert_eq!(policy.initial_backoff, Duration::from_millis(100));
+        assert_eq!(policy.timeout, DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn retry_after_header() {
+        let response = |value: &str| {
+            Response::from(
+                axum::http::Response::builder()
+                    .status(503)
+                    .header(RETRY_AFTER, value)
+                    .body("")
+                    .unwrap(),
+            )
+        };
+        assert_eq!(
+            retry_after(&response("120")),
+            Some(Duration::from_secs(120))
+        );
+        let mut date = Vec::new();
+        headers::Date::from(SystemTime::now() + Duration::from_secs(60)).encode(&mut date);
+        let delay = retry_after(&response(date[0].to_str().unwrap())).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+        // Dates in the past mean right away.
+        assert_eq!(
+            retry_after(&response("Tue, 15 Nov 1994 08:12:31 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&response("soon")), None);
+
+        assert!(retryable_status(StatusCode::SERVICE_UNAVAILABLE, false));
+        assert!(retryable_status(StatusCode::TOO_MANY_REQUESTS, false));
+        assert!(!retryable_status(StatusCode::INTERNAL_SERVER_ERROR, false));
+        assert!(retryable_status(StatusCode::INTERNAL_SERVER_ERROR, true));
+        assert!(!retryable_status(StatusCode::NOT_FOUND, true));
+    }
+}
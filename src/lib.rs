@@ -1,3 +1,4 @@
+use log::error;
 use std::net::SocketAddr;
 
 use std::fmt;
@@ -8,8 +9,11 @@ mod binary_utils;
 pub mod config;
 pub mod data_dir;
 mod endpoints;
+pub mod events;
+pub mod file_watcher;
 pub mod kittengrid_api;
 pub mod process_controller;
+pub mod proxy;
 pub mod utils;
 use axum::{
     routing::{get, post},
@@ -17,8 +21,12 @@ use axum::{
 };
 pub mod kittengrid_agent;
 pub mod persisted_buf_reader_broadcaster;
+pub mod pty;
 pub mod service;
+pub mod shutdown;
+pub mod terminal;
 pub mod ttyd;
+pub mod tunnel;
 
 pub mod wireguard;
 
@@ -28,9 +36,15 @@ extern crate log;
 
 pub struct AxumState {
     services: Arc<crate::service::Services>,
+    events: crate::events::Events,
+    shutdown: crate::shutdown::Shutdown,
+    tickets: endpoints::tickets::StreamTickets,
+    recordings: crate::terminal::recording::Recordings,
+    proxy: Option<Arc<crate::proxy::ProxyRoutes>>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, Copy, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum HealthStatus {
     Healthy,
     Unhealthy,
@@ -46,10 +60,15 @@ impl fmt::Display for HealthStatus {
 }
 
 pub fn router(state: AxumState) -> Router {
+    let state = Arc::new(state);
     Router::new()
         .route("/sys/hello", get(endpoints::sys::hello))
         .route("/sys/shutdown", post(endpoints::sys::shutdown))
         .route("/public/services", get(endpoints::public::services::index))
+        .route(
+            "/public/services/{id}",
+            get(endpoints::public::services::show),
+        )
         .route(
             "/public/services/{id}/stdout",
             get(endpoints::public::services::stdout),
@@ -62,6 +81,31 @@ pub fn router(state: AxumState) -> Router {
             "/public/services/{id}/combined_output",
             get(endpoints::public::services::combined_output),
         )
+        .route(
+            "/public/services/{id}/attach",
+            get(endpoints::public::services::attach),
+        )
+        .route(
+            "/public/services/{id}/access_log",
+            get(endpoints::public::services::access_log),
+        )
+        .route("/public/events", get(endpoints::public::events::events))
+        .route(
+            "/public/terminal",
+            get(endpoints::public::terminal::terminal),
+        )
+        .route(
+            "/public/terminal/sessions",
+            get(endpoints::public::terminal::sessions),
+        )
+        .route(
+            "/public/terminal/sessions/{id}/recording",
+            get(endpoints::public::terminal::recording),
+        )
+        .route(
+            "/public/services/{id}/stream_tickets",
+            post(endpoints::public::services::stream_tickets),
+        )
         .route(
             "/public/services/{id}/stop",
             post(endpoints::public::services::stop),
@@ -70,19 +114,75 @@ pub fn router(state: AxumState) -> Router {
             "/public/services/{id}/start",
             post(endpoints::public::services::start),
         )
-        .with_state(Arc::new(state))
+        .route(
+            "/public/services/{id}/requests",
+            get(endpoints::public::services::requests),
+        )
+        .route(
+            "/public/services/{id}/requests/{request_id}/replay",
+            post(endpoints::public::services::replay),
+        )
+        .fallback(endpoints::proxy::proxy)
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            endpoints::proxy::route_by_host,
+        ))
+        .with_state(state)
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(true)),
         )
 }
 
-pub async fn launch(listener: tokio::net::TcpListener, services: Arc<crate::service::Services>) {
-    let state = AxumState { services };
+/// Serves the agent endpoints until the shutdown is triggered.
+pub async fn launch(
+    listener: tokio::net::TcpListener,
+    services: Arc<crate::service::Services>,
+    events: crate::events::Events,
+    shutdown: crate::shutdown::Shutdown,
+) {
+    let proxy_config = &config::get_config().proxy;
+    let proxy =
+        match proxy_config.enabled() {
+            true => {
+                let routes =
+                    match crate::proxy::ProxyRoutes::from_services(services.clone(), proxy_config)
+                        .await
+                    {
+                        Ok(routes) => Arc::new(routes),
+                        Err(e) => {
+                            error!("Could not serve the agent endpoints: {}", e);
+                            shutdown.trigger("proxy unavailable");
+                            return;
+                        }
+                    };
+                routes.clone().spawn_idle_monitor(shutdown.clone());
+                Some(routes)
+            }
+            false => None,
+        };
+    let recordings =
+        match crate::terminal::recording::Recordings::from_config(&config::get_config().terminal) {
+            Ok(recordings) => recordings,
+            Err(e) => {
+                error!("Could not serve the agent endpoints: {}", e);
+                shutdown.trigger("recordings unavailable");
+                return;
+            }
+        };
+    let state = AxumState {
+        services,
+        events,
+        shutdown: shutdown.clone(),
+        tickets: endpoints::tickets::StreamTickets::new(),
+        recordings,
+        proxy,
+    };
     axum::serve(
         listener,
         router(state).into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(async move { shutdown.triggered().await })
     .await
     .unwrap();
 }
@@ -1,5 +1,6 @@
 use lib::kittengrid_agent::KittengridAgent;
-use log::{error, info};
+use lib::kittengrid_api::RetryPolicy;
+use log::{error, info, warn};
 use std::process::exit;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 20)]
@@ -11,30 +12,70 @@ async fn main() {
     lib::utils::initialize_logger();
 
     // Service setup
-    agent.init().await;
+    if let Err(e) = agent.init().await {
+        error!("Failed to initialize the agent: {}", e);
+        exit(1);
+    }
 
     // Bind to the network
     let listener = agent.bind().await;
 
+    // The api may be unavailable for a while (e.g. while it is deployed), so calls that
+    // failed for that are tried again, up to `api_client.startup_attempts` times.
+    let retry_policy = RetryPolicy::from(&config.api_client);
+    let startup_attempts = config.api_client.startup_attempts();
+
     // Register with API so we can fetch network configuration
-    match agent.register().await {
-        Ok(_) => {
-            info!("Successfully registered with kittengrid api.");
-        }
-        Err(e) => {
-            error!("Failed to register with kittengrid api: {}", e);
-            exit(1);
+    let mut retries = 0;
+    loop {
+        match agent.register().await {
+            Ok(_) => {
+                info!("Successfully registered with kittengrid api.");
+                break;
+            }
+            Err(e) if e.is_transient() && retries + 1 < startup_attempts => {
+                retries += 1;
+                let delay = retry_policy.backoff(retries);
+                warn!(
+                    "Failed to register with kittengrid api: {}, retrying in {}s (attempt {}/{}).",
+                    e,
+                    delay.as_secs(),
+                    retries + 1,
+                    startup_attempts
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                error!("Failed to register with kittengrid api: {}", e);
+                exit(1);
+            }
         }
     }
 
     info!("Publishing service info.");
-    match agent.publish_services().await {
-        Ok(_) => {
-            info!("Successfully published services.");
-        }
-        Err(e) => {
-            error!("Failed to publish services: {}.", e);
-            exit(1);
+    let mut retries = 0;
+    loop {
+        match agent.publish_services().await {
+            Ok(_) => {
+                info!("Successfully published services.");
+                break;
+            }
+            Err(e) if e.is_transient() && retries + 1 < startup_attempts => {
+                retries += 1;
+                let delay = retry_policy.backoff(retries);
+                warn!(
+                    "Failed to publish services: {}, retrying in {}s (attempt {}/{}).",
+                    e,
+                    delay.as_secs(),
+                    retries + 1,
+                    startup_attempts
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                error!("Failed to publish services: {}.", e);
+                exit(1);
+            }
         }
     }
 
@@ -58,10 +99,7 @@ async fn main() {
     if config.start_terminal {
         info!("Starting debugging terminal.");
         let id = uuid::Uuid::new_v4();
-        match lib::ttyd::Executable::default()
-            .start(&format!("/{}", id))
-            .await
-        {
+        match agent.start_terminal(id).await {
             Ok(port) => {
                 match agent
                     .register_service(
@@ -118,7 +156,10 @@ async fn main() {
     }
 
     if config.start_services || config.start_terminal {
+        agent.shutdown_handle().listen_for_signals();
         agent.wait(listener).await;
+        agent.shutdown().await;
+        info!("Shutdown complete.");
     } else {
         info!("Service start disabled. Exiting.");
         agent
@@ -120,31 +120,47 @@ impl PersistedBufReaderBroadcaster {
         let join_handle = tokio::spawn({
             let cancel_token = self.cancel_token.clone();
             let channel_set = self.channel_set.clone();
-            let mut buf = Vec::new();
             let output_mode = self.output_mode.clone();
             async move {
                 loop {
+                    // Reads block, so they are done in the blocking pool. Blocking here would
+                    // stall the worker thread, and with it the receivers woken by the broadcast.
+                    let read = tokio::task::spawn_blocking(move || {
+                        let mut buf = Vec::new();
+                        // we use read_until because we want to be able to read binary data (terminal escapes sequences?)
+                        debug!("Going to read from the buffer.");
+                        let read = buffer.read_until(b'\n', &mut buf);
+                        (buffer, buf, read)
+                    });
+
                     tokio::select! {
                         _ = cancel_token.cancelled() => {
                             debug!("Cancellation request received, stopping the task.");
                             break;
                         }
-                        _ = async {
-                            // we use read_until because we want to be able to read binary data (terminal escapes sequences?)
-                            debug!("Going to read from the buffer.");
-                            if buffer.read_until(b'\n', &mut buf).unwrap() == 0 {
+                        result = read => {
+                            let (returned_buffer, buf, read) = result.unwrap();
+                            if read.unwrap() == 0 {
                                 debug!("EOF reached, stopping the task.");
                                 cancel_token.cancel();
-                            } else {
-                                if !matches!(output_mode, OutputMode::None) {
-                                    Self::write_to_static_output(&output_mode, buf.clone()).await;
-                                }
+                                break;
+                            }
 
-                                channel_set.broadcast(buf.clone().into()).await;
-                                buf.clear();
-                                debug!("Data sent");
+                            if !matches!(output_mode, OutputMode::None) {
+                                Self::write_to_static_output(&output_mode, buf.clone()).await;
+                            }
+
+                            // Slow receivers can keep the broadcast waiting, it must not
+                            // prevent the task from being cancelled (e.g. on restarts).
+                            tokio::select! {
+                                _ = cancel_token.cancelled() => {
+                                    debug!("Cancellation request received, stopping the task.");
+                                    break;
+                                }
+                                _ = channel_set.broadcast(buf.into()) => debug!("Data sent"),
                             }
-                        } => {}
+                            buffer = returned_buffer;
+                        }
                     }
                 }
                 info!("Task finished.");
@@ -178,10 +194,19 @@ impl PersistedBufReaderBroadcaster {
         }
     }
 
+    /// Broadcasts the given data to all the receivers (and keeps it in the history) as if it
+    /// had been read from the buffer. Useful for injecting markers into the stream.
+    pub async fn write(&self, data: Bytes) {
+        if !matches!(self.output_mode, OutputMode::None) {
+            Self::write_to_static_output(&self.output_mode, data.to_vec()).await;
+        }
+        self.channel_set.broadcast(data).await;
+    }
+
     /// Returns a new receiver that will receive all the data that has been read so far + all the new data.
+    /// The data read while nobody was subscribed is received as it was read, the rest in a single chunk.
     pub async fn subscribe(&self) -> BufferReceiver {
-        let (sender, receiver) = self.new_channel().await;
-        let id = self.channel_set.add_sender(sender).await;
+        let (id, receiver) = self.channel_set.add_receiver().await;
 
         BufferReceiver { receiver, id }
     }
@@ -191,8 +216,14 @@ impl PersistedBufReaderBroadcaster {
         self.channel_set.drop_sender(receiver).await;
     }
 
-    async fn new_channel(&self) -> (Sender<Bytes>, Receiver<Bytes>) {
-        tokio::sync::mpsc::channel(1)
+    /// Returns the number of bytes kept in the history.
+    pub fn history_size(&self) -> usize {
+        self.channel_set.sent_data.size()
+    }
+
+    /// Returns the number of receivers currently subscribed.
+    pub fn subscribers(&self) -> usize {
+        self.channel_set.senders()
     }
 }
 
@@ -226,6 +257,10 @@ impl HistoricData {
         data.push(bytes);
     }
 
+    pub fn chunks(&self) -> Vec<Bytes> {
+        self.data.read().unwrap().clone()
+    }
+
     pub fn read(&self) -> Bytes {
         let mut bytes: bytes::BytesMut = bytes::BytesMut::new();
         for line in self.data.read().unwrap().iter() {
@@ -233,6 +268,15 @@ impl HistoricData {
         }
         bytes.into()
     }
+
+    pub fn size(&self) -> usize {
+        self.data
+            .read()
+            .unwrap()
+            .iter()
+            .map(|line| line.len())
+            .sum()
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -279,25 +323,47 @@ impl ChannelSet {
         }
     }
 
-    /// Adds a new sender to the list of receivers.
-    /// It allows sending some initial data to the sender, this is useful when connecting to a stream that has already started.
+    /// Adds a new receiver to the list of receivers.
+    /// The data sent so far is sent to it first, this is useful when connecting to a stream that has already started.
+    /// The first receiver gets it as it was read, as if it had been there from the start (e.g. when subscribing
+    /// right after a service is started), the others in a single chunk.
     /// Returns a UUID that can be used to identify the sender (and delete it later).
-    pub async fn add_sender(&self, sender: Sender<Bytes>) -> Uuid {
+    pub async fn add_receiver(&self) -> (Uuid, Receiver<Bytes>) {
         let _lock = self.lock.lock().await;
 
-        let initial_data = self.sent_data.read();
-        if !initial_data.is_empty() {
-            info!("Sending initial data to the new sender.");
-            match sender.reserve().await {
-                Ok(permit) => permit.send(initial_data),
-                Err(e) => error!("Error sending data: {:?}", e),
+        let (sender, receiver) = if self.senders.read().unwrap().is_empty() {
+            let chunks = self.sent_data.chunks();
+            let (sender, receiver) = tokio::sync::mpsc::channel(chunks.len().max(1));
+            if !chunks.is_empty() {
+                info!("Sending the data read so far to the first sender.");
             }
-        }
+            for chunk in chunks {
+                // There is room for all of them
+                sender.try_send(chunk).unwrap();
+            }
+            (sender, receiver)
+        } else {
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            let initial_data = self.sent_data.read();
+            if !initial_data.is_empty() {
+                info!("Sending initial data to the new sender.");
+                match sender.reserve().await {
+                    Ok(permit) => permit.send(initial_data),
+                    Err(e) => error!("Error sending data: {:?}", e),
+                }
+            }
+            (sender, receiver)
+        };
 
         let uuid = Uuid::new_v4();
         self.senders.write().unwrap().insert(uuid, sender);
         debug!("Sender added to the list of receivers.");
-        uuid
+        (uuid, receiver)
+    }
+
+    /// Returns the number of senders in the set.
+    pub fn senders(&self) -> usize {
+        self.senders.read().unwrap().len()
     }
 
     /// Drops a sender from the list of senders given its reciver
@@ -332,6 +398,14 @@ mod tests {
 
         let mut receiver = broadcaster.subscribe().await;
         assert_eq!(receiver.recv().await.unwrap(), "foo\n".to_string());
+        // Lines are read in the blocking pool, wait until both are in the history.
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while broadcaster.history_size() < "foo\nbar\n".len() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("Lines were not added to the history");
         let mut receiver2 = broadcaster.subscribe().await;
         assert_eq!(receiver2.recv().await.unwrap(), "foo\nbar\n".to_string());
         broadcaster.close().await;
@@ -395,6 +469,40 @@ mod tests {
         broadcaster.close().await;
     }
 
+    #[tokio::test]
+    async fn test_write_marker() {
+        let buffer = BufReader::new("foo\n".as_bytes());
+        let mut broadcaster = PersistedBufReaderBroadcaster::new().await;
+        broadcaster.watch(buffer).await;
+
+        let mut receiver = broadcaster.subscribe().await;
+        assert_eq!(receiver.recv().await.unwrap(), "foo\n".to_string());
+        broadcaster.write(Bytes::from("marker\n")).await;
+        assert_eq!(receiver.recv().await.unwrap(), "marker\n".to_string());
+
+        let mut receiver2 = broadcaster.subscribe().await;
+        assert_eq!(receiver2.recv().await.unwrap(), "foo\nmarker\n".to_string());
+        broadcaster.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let buffer = BufReader::new("foo\nbar\n".as_bytes());
+        let mut broadcaster = PersistedBufReaderBroadcaster::new().await;
+        assert_eq!(broadcaster.subscribers(), 0);
+        let mut receiver = broadcaster.subscribe().await;
+        broadcaster.watch(buffer).await;
+
+        assert_eq!(receiver.recv().await.unwrap(), "foo\n".to_string());
+        assert_eq!(receiver.recv().await.unwrap(), "bar\n".to_string());
+        assert_eq!(broadcaster.history_size(), 8);
+        assert_eq!(broadcaster.subscribers(), 1);
+
+        broadcaster.unsubscribe(receiver).await;
+        assert_eq!(broadcaster.subscribers(), 0);
+        broadcaster.close().await;
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 20)]
     async fn test_write_read() {
         let (stdout_writer, mut child) = StdoutWriter::new();
@@ -14,6 +14,9 @@ use tokio::sync::broadcast;
 use tokio::task::JoinSet;
 use tokio::time::{self, Duration};
 
+/// Time a process is given to exit after the TERM signal before it is killed.
+pub const DEFAULT_STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Error)]
 pub enum ProcessControllerError {
     #[error("Error receiving message: {0}")]
@@ -103,11 +106,14 @@ impl ProcessController {
     ///   It will receive the status of the process.
     /// - `health_check`: The health check to execute to determine if the process is
     ///   still running.
+    /// - `stop_grace_period`: Time to wait for the process to exit after sending it a TERM
+    ///   signal when stopping it, it is killed afterwards.
     pub async fn new(
         child: Child,
         on_stop: Arc<OnStopCallback>,
         health_check: Option<HealthCheck>,
         health_state_changed: Option<Arc<OnStateChangedCallback>>,
+        stop_grace_period: Duration,
     ) -> Self {
         let (stop_tx, stop_rx) = broadcast::channel(1);
         let mut set = JoinSet::new();
@@ -118,6 +124,7 @@ impl ProcessController {
             on_stop,
             stop_rx.resubscribe(),
             stop_tx.clone(),
+            stop_grace_period,
         ));
 
         // Spawn the lifecycle check task if configured
@@ -181,21 +188,21 @@ impl ProcessController {
     /// This is the task that gets spawned to monitor the process.
     /// It will wait for the process to finish and will gather the status,
     /// and will execute the callback with the status.
-    /// It will also listen for the stop signal and will kill the process
+    /// It will also listen for the stop signal and will terminate the process
     /// if it receives it.
     async fn spawn_process_monitor_task(
         mut child: Child,
         on_stop: Arc<OnStopCallback>,
         mut stop_rx: broadcast::Receiver<ServiceCommand>,
         stop_tx: broadcast::Sender<ServiceCommand>,
+        stop_grace_period: Duration,
     ) -> Result<(), ProcessControllerError> {
         loop {
             tokio::select! {
                 msg = stop_rx.recv() => {
                     match msg {
                         Ok(ServiceCommand::Stop) => {
-                            child.kill()?;
-                            let status = child.wait()?;
+                            let status = Self::terminate(&mut child, stop_grace_period).await?;
                             on_stop(status).await;
                             return Ok(())
                         },
@@ -222,6 +229,33 @@ impl ProcessController {
             }
         }
     }
+
+    /// Sends a TERM signal to the process and gives it `grace_period` to exit,
+    /// killing it if it is still running afterwards.
+    async fn terminate(
+        child: &mut Child,
+        grace_period: Duration,
+    ) -> Result<ExitStatus, ProcessControllerError> {
+        let pid = child.id() as libc::pid_t;
+        // SAFETY: kill(2) does not touch our memory, and the child has not been reaped
+        // yet (we own it), so the pid can't belong to another process.
+        if unsafe { libc::kill(pid, libc::SIGTERM) } == 0 {
+            let deadline = time::Instant::now() + grace_period;
+            while time::Instant::now() < deadline {
+                if let Some(status) = child.try_wait()? {
+                    return Ok(status);
+                }
+                time::sleep(Duration::from_millis(50)).await;
+            }
+            debug!(
+                "Process {} still running after {:?}, killing it",
+                pid, grace_period
+            );
+        }
+
+        child.kill()?;
+        Ok(child.wait()?)
+    }
 }
 
 #[cfg(test)]
@@ -253,14 +287,66 @@ mod test {
             .arg("10")
             .spawn()
             .unwrap();
-        let mut controller =
-            ProcessController::new(child, Arc::new(closure(data_clone)), None, None).await;
+        let mut controller = ProcessController::new(
+            child,
+            Arc::new(closure(data_clone)),
+            None,
+            None,
+            DEFAULT_STOP_GRACE_PERIOD,
+        )
+        .await;
 
         assert!(controller.stop().await.is_ok());
         controller.wait().await.unwrap();
         assert_eq!(*data.lock().unwrap(), None);
     }
 
+    #[tokio::test]
+    async fn test_process_controller_stop_grace_period() {
+        let data = Arc::new(Mutex::new(Some(-1)));
+        let data_clone = data.clone();
+
+        // Exits with 3 on TERM, but only after a while.
+        let child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("trap 'sleep 0.2; exit 3' TERM; while true; do sleep 0.1; done")
+            .spawn()
+            .unwrap();
+        let mut controller = ProcessController::new(
+            child,
+            Arc::new(closure(data_clone)),
+            None,
+            None,
+            Duration::from_secs(5),
+        )
+        .await;
+        // Give the shell some time to set up the trap.
+        time::sleep(Duration::from_millis(200)).await;
+        controller.stop().await.unwrap();
+        assert_eq!(*data.lock().unwrap(), Some(3));
+
+        // Processes ignoring the signal are killed once the grace period is over.
+        let data_clone = data.clone();
+        let child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("trap '' TERM; while true; do sleep 0.1; done")
+            .spawn()
+            .unwrap();
+        let mut controller = ProcessController::new(
+            child,
+            Arc::new(closure(data_clone)),
+            None,
+            None,
+            Duration::from_millis(300),
+        )
+        .await;
+        time::sleep(Duration::from_millis(200)).await;
+        let started = time::Instant::now();
+        controller.stop().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(*data.lock().unwrap(), None);
+    }
+
     #[tokio::test]
     async fn test_process_controller_exiting_0() {
         let data = Arc::new(Mutex::new(None));
@@ -271,8 +357,14 @@ mod test {
             .arg("exit 0")
             .spawn()
             .unwrap();
-        let mut controller =
-            ProcessController::new(child, Arc::new(closure(data_clone)), None, None).await;
+        let mut controller = ProcessController::new(
+            child,
+            Arc::new(closure(data_clone)),
+            None,
+            None,
+            DEFAULT_STOP_GRACE_PERIOD,
+        )
+        .await;
 
         controller.wait().await.unwrap();
         assert_eq!(*data.lock().unwrap(), Some(0));
@@ -288,8 +380,14 @@ mod test {
             .arg("exit 1")
             .spawn()
             .unwrap();
-        let mut controller =
-            ProcessController::new(child, Arc::new(closure(data_clone)), None, None).await;
+        let mut controller = ProcessController::new(
+            child,
+            Arc::new(closure(data_clone)),
+            None,
+            None,
+            DEFAULT_STOP_GRACE_PERIOD,
+        )
+        .await;
 
         controller.wait().await.unwrap();
         assert_eq!(*data.lock().unwrap(), Some(1));
@@ -335,6 +433,7 @@ mod test {
                     })
                 }
             })),
+            DEFAULT_STOP_GRACE_PERIOD,
         )
         .await;
         assert_eq!(*data.lock().unwrap(), None);
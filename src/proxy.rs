@@ -0,0 +1,1172 @@
+use crate::config::ProxyConfig;
+use crate::kittengrid_api::PullRequestStatus;
+use crate::persisted_buf_reader_broadcaster::PersistedBufReaderBroadcaster;
+use crate::proxy::capture::{CaptureError, Captures};
+use crate::service::{ServiceStatus, Services};
+use crate::shutdown::Shutdown;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri, Version},
+    response::{IntoResponse, Json, Response},
+};
+use hyper::body::{Frame, Incoming, SizeHint};
+use hyper_util::rt::TokioIo;
+use log::{debug, error, info, warn};
+use serde_json::json;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+pub mod capture;
+
+// Headers that only apply to a single connection, they are not forwarded.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_WAKE_TIMEOUT: Duration = Duration::from_secs(120);
+// How often a waking service is checked for being ready.
+const WAKE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("Service unreachable: {0}")]
+    Connect(std::io::Error),
+
+    #[error("Service error: {0}")]
+    Upstream(#[from] hyper::Error),
+
+    #[error("Invalid uri: {0}")]
+    InvalidUri(#[from] axum::http::uri::InvalidUri),
+
+    #[error("Invalid uri: {0}")]
+    InvalidUriParts(#[from] axum::http::uri::InvalidUriParts),
+
+    #[error("Failed to wake up service: {0}")]
+    Wake(std::io::Error),
+
+    #[error("Service '{0}' did not become healthy in time")]
+    WakeTimeout(String),
+}
+
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ProxyError::InvalidUri(_) | ProxyError::InvalidUriParts(_) => StatusCode::BAD_REQUEST,
+            ProxyError::Wake(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::WakeTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        (status, Json(json!({"error": self.to_string()}))).into_response()
+    }
+}
+
+/// Requests to a service, so it is put to sleep when it is idle.
+#[derive(Debug)]
+struct Activity {
+    last_request: std::sync::Mutex<Instant>,
+    // Requests (and upgraded connections) being proxied.
+    in_flight: AtomicUsize,
+    // Held while the service is woken up or put to sleep.
+    transition: tokio::sync::Mutex<()>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            last_request: std::sync::Mutex::new(Instant::now()),
+            in_flight: AtomicUsize::new(0),
+            transition: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+impl Activity {
+    fn is_idle(&self, idle_timeout: Duration) -> bool {
+        self.in_flight.load(Ordering::SeqCst) == 0
+            && self.last_request.lock().unwrap().elapsed() >= idle_timeout
+    }
+}
+
+// Counts a request as in flight until it is dropped.
+struct ActivityGuard(Arc<Activity>);
+
+impl ActivityGuard {
+    fn new(activity: &Arc<Activity>) -> Self {
+        activity.in_flight.fetch_add(1, Ordering::SeqCst);
+        *activity.last_request.lock().unwrap() = Instant::now();
+        Self(Arc::clone(activity))
+    }
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        *self.0.last_request.lock().unwrap() = Instant::now();
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Where the reverse proxy sends the requests of a service.
+#[derive(Debug, Clone)]
+struct Route {
+    service_id: uuid::Uuid,
+    name: String,
+    host: Option<String>,
+    path_prefix: Option<String>,
+    strip_prefix: bool,
+    port: u16,
+    // Services with a health check are ready once healthy, the rest once they accept
+    // connections.
+    health_check: bool,
+    idle_timeout: Option<Duration>,
+    activity: Arc<Activity>,
+    access_log: PersistedBufReaderBroadcaster,
+    capture: Option<Captures>,
+}
+
+impl Route {
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match &self.host {
+            Some(route_host) => host.is_some_and(|host| host.eq_ignore_ascii_case(route_host)),
+            None => true,
+        };
+        host_matches && self.prefix_len(path).is_some()
+    }
+
+    // Length of the prefix matched by `path`, prefixes match whole segments.
+    fn prefix_len(&self, path: &str) -> Option<usize> {
+        let prefix = match self.path_prefix.as_deref() {
+            None | Some("/") => return Some(0),
+            Some(prefix) => prefix,
+        };
+        let rest = path.strip_prefix(prefix)?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(prefix.len())
+    }
+
+    // Path (and query) of the request sent to the service.
+    fn upstream_path(&self, uri: &Uri) -> String {
+        let path = uri.path();
+        let path = match self.prefix_len(path) {
+            Some(len) if self.strip_prefix => &path[len..],
+            _ => path,
+        };
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{}", path)
+        };
+        match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        }
+    }
+}
+
+/// Routes of the reverse proxy, built from the services when the agent starts serving.
+///
+/// Services without proxied requests for their idle timeout are put to sleep (see
+/// `spawn_idle_monitor`), and started again on the next request for them.
+#[derive(Debug)]
+pub struct ProxyRoutes {
+    routes: Vec<Route>,
+    services: Arc<Services>,
+    wake_timeout: Duration,
+    idle_check_interval: Duration,
+    // Whether the pull request was reported as sleeping.
+    sleeping: AtomicBool,
+}
+
+impl Default for ProxyRoutes {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            services: Arc::new(Services::new()),
+            wake_timeout: DEFAULT_WAKE_TIMEOUT,
+            idle_check_interval: IDLE_CHECK_INTERVAL,
+            sleeping: AtomicBool::new(false),
+        }
+    }
+}
+
+impl ProxyRoutes {
+    pub async fn from_services(
+        services: Arc<Services>,
+        config: &ProxyConfig,
+    ) -> Result<Self, crate::data_dir::DataDirError> {
+        let mut routes = Vec::new();
+        for (id, description) in services.descriptions().await {
+            let access_log = match services.fetch(id).await {
+                Some(service) => service.lock().await.access_log(),
+                None => continue,
+            };
+            let capture = match description.proxy_capture() {
+                Some(config) => Some(Captures::from_config(id, &config).await?),
+                None => None,
+            };
+            routes.push(Route {
+                service_id: id,
+                name: description.name(),
+                host: description.proxy_host(),
+                path_prefix: description.proxy_path_prefix(),
+                strip_prefix: description.proxy_strip_prefix(),
+                port: description.port(),
+                health_check: description.health_check().is_some(),
+                idle_timeout: description.proxy_idle_timeout(config.idle_timeout),
+                activity: Arc::new(Activity::default()),
+                access_log,
+                capture,
+            });
+        }
+        Ok(Self {
+            routes,
+            services,
+            wake_timeout: config.wake_timeout(),
+            ..Default::default()
+        })
+    }
+
+    /// Route of a request, routes with a host come first, then the longest prefix wins.
+    fn find(&self, host: Option<&str>, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.matches(host, path))
+            .max_by_key(|route| (route.host.is_some(), route.prefix_len(path)))
+    }
+
+    /// Whether the request is for the host of a service, those are routed to the service
+    /// before the endpoints of the agent, whatever their path.
+    pub fn routes_host(&self, request: &Request) -> bool {
+        let host = request_host(request);
+        self.find(host.as_deref(), request.uri().path())
+            .is_some_and(|route| route.host.is_some())
+    }
+
+    /// Sends the request to the service it is routed to and returns its response, websocket
+    /// (and other) upgrades are forwarded.
+    pub async fn handle(&self, client: SocketAddr, request: Request) -> Response {
+        let host = request_host(&request);
+        let route = match self.find(host.as_deref(), request.uri().path()) {
+            Some(route) => route,
+            None => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(json!({"error": "No service found"})),
+                )
+                    .into_response()
+            }
+        };
+
+        let started_at = Instant::now();
+        let method = request.method().to_string();
+        let path = request
+            .uri()
+            .path_and_query()
+            .map(|path| path.to_string())
+            .unwrap_or_default();
+
+        let (request, capture) = match &route.capture {
+            Some(captures) => {
+                let upstream_path = route.upstream_path(request.uri());
+                let (request, pending) = captures.start(client, upstream_path, request);
+                (request, Some(pending))
+            }
+            None => (request, None),
+        };
+
+        let activity = ActivityGuard::new(&route.activity);
+        let forwarded = match self.wake(route).await {
+            Ok(()) => forward(route, client, request, activity).await,
+            Err(e) => Err(e),
+        };
+        let response = match forwarded {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to proxy {} {}: {}.", method, path, e);
+                e.into_response()
+            }
+        };
+
+        let entry = json!({
+            "timestamp": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            "client": client.ip().to_canonical(),
+            "method": method,
+            "path": path,
+            "status": response.status().as_u16(),
+            "duration_ms": started_at.elapsed().as_millis() as u64,
+        });
+        route.access_log.write(format!("{}\n", entry).into()).await;
+        if let (Some(captures), Some(pending)) = (&route.capture, capture) {
+            captures
+                .save(pending, response.status(), started_at.elapsed())
+                .await;
+        }
+
+        response
+    }
+
+    /// Captured requests of a service, None if they are not captured.
+    pub fn captures(&self, service_id: uuid::Uuid) -> Option<&Captures> {
+        self.routes
+            .iter()
+            .find(|route| route.service_id == service_id)
+            .and_then(|route| route.capture.as_ref())
+    }
+
+    /// Sends a captured request again to its service (see `Captures::replay`), waking it
+    /// up first if it is sleeping.
+    pub async fn replay(
+        &self,
+        service_id: uuid::Uuid,
+        id: uuid::Uuid,
+    ) -> Result<Response, CaptureError> {
+        let (route, captures) = match self
+            .routes
+            .iter()
+            .find(|route| route.service_id == service_id)
+        {
+            Some(route) => match &route.capture {
+                Some(captures) => (route, captures),
+                None => return Err(CaptureError::NotFound(id)),
+            },
+            None => return Err(CaptureError::NotFound(id)),
+        };
+        if captures.find(id).is_none() {
+            return Err(CaptureError::NotFound(id));
+        }
+
+        let _activity = ActivityGuard::new(&route.activity);
+        self.wake(route).await?;
+        captures.replay(id, route.port).await
+    }
+
+    /// Starts the service of the route if it is sleeping, and waits until it is ready.
+    /// Requests for a service being woken up wait for it too.
+    async fn wake(&self, route: &Route) -> Result<(), ProxyError> {
+        let _transition = route.activity.transition.lock().await;
+        if self.status(route.service_id).await != Some(ServiceStatus::Sleeping) {
+            return Ok(());
+        }
+
+        info!("Waking up service '{}'.", route.name);
+        self.services
+            .start_service(route.service_id)
+            .await
+            .map_err(ProxyError::Wake)?;
+        self.report_running().await;
+
+        tokio::time::timeout(self.wake_timeout, self.ready(route))
+            .await
+            .map_err(|_| ProxyError::WakeTimeout(route.name.clone()))
+    }
+
+    // Waits until the service of the route is healthy, or accepts connections if it has no
+    // health check.
+    async fn ready(&self, route: &Route) {
+        loop {
+            let ready = if route.health_check {
+                self.services
+                    .info(route.service_id)
+                    .await
+                    .is_some_and(|info| info.health == Some(crate::HealthStatus::Healthy))
+            } else {
+                tokio::net::TcpStream::connect((Ipv4Addr::LOCALHOST, route.port))
+                    .await
+                    .is_ok()
+            };
+            if ready {
+                return;
+            }
+            tokio::time::sleep(WAKE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Puts the idle services to sleep until the shutdown is triggered, nothing is spawned
+    /// if no service has an idle timeout.
+    pub fn spawn_idle_monitor(
+        self: Arc<Self>,
+        shutdown: Shutdown,
+    ) -> Option<tokio::task::JoinHandle<()>> {
+        if self.routes.iter().all(|route| route.idle_timeout.is_none()) {
+            return None;
+        }
+        Some(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.idle_check_interval) => {}
+                    _ = shutdown.triggered() => break,
+                }
+                self.sleep_idle_services().await;
+            }
+            debug!("Idle monitor stopped.");
+        }))
+    }
+
+    /// Stops the running services without requests for their idle timeout. The pull
+    /// request is reported as sleeping once no service is running.
+    async fn sleep_idle_services(&self) {
+        for id in self.services.start_order().await.into_iter().rev() {
+            let route = match self.routes.iter().find(|route| route.service_id == id) {
+                Some(route) => route,
+                None => continue,
+            };
+            let idle_timeout = match route.idle_timeout {
+                Some(idle_timeout) => idle_timeout,
+                None => continue,
+            };
+
+            let _transition = route.activity.transition.lock().await;
+            if !route.activity.is_idle(idle_timeout)
+                || self.status(id).await != Some(ServiceStatus::Running)
+            {
+                continue;
+            }
+            info!(
+                "No requests for service '{}' in {}s, putting it to sleep.",
+                route.name,
+                idle_timeout.as_secs()
+            );
+            if let Err(e) = self.services.sleep_service(id).await {
+                error!("Failed to put service '{}' to sleep: {}", route.name, e);
+            }
+        }
+
+        let running = self
+            .services
+            .infos()
+            .await
+            .iter()
+            .any(|info| info.status == ServiceStatus::Running);
+        if !running && !self.sleeping.swap(true, Ordering::SeqCst) {
+            info!("No service is running, the pull request is sleeping.");
+            self.set_status(PullRequestStatus::Sleeping).await;
+        } else if running {
+            // Started without a request, e.g. by its restart policy.
+            self.report_running().await;
+        }
+    }
+
+    /// To be called when a service is started outside of the proxy (e.g. by the start
+    /// endpoint): it gets a whole idle timeout before it is put to sleep again, and the
+    /// pull request is reported as running if it was sleeping.
+    pub async fn started(&self, service_id: uuid::Uuid) {
+        if let Some(route) = self
+            .routes
+            .iter()
+            .find(|route| route.service_id == service_id)
+        {
+            *route.activity.last_request.lock().unwrap() = Instant::now();
+        }
+        self.report_running().await;
+    }
+
+    async fn report_running(&self) {
+        if self.sleeping.swap(false, Ordering::SeqCst) {
+            info!("A service was started, the pull request is running.");
+            self.set_status(PullRequestStatus::Running).await;
+        }
+    }
+
+    async fn status(&self, id: uuid::Uuid) -> Option<ServiceStatus> {
+        self.services.info(id).await.map(|info| info.status)
+    }
+
+    async fn set_status(&self, status: PullRequestStatus) {
+        if let Some(api) = self.services.kittengrid_api().await {
+            if let Err(e) = api.agents_update_pull_request(status.clone()).await {
+                error!("Failed to set status to: {}. {}", status, e);
+            }
+        }
+    }
+}
+
+// Host of the request without the port, from the `Host` header or the uri (HTTP/2).
+fn request_host(request: &Request) -> Option<String> {
+    let host = match request.headers().get(header::HOST) {
+        Some(host) => host.to_str().ok()?.to_string(),
+        None => request.uri().authority()?.to_string(),
+    };
+    let host = match host.rsplit_once(':') {
+        // IPv6 addresses are between brackets
+        Some((host, port)) if !port.contains(']') => host.to_string(),
+        _ => host,
+    };
+    Some(host.to_lowercase())
+}
+
+fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+// Removes the hop-by-hop headers, but the ones asking for an upgrade.
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap, upgrade: bool) {
+    let upgrade_headers = headers.get(header::UPGRADE).cloned().filter(|_| upgrade);
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+    if let Some(protocol) = upgrade_headers {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, protocol);
+    }
+}
+
+async fn forward(
+    route: &Route,
+    client: SocketAddr,
+    mut request: Request,
+    activity: ActivityGuard,
+) -> Result<Response, ProxyError> {
+    let original_path = request.uri().path().to_string();
+    let mut uri = request.uri().clone().into_parts();
+    uri.path_and_query = Some(route.upstream_path(request.uri()).parse()?);
+    *request.uri_mut() = Uri::from_parts(uri)?;
+    if let Some(prefix) = route.path_prefix.as_ref().filter(|_| route.strip_prefix) {
+        if route.prefix_len(&original_path).is_some_and(|len| len > 0) {
+            if let Ok(value) = HeaderValue::from_str(prefix) {
+                request.headers_mut().insert("x-forwarded-prefix", value);
+            }
+        }
+    }
+    forward_to_port(route.port, client, request, activity).await
+}
+
+// Body of a proxied response, the guard is kept until it is fully sent or dropped so
+// streamed responses (downloads, server-sent events) count as in flight.
+struct GuardedBody<G> {
+    body: Incoming,
+    guard: Option<G>,
+}
+
+impl<G: Unpin> hyper::body::Body for GuardedBody<G> {
+    type Data = bytes::Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.body).poll_frame(cx);
+        if let Poll::Ready(None) | Poll::Ready(Some(Err(_))) = frame {
+            self.guard = None;
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Sends the request to the local upstream listening on `port` as is, adding the
+/// `X-Forwarded-*` headers, and returns its response. Upgraded connections are forwarded,
+/// `guard` is kept until the response body (or the upgraded connection) is done.
+pub(crate) async fn forward_to_port<G: Send + Unpin + 'static>(
+    port: u16,
+    client: SocketAddr,
+    mut request: Request,
+    guard: G,
+) -> Result<Response, ProxyError> {
+    let host = request_host(&request);
+    let upgrade = is_upgrade(request.headers());
+    let client_upgrade = upgrade.then(|| hyper::upgrade::on(&mut request));
+
+    let (mut parts, body) = request.into_parts();
+    parts.uri = match parts.uri.path_and_query() {
+        Some(path) => path.as_str().parse::<Uri>()?,
+        None => Uri::from_static("/"),
+    };
+    parts.version = Version::HTTP_11;
+
+    let headers = &mut parts.headers;
+    remove_hop_by_hop_headers(headers, upgrade);
+    if !headers.contains_key(header::HOST) {
+        if let Ok(value) = HeaderValue::from_str(&format!("127.0.0.1:{}", port)) {
+            headers.insert(header::HOST, value);
+        }
+    }
+    let client_ip = client.ip().to_canonical().to_string();
+    let forwarded_for = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+        Some(previous) => format!("{}, {}", previous, client_ip),
+        None => client_ip,
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert("x-forwarded-for", value);
+    }
+    if let Some(value) = host.and_then(|host| HeaderValue::from_str(&host).ok()) {
+        headers.insert("x-forwarded-host", value);
+    }
+    headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+
+    let stream = tokio::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+        .await
+        .map_err(ProxyError::Connect)?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.with_upgrades().await {
+            debug!("Proxied connection closed: {}", e);
+        }
+    });
+
+    let mut response = sender
+        .send_request(Request::from_parts(parts, body))
+        .await?;
+
+    let guard = match client_upgrade {
+        Some(client_upgrade) if response.status() == StatusCode::SWITCHING_PROTOCOLS => {
+            let upstream_upgrade = hyper::upgrade::on(&mut response);
+            tokio::spawn(async move {
+                let (client, upstream) = match tokio::try_join!(client_upgrade, upstream_upgrade) {
+                    Ok(upgraded) => upgraded,
+                    Err(e) => {
+                        error!("Failed to upgrade proxied connection: {}", e);
+                        return;
+                    }
+                };
+                let (mut client, mut upstream) = (TokioIo::new(client), TokioIo::new(upstream));
+                if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                    debug!("Upgraded proxied connection closed: {}", e);
+                }
+                // Open connections keep the service awake.
+                drop(guard);
+            });
+            None
+        }
+        _ => {
+            remove_hop_by_hop_headers(response.headers_mut(), false);
+            Some(guard)
+        }
+    };
+
+    Ok(response.map(|body| Body::new(GuardedBody { body, guard })))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{extract::ws::WebSocketUpgrade, routing::get, Router};
+    use futures_util::{SinkExt, StreamExt};
+
+    fn route(host: Option<&str>, path_prefix: Option<&str>, port: u16) -> Route {
+        Route {
+            service_id: uuid::Uuid::new_v4(),
+            name: "test".to_string(),
+            host: host.map(String::from),
+            path_prefix: path_prefix.map(String::from),
+            strip_prefix: true,
+            port,
+            health_check: false,
+            idle_timeout: None,
+            activity: Arc::new(Activity::default()),
+            access_log: PersistedBufReaderBroadcaster::default(),
+            capture: None,
+        }
+    }
+
+    #[test]
+    fn routing() {
+        let routes = ProxyRoutes {
+            routes: vec![
+                route(None, Some("/api"), 1),
+                route(None, Some("/api/v2"), 2),
+                route(Some("app.example.com"), None, 3),
+                route(Some("app.example.com"), Some("/api"), 4),
+            ],
+            ..Default::default()
+        };
+        let port = |host, path| routes.find(host, path).map(|route| route.port);
+
+        assert_eq!(port(None, "/api"), Some(1));
+        assert_eq!(port(None, "/api/users"), Some(1));
+        assert_eq!(port(None, "/api/v2/users"), Some(2));
+        assert_eq!(port(None, "/apis"), None);
+        assert_eq!(port(Some("other.example.com"), "/"), None);
+        assert_eq!(port(Some("app.example.com"), "/"), Some(3));
+        assert_eq!(port(Some("APP.example.com"), "/users"), Some(3));
+        assert_eq!(port(Some("app.example.com"), "/api/users"), Some(4));
+
+        let uri = |uri: &str| uri.parse::<Uri>().unwrap();
+        let api = route(None, Some("/api"), 1);
+        assert_eq!(api.upstream_path(&uri("/api")), "/");
+        assert_eq!(
+            api.upstream_path(&uri("/api/users?page=2")),
+            "/users?page=2"
+        );
+        let api = Route {
+            strip_prefix: false,
+            ..api
+        };
+        assert_eq!(api.upstream_path(&uri("/api/users")), "/api/users");
+    }
+
+    #[test]
+    fn hosts() {
+        let request = |host: &str| {
+            Request::builder()
+                .uri("/")
+                .header(header::HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(
+            request_host(&request("App.example.com:8080")).as_deref(),
+            Some("app.example.com")
+        );
+        assert_eq!(
+            request_host(&request("app.example.com")).as_deref(),
+            Some("app.example.com")
+        );
+        assert_eq!(request_host(&request("[::1]")).as_deref(), Some("[::1]"));
+        assert_eq!(request_host(&request("[::1]:80")).as_deref(), Some("[::1]"));
+    }
+
+    // Upstream echoing the path and forwarded headers, with a websocket echo at /ws.
+    async fn upstream() -> u16 {
+        let app = Router::new()
+            .route(
+                "/ws",
+                get(|ws: WebSocketUpgrade| async {
+                    ws.on_upgrade(|mut socket| async move {
+                        while let Some(Ok(message)) = socket.recv().await {
+                            if socket.send(message).await.is_err() {
+                                break;
+                            }
+                        }
+                    })
+                }),
+            )
+            .fallback(|request: Request| async move {
+                let header = |name: &str| {
+                    request
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(String::from)
+                };
+                Json(json!({
+                    "path": request.uri().to_string(),
+                    "forwarded_for": header("x-forwarded-for"),
+                    "forwarded_prefix": header("x-forwarded-prefix"),
+                }))
+            });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        port
+    }
+
+    // Agent-like server proxying everything.
+    async fn proxy(routes: impl Into<Arc<ProxyRoutes>>) -> SocketAddr {
+        let routes = routes.into();
+        let app = Router::new().fallback(
+            move |axum::extract::ConnectInfo(client): axum::extract::ConnectInfo<SocketAddr>,
+                  request: Request| {
+                let routes = routes.clone();
+                async move { routes.handle(client, request).await }
+            },
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn forward() {
+        let upstream_route = route(None, Some("/app"), upstream().await);
+        let mut access_log = upstream_route.access_log.subscribe().await;
+        // Nothing listens on port 1
+        let addr = proxy(ProxyRoutes {
+            routes: vec![upstream_route, route(None, Some("/down"), 1)],
+            ..Default::default()
+        })
+        .await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("http://{}/app/users?page=2", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["path"], "/users?page=2");
+        assert_eq!(body["forwarded_for"], "127.0.0.1");
+        assert_eq!(body["forwarded_prefix"], "/app");
+
+        let entry: serde_json::Value =
+            serde_json::from_slice(&access_log.recv().await.unwrap()).unwrap();
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["path"], "/app/users?page=2");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["client"], "127.0.0.1");
+
+        let response = client
+            .get(format!("http://{}/other", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = client
+            .get(format!("http://{}/down", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn hosts_before_agent_endpoints() {
+        let recordings = tempfile::tempdir().unwrap();
+        let state = crate::AxumState {
+            services: Arc::new(Services::new()),
+            events: crate::events::Events::new(),
+            shutdown: Shutdown::new(),
+            tickets: crate::endpoints::tickets::StreamTickets::new(),
+            recordings: crate::terminal::recording::Recordings::new(
+                recordings.path().to_path_buf(),
+                Duration::from_secs(60),
+                1,
+            ),
+            proxy: Some(Arc::new(ProxyRoutes {
+                routes: vec![route(Some("app.example.com"), None, upstream().await)],
+                ..Default::default()
+            })),
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                crate::router(state).into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        let client = reqwest::Client::new();
+
+        for path in ["/sys/hello", "/public/services"] {
+            let response = client
+                .get(format!("http://{}{}", addr, path))
+                .header(header::HOST, "app.example.com")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["path"], path);
+        }
+
+        // Other hosts reach the agent, which requires a token
+        let response = client
+            .get(format!("http://{}/public/services", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn capture() {
+        let dir = tempfile::tempdir().unwrap();
+        let service_id = uuid::Uuid::new_v4();
+        let routes = Arc::new(ProxyRoutes {
+            routes: vec![Route {
+                service_id,
+                capture: Some(Captures::new(
+                    dir.path().to_path_buf(),
+                    &crate::config::CaptureConfig::default(),
+                )),
+                ..route(None, Some("/app"), upstream().await)
+            }],
+            ..Default::default()
+        });
+        let addr = proxy(routes.clone()).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/app/users", addr))
+            .header(header::COOKIE, "session=secret")
+            .body("meow")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let captures = routes.captures(service_id).unwrap().list();
+        assert_eq!(captures.len(), 1);
+        let captured = &captures[0];
+        assert_eq!(captured.method, "POST");
+        assert_eq!(captured.path, "/app/users");
+        assert_eq!(captured.upstream_path, "/users");
+        assert_eq!(captured.body, "meow");
+        assert_eq!(captured.status, 200);
+        assert!(captured
+            .headers
+            .contains(&("cookie".to_string(), "[redacted]".to_string())));
+
+        let response = routes.replay(service_id, captured.id).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(routes.captures(uuid::Uuid::new_v4()).is_none());
+        assert!(routes
+            .replay(uuid::Uuid::new_v4(), captured.id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn forward_websocket() {
+        let upstream_route = route(None, Some("/app"), upstream().await);
+        let mut access_log = upstream_route.access_log.subscribe().await;
+        let addr = proxy(ProxyRoutes {
+            routes: vec![upstream_route],
+            ..Default::default()
+        })
+        .await;
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/app/ws", addr))
+            .await
+            .unwrap();
+        socket
+            .send(tokio_tungstenite::tungstenite::Message::text("meow"))
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            tokio_tungstenite::tungstenite::Message::text("meow")
+        );
+
+        let entry: serde_json::Value =
+            serde_json::from_slice(&access_log.recv().await.unwrap()).unwrap();
+        assert_eq!(entry["status"], 101);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn scale_to_zero() {
+        crate::test_utils::initialize_tests();
+        let port = upstream().await;
+        let services = Arc::new(Services::new());
+        for name in ["web", "db"] {
+            services
+                .insert(crate::service::Service::from(
+                    crate::config::ServiceConfig {
+                        name: name.to_string(),
+                        cmd: Some("sleep".to_string()),
+                        args: Some(vec!["60".to_string()]),
+                        port,
+                        ..Default::default()
+                    },
+                ))
+                .await;
+        }
+        for id in services.start_order().await {
+            services.start_service(id).await.unwrap();
+        }
+        let web = services.ids_by_name("web").await[0];
+        let db = services.ids_by_name("db").await[0];
+        let status = |id| {
+            let services = services.clone();
+            async move { services.info(id).await.unwrap().status }
+        };
+
+        let config = ProxyConfig {
+            enabled: Some(true),
+            idle_timeout: Some(60),
+            ..Default::default()
+        };
+        let mut routes = ProxyRoutes::from_services(services.clone(), &config)
+            .await
+            .unwrap();
+        assert!(routes
+            .routes
+            .iter()
+            .all(|route| route.idle_timeout.is_some()));
+        let captured = tempfile::tempdir().unwrap();
+        for route in routes.routes.iter_mut() {
+            route.idle_timeout = Some(Duration::from_millis(300));
+            if route.service_id == web {
+                route.capture = Some(Captures::new(
+                    captured.path().to_path_buf(),
+                    &crate::config::CaptureConfig::default(),
+                ));
+            }
+        }
+        routes.idle_check_interval = Duration::from_millis(50);
+        let routes = Arc::new(routes);
+        let shutdown = Shutdown::new();
+        let monitor = routes.clone().spawn_idle_monitor(shutdown.clone()).unwrap();
+        let addr = proxy(routes.clone()).await;
+
+        // Both go to sleep.
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !routes.sleeping.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(status(web).await, ServiceStatus::Sleeping);
+        assert_eq!(status(db).await, ServiceStatus::Sleeping);
+
+        // The next request wakes its service up.
+        let response = reqwest::get(format!("http://{}/web/users", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["path"], "/users");
+        assert_eq!(status(web).await, ServiceStatus::Running);
+        assert_eq!(status(db).await, ServiceStatus::Sleeping);
+        assert_eq!(services.info(web).await.unwrap().restart_count, 1);
+        assert!(!routes.sleeping.load(Ordering::SeqCst));
+
+        // Started without a request, e.g. by the start endpoint.
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !routes.sleeping.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        services.start_service(db).await.unwrap();
+        routes.started(db).await;
+        assert!(!routes.sleeping.load(Ordering::SeqCst));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(status(db).await, ServiceStatus::Running);
+
+        // Replays wake the service up too.
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !routes.sleeping.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let request = routes.captures(web).unwrap().list()[0].id;
+        let response = routes.replay(web, request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(status(web).await, ServiceStatus::Running);
+        assert!(!routes.sleeping.load(Ordering::SeqCst));
+
+        shutdown.trigger("test");
+        monitor.await.unwrap();
+        services.stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn streamed_responses_keep_awake() {
+        crate::test_utils::initialize_tests();
+        // Sends a chunk every 100ms for a second, longer than the idle timeout.
+        let app = Router::new().route(
+            "/events",
+            get(|| async {
+                let chunks = futures_util::stream::iter(0..10).then(|index| async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Ok::<_, std::io::Error>(format!("data: {index}\n\n"))
+                });
+                Body::from_stream(chunks)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let services = Arc::new(Services::new());
+        services
+            .insert(crate::service::Service::from(
+                crate::config::ServiceConfig {
+                    name: "web".to_string(),
+                    cmd: Some("sleep".to_string()),
+                    args: Some(vec!["60".to_string()]),
+                    port,
+                    ..Default::default()
+                },
+            ))
+            .await;
+        let web = services.ids_by_name("web").await[0];
+        services.start_service(web).await.unwrap();
+
+        let config = ProxyConfig {
+            enabled: Some(true),
+            idle_timeout: Some(60),
+            ..Default::default()
+        };
+        let mut routes = ProxyRoutes::from_services(services.clone(), &config)
+            .await
+            .unwrap();
+        routes.routes[0].idle_timeout = Some(Duration::from_millis(300));
+        routes.idle_check_interval = Duration::from_millis(50);
+        let routes = Arc::new(routes);
+        let shutdown = Shutdown::new();
+        let monitor = routes.clone().spawn_idle_monitor(shutdown.clone()).unwrap();
+        let addr = proxy(routes.clone()).await;
+        let sleeping = || async {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while !routes.sleeping.load(Ordering::SeqCst) {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .unwrap();
+        };
+
+        // Woken up by the request, and kept running while the response is streamed.
+        sleeping().await;
+        let mut response = reqwest::get(format!("http://{}/web/events", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut chunks = 0;
+        while let Some(chunk) = response.chunk().await.unwrap() {
+            chunks += chunk.len();
+            assert_eq!(
+                services.info(web).await.unwrap().status,
+                ServiceStatus::Running
+            );
+        }
+        assert_eq!(chunks, "data: 0\n\n".len() * 10);
+        assert!(!routes.sleeping.load(Ordering::SeqCst));
+
+        // Put to sleep once the response is done.
+        sleeping().await;
+        assert_eq!(
+            services.info(web).await.unwrap().status,
+            ServiceStatus::Sleeping
+        );
+
+        shutdown.trigger("test");
+        monitor.await.unwrap();
+        services.stop().await.unwrap();
+    }
+}
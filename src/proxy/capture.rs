@@ -0,0 +1,657 @@
+use super::{remove_hop_by_hop_headers, ProxyError, HOP_BY_HOP_HEADERS};
+use crate::config::CaptureConfig;
+use crate::data_dir::DataDirError;
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use base64::{engine::general_purpose, Engine as _};
+use futures_util::{stream, StreamExt};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const DEFAULT_MAX_REQUESTS: usize = 100;
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+// Headers carrying credentials, their values are never kept.
+const REDACTED_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie"];
+const REDACTED_QUERY_PARAMETERS: &[&str] = &[
+    "token",
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "api_key",
+    "apikey",
+    "key",
+    "secret",
+    "password",
+    "signature",
+];
+const REDACTED: &str = "[redacted]";
+const EXTENSION: &str = "json";
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("Request {0} not found")]
+    NotFound(uuid::Uuid),
+
+    #[error("Request {0} can't be replayed, its body was truncated")]
+    Truncated(uuid::Uuid),
+
+    #[error("Request {0} can't be replayed: {1}")]
+    Invalid(uuid::Uuid, String),
+
+    #[error("Service error: {0}")]
+    Replay(#[from] reqwest::Error),
+
+    #[error(transparent)]
+    Wake(#[from] ProxyError),
+}
+
+impl IntoResponse for CaptureError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            CaptureError::NotFound(_) => StatusCode::NOT_FOUND,
+            CaptureError::Truncated(_) | CaptureError::Invalid(..) => StatusCode::CONFLICT,
+            CaptureError::Replay(_) => StatusCode::BAD_GATEWAY,
+            CaptureError::Wake(e) => return e.into_response(),
+        };
+        (status, Json(json!({"error": self.to_string()}))).into_response()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyEncoding {
+    Utf8,
+    Base64,
+}
+
+/// A request proxied to a service, as listed by `Captures::list`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    pub id: uuid::Uuid,
+    /// Unix timestamp of the request, in milliseconds.
+    pub timestamp: u64,
+    pub client: String,
+    pub method: String,
+    /// Path (and query) the request was received with, redacted query parameters have
+    /// `[redacted]` values.
+    pub path: String,
+    /// Path (and query) the request was sent to the service with, redacted like `path`.
+    pub upstream_path: String,
+    /// Headers in the order they were received, redacted ones have `[redacted]` values.
+    pub headers: Vec<(String, String)>,
+    /// Body up to the size cap, as text or base64 when it is not valid UTF-8.
+    pub body: String,
+    pub body_encoding: BodyEncoding,
+    /// Size of the body received, the whole body unless it is truncated.
+    pub body_size: usize,
+    /// Whether the body is over the size cap, or the response was received before the
+    /// whole body was sent.
+    pub body_truncated: bool,
+    pub status: u16,
+    pub duration_ms: u64,
+}
+
+impl CapturedRequest {
+    fn body_bytes(&self) -> Result<Vec<u8>, CaptureError> {
+        match self.body_encoding {
+            BodyEncoding::Utf8 => Ok(self.body.clone().into_bytes()),
+            BodyEncoding::Base64 => general_purpose::STANDARD
+                .decode(&self.body)
+                .map_err(|e| CaptureError::Invalid(self.id, e.to_string())),
+        }
+    }
+}
+
+/// Requests proxied to a service, kept as a ring buffer of `<request id>.json` files.
+///
+/// The values of the headers and query parameters that carry credentials (and the
+/// configured ones) are redacted, and bodies are kept up to the size cap.
+#[derive(Debug, Clone)]
+pub struct Captures {
+    path: PathBuf,
+    max_requests: usize,
+    max_body_size: usize,
+    redact_headers: Vec<String>,
+    redact_query_parameters: Vec<String>,
+    // Oldest first.
+    requests: Arc<Mutex<VecDeque<CapturedRequest>>>,
+}
+
+impl Captures {
+    /// Loads the requests already captured in `path`.
+    pub fn new(path: PathBuf, config: &CaptureConfig) -> Self {
+        let configured = config.redact_headers.iter().flatten();
+        let redact_headers = REDACTED_HEADERS
+            .iter()
+            .map(|name| name.to_string())
+            .chain(configured.clone().map(|name| name.to_lowercase()))
+            .collect();
+        let redact_query_parameters = REDACTED_QUERY_PARAMETERS
+            .iter()
+            .map(|name| name.to_string())
+            .chain(configured.map(|name| name.to_lowercase()))
+            .collect();
+        let captures = Self {
+            path,
+            max_requests: config.max_requests.unwrap_or(DEFAULT_MAX_REQUESTS),
+            max_body_size: config.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            redact_headers,
+            redact_query_parameters,
+            requests: Arc::new(Mutex::new(VecDeque::new())),
+        };
+        captures.load();
+        captures
+    }
+
+    /// Keeps the requests of the service in the DataDir.
+    pub async fn from_config(
+        service_id: uuid::Uuid,
+        config: &CaptureConfig,
+    ) -> Result<Self, DataDirError> {
+        let path = crate::data_dir::get_data_dir()
+            .captures_path()?
+            .join(service_id.to_string());
+        let config = config.clone();
+        Ok(
+            tokio::task::spawn_blocking(move || Self::new(path, &config))
+                .await
+                .expect("Loading captured requests panicked"),
+        )
+    }
+
+    /// Starts capturing a request, its body is captured while it is sent to the service.
+    pub fn start(
+        &self,
+        client: SocketAddr,
+        upstream_path: String,
+        request: Request,
+    ) -> (Request, PendingCapture) {
+        let (parts, body) = request.into_parts();
+        let headers = parts
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let value = match self.is_redacted(name.as_str()) {
+                    true => REDACTED.to_string(),
+                    false => String::from_utf8_lossy(value.as_bytes()).to_string(),
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+
+        // The body is complete once its length is read, or its end without a length.
+        let content_length = HttpBody::size_hint(&body)
+            .exact()
+            .map(|length| length as usize);
+        let body_capture = Arc::new(Mutex::new(BodyCapture {
+            complete: content_length == Some(0),
+            ..Default::default()
+        }));
+        let max_body_size = self.max_body_size;
+        let stream = {
+            let body_capture = Arc::clone(&body_capture);
+            body.into_data_stream().map(move |chunk| {
+                if let Ok(data) = &chunk {
+                    let mut body_capture = body_capture.lock().unwrap();
+                    let kept = max_body_size.saturating_sub(body_capture.data.len());
+                    body_capture
+                        .data
+                        .extend_from_slice(&data[..kept.min(data.len())]);
+                    body_capture.size += data.len();
+                    if content_length == Some(body_capture.size) {
+                        body_capture.complete = true;
+                    }
+                }
+                chunk
+            })
+        };
+        let stream = {
+            let body_capture = Arc::clone(&body_capture);
+            stream.chain(
+                stream::once(async move {
+                    body_capture.lock().unwrap().complete = true;
+                    None
+                })
+                .filter_map(std::future::ready),
+            )
+        };
+
+        let pending = PendingCapture {
+            request: CapturedRequest {
+                // Time ordered, so requests in the same millisecond keep their order.
+                id: uuid::Uuid::now_v7(),
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64,
+                client: client.ip().to_canonical().to_string(),
+                method: parts.method.to_string(),
+                path: self.redact_query(
+                    parts.uri.path_and_query().map_or("", |path| path.as_str()),
+                    Some(REDACTED),
+                ),
+                upstream_path: self.redact_query(&upstream_path, Some(REDACTED)),
+                headers,
+                body: String::new(),
+                body_encoding: BodyEncoding::Utf8,
+                body_size: 0,
+                body_truncated: false,
+                status: 0,
+                duration_ms: 0,
+            },
+            body: body_capture,
+        };
+        (
+            Request::from_parts(parts, Body::from_stream(stream)),
+            pending,
+        )
+    }
+
+    /// Saves a captured request, deleting the oldest ones over the limit. The body is
+    /// marked as truncated if the service has not read all of it yet.
+    pub async fn save(&self, pending: PendingCapture, status: StatusCode, duration: Duration) {
+        let request = pending.finish(status, duration);
+        let saved = match serde_json::to_vec(&request) {
+            Ok(data) => match tokio::fs::create_dir_all(&self.path).await {
+                Ok(()) => tokio::fs::write(self.path_for(request.id), data).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = saved {
+            error!("Could not save captured request {}: {}", request.id, e);
+        }
+
+        let expired: Vec<uuid::Uuid> = {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push_back(request);
+            let expired = requests.len().saturating_sub(self.max_requests);
+            requests
+                .drain(..expired)
+                .map(|request| request.id)
+                .collect()
+        };
+        for id in expired {
+            if let Err(e) = tokio::fs::remove_file(self.path_for(id)).await {
+                error!("Could not delete captured request {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Returns the captured requests, newest first.
+    pub fn list(&self) -> Vec<CapturedRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    pub fn find(&self, id: uuid::Uuid) -> Option<CapturedRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|request| request.id == id)
+            .cloned()
+    }
+
+    /// Sends a captured request again to the service listening on `port`, and returns its
+    /// response. Redacted headers and query parameters are not sent, and requests with a
+    /// truncated body can't be replayed.
+    pub async fn replay(&self, id: uuid::Uuid, port: u16) -> Result<Response, CaptureError> {
+        let request = self.find(id).ok_or(CaptureError::NotFound(id))?;
+        if request.body_truncated {
+            return Err(CaptureError::Truncated(id));
+        }
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())
+            .map_err(|e| CaptureError::Invalid(id, e.to_string()))?;
+
+        let mut builder = reqwest::Client::new()
+            .request(
+                method,
+                format!(
+                    "http://127.0.0.1:{}{}",
+                    port,
+                    self.redact_query(&request.upstream_path, None)
+                ),
+            )
+            .timeout(REPLAY_TIMEOUT);
+        for (name, value) in request.headers.iter() {
+            let skipped = self.is_redacted(name)
+                || HOP_BY_HOP_HEADERS.contains(&name.as_str())
+                || name == header::HOST.as_str()
+                || name == header::CONTENT_LENGTH.as_str();
+            if !skipped {
+                builder = builder.header(name, value);
+            }
+        }
+        let upstream = builder.body(request.body_bytes()?).send().await?;
+
+        let mut response = Response::builder()
+            .status(upstream.status())
+            .body(Body::empty())
+            .unwrap();
+        *response.headers_mut() = upstream.headers().clone();
+        remove_hop_by_hop_headers(response.headers_mut(), false);
+        response.headers_mut().insert(
+            "x-kittengrid-replay-of",
+            HeaderValue::from_str(&id.to_string()).unwrap(),
+        );
+        *response.body_mut() = Body::from(upstream.bytes().await?);
+        Ok(response)
+    }
+
+    fn is_redacted(&self, name: &str) -> bool {
+        self.redact_headers
+            .iter()
+            .any(|redacted| redacted.eq_ignore_ascii_case(name))
+    }
+
+    // Replaces the values of the redacted query parameters of `path`, or removes them
+    // without a `replacement`. The other parameters are kept as they are.
+    fn redact_query(&self, path: &str, replacement: Option<&str>) -> String {
+        let (path, query) = match path.split_once('?') {
+            Some(split) => split,
+            None => return path.to_string(),
+        };
+        let parameters: Vec<String> = query
+            .split('&')
+            .filter_map(|parameter| {
+                let name = parameter
+                    .split_once('=')
+                    .map_or(parameter, |(name, _)| name);
+                let decoded = url::form_urlencoded::parse(name.as_bytes())
+                    .next()
+                    .map(|(name, _)| name.into_owned())
+                    .unwrap_or_default();
+                let redacted = self
+                    .redact_query_parameters
+                    .iter()
+                    .any(|redacted| redacted.eq_ignore_ascii_case(&decoded));
+                match redacted {
+                    true => replacement.map(|replacement| format!("{name}={replacement}")),
+                    false => Some(parameter.to_string()),
+                }
+            })
+            .collect();
+        match parameters.is_empty() {
+            true => path.to_string(),
+            false => format!("{}?{}", path, parameters.join("&")),
+        }
+    }
+
+    fn path_for(&self, id: uuid::Uuid) -> PathBuf {
+        self.path.join(format!("{id}.{EXTENSION}"))
+    }
+
+    // Loads the requests captured before the agent (re)started, the ones over the limit
+    // are deleted. Blocking, see `from_config`.
+    fn load(&self) {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                error!("Could not list captured requests: {}", e);
+                return;
+            }
+        };
+
+        let mut requests: Vec<CapturedRequest> = Vec::new();
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if path.extension().and_then(|extension| extension.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            match fs::read(&path).map(|data| serde_json::from_slice::<CapturedRequest>(&data)) {
+                Ok(Ok(request)) => requests.push(request),
+                Ok(Err(e)) => warn!(
+                    "Skipping invalid captured request {}: {}",
+                    path.display(),
+                    e
+                ),
+                Err(e) => warn!("Could not read captured request {}: {}", path.display(), e),
+            }
+        }
+        requests.sort_by_key(|request| (request.timestamp, request.id));
+        let expired = requests.len().saturating_sub(self.max_requests);
+        for request in requests.drain(..expired) {
+            if let Err(e) = fs::remove_file(self.path_for(request.id)) {
+                error!("Could not delete captured request {}: {}", request.id, e);
+            }
+        }
+        *self.requests.lock().unwrap() = requests.into();
+    }
+}
+
+#[derive(Debug, Default)]
+struct BodyCapture {
+    data: Vec<u8>,
+    size: usize,
+    // Whether the whole body was read.
+    complete: bool,
+}
+
+/// Request being proxied, saved with `Captures::save` once the response is received.
+#[derive(Debug)]
+pub struct PendingCapture {
+    request: CapturedRequest,
+    body: Arc<Mutex<BodyCapture>>,
+}
+
+impl PendingCapture {
+    fn finish(self, status: StatusCode, duration: Duration) -> CapturedRequest {
+        let body = self.body.lock().unwrap();
+        let (data, body_encoding) = match std::str::from_utf8(&body.data) {
+            Ok(text) => (text.to_string(), BodyEncoding::Utf8),
+            Err(_) => (
+                general_purpose::STANDARD.encode(&body.data),
+                BodyEncoding::Base64,
+            ),
+        };
+        CapturedRequest {
+            body: data,
+            body_encoding,
+            body_size: body.size,
+            body_truncated: body.size > body.data.len() || !body.complete,
+            status: status.as_u16(),
+            duration_ms: duration.as_millis() as u64,
+            ..self.request
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{routing::post, Router};
+
+    fn config(max_requests: usize, max_body_size: usize) -> CaptureConfig {
+        CaptureConfig {
+            max_requests: Some(max_requests),
+            max_body_size: Some(max_body_size),
+            redact_headers: Some(vec!["X-Api-Key".to_string()]),
+        }
+    }
+
+    // Captures a request, reading its body like the proxy would.
+    async fn capture(captures: &Captures, body: &'static [u8]) -> uuid::Uuid {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/app/users?page=2&access_token=secret&X-Api-Key=secret")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .header("x-api-key", "secret")
+            .header("x-request", "kept")
+            .body(Body::from(body))
+            .unwrap();
+        let (request, pending) = captures.start(
+            "127.0.0.1:4242".parse().unwrap(),
+            "/users?page=2&access_token=secret&X-Api-Key=secret".to_string(),
+            request,
+        );
+        axum::body::to_bytes(request.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let id = pending.request.id;
+        captures
+            .save(pending, StatusCode::CREATED, Duration::from_millis(12))
+            .await;
+        id
+    }
+
+    #[tokio::test]
+    async fn ring_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let captures = Captures::new(dir.path().to_path_buf(), &config(2, 4));
+
+        let first = capture(&captures, b"meow").await;
+        let request = captures.find(first).unwrap();
+        assert_eq!(request.method, "POST");
+        // Query parameters are redacted like headers, by default and configured names.
+        assert_eq!(
+            request.path,
+            "/app/users?page=2&access_token=[redacted]&X-Api-Key=[redacted]"
+        );
+        assert_eq!(
+            request.upstream_path,
+            "/users?page=2&access_token=[redacted]&X-Api-Key=[redacted]"
+        );
+        assert_eq!(request.client, "127.0.0.1");
+        assert_eq!(request.status, 201);
+        assert_eq!(request.duration_ms, 12);
+        assert_eq!(
+            request.headers,
+            vec![
+                ("authorization".to_string(), REDACTED.to_string()),
+                ("x-api-key".to_string(), REDACTED.to_string()),
+                ("x-request".to_string(), "kept".to_string()),
+            ]
+        );
+        assert_eq!(request.body, "meow");
+        assert!(!request.body_truncated);
+
+        // Bodies are cut at the size cap, binary ones are kept as base64.
+        let truncated = capture(&captures, b"meow meow").await;
+        let request = captures.find(truncated).unwrap();
+        assert_eq!(request.body, "meow");
+        assert_eq!(request.body_size, 9);
+        assert!(request.body_truncated);
+        let binary = capture(&captures, b"\xff\xfe").await;
+        let request = captures.find(binary).unwrap();
+        assert_eq!(request.body_encoding, BodyEncoding::Base64);
+        assert_eq!(request.body_bytes().unwrap(), b"\xff\xfe");
+
+        // Only the last two are kept, on disk too.
+        let ids = |captures: &Captures| {
+            captures
+                .list()
+                .iter()
+                .map(|request| request.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&captures), vec![binary, truncated]);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        let reloaded = Captures::new(dir.path().to_path_buf(), &config(1, 4));
+        assert_eq!(ids(&reloaded), vec![binary]);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn unread_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let captures = Captures::new(dir.path().to_path_buf(), &config(10, 1024));
+        let (request, pending) = captures.start(
+            "127.0.0.1:4242".parse().unwrap(),
+            "/".to_string(),
+            Request::builder()
+                .method("POST")
+                .uri("/")
+                .body(Body::from("meow"))
+                .unwrap(),
+        );
+        let id = pending.request.id;
+
+        // The service answered before reading the body.
+        captures
+            .save(pending, StatusCode::UNAUTHORIZED, Duration::from_millis(1))
+            .await;
+        drop(request);
+        let request = captures.find(id).unwrap();
+        assert!(request.body_truncated);
+        assert!(matches!(
+            captures.replay(id, 1).await,
+            Err(CaptureError::Truncated(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn replay() {
+        // Upstream echoing the request.
+        let app = Router::new().route(
+            "/users",
+            post(|request: Request| async move {
+                let query = request.uri().query().map(String::from);
+                let headers = request.headers().clone();
+                let header = |name: &str| {
+                    headers
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(String::from)
+                };
+                let body = axum::body::to_bytes(request.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                Json(json!({
+                    "query": query,
+                    "authorization": header("authorization"),
+                    "x_request": header("x-request"),
+                    "body": String::from_utf8_lossy(&body),
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let dir = tempfile::tempdir().unwrap();
+        let captures = Captures::new(dir.path().to_path_buf(), &config(10, 4));
+        let id = capture(&captures, b"meow").await;
+
+        let response = captures.replay(id, port).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-kittengrid-replay-of"], id.to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["query"], "page=2");
+        assert_eq!(body["authorization"], serde_json::Value::Null);
+        assert_eq!(body["x_request"], "kept");
+        assert_eq!(body["body"], "meow");
+
+        let truncated = capture(&captures, b"meow meow").await;
+        assert!(matches!(
+            captures.replay(truncated, port).await,
+            Err(CaptureError::Truncated(_))
+        ));
+        let unknown = uuid::Uuid::new_v4();
+        assert!(matches!(
+            captures.replay(unknown, port).await,
+            Err(CaptureError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// Size of a terminal window, in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct WindowSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Default for WindowSize {
+    fn default() -> Self {
+        Self { cols: 80, rows: 24 }
+    }
+}
+
+/// Pseudo terminal pair, used to run processes that expect to be attached to a terminal
+/// (TUIs, programs that only use colors when their output is a tty...).
+///
+/// The process gets the slave side as its controlling terminal and stdin/stdout/stderr
+/// (see `Pty::attach`), the agent reads its output from and writes its input into
+/// the master side.
+#[derive(Debug)]
+pub struct Pty {
+    master: File,
+    slave: File,
+}
+
+impl Pty {
+    pub fn open(size: WindowSize) -> io::Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+        let winsize = winsize(size);
+        if unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &winsize,
+            )
+        } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { OwnedFd::from_raw_fd(master) };
+        let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+
+        // Other processes spawned by the agent must not inherit them, the master would not
+        // get the EOF until every copy of the slave is closed.
+        for fd in [&master, &slave] {
+            if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Self {
+            master: master.into(),
+            slave: slave.into(),
+        })
+    }
+
+    /// Makes the command run in a new session with the terminal as its controlling
+    /// terminal and stdin, stdout and stderr.
+    pub fn attach(&self, cmd: &mut Command) -> io::Result<()> {
+        cmd.stdin(Stdio::from(self.slave.try_clone()?))
+            .stdout(Stdio::from(self.slave.try_clone()?))
+            .stderr(Stdio::from(self.slave.try_clone()?));
+
+        // Runs in the child after stdin/stdout/stderr are set up.
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns a reader for the output of the terminal.
+    pub fn reader(&self) -> io::Result<PtyReader> {
+        Ok(PtyReader::new(self.master.try_clone()?))
+    }
+
+    /// Returns a handle to write into the terminal, it can also be used to resize it.
+    pub fn writer(&self) -> io::Result<File> {
+        self.master.try_clone()
+    }
+}
+
+/// Changes the window size of the terminal, the process running in it gets a SIGWINCH.
+pub fn resize(master: &impl AsRawFd, size: WindowSize) -> io::Result<()> {
+    let winsize = winsize(size);
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn winsize(size: WindowSize) -> libc::winsize {
+    libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+/// Reader for the master side of a terminal.
+///
+/// Reading from the master once the process exits fails with EIO instead of returning
+/// EOF, here it is reported as EOF. Also, `read_until` returns whatever is available
+/// instead of waiting for the delimiter: terminal output (prompts, screen updates)
+/// does not always end with a new line.
+#[derive(Debug)]
+pub struct PtyReader {
+    master: File,
+    buffer: Box<[u8]>,
+    position: usize,
+    filled: usize,
+}
+
+impl PtyReader {
+    fn new(master: File) -> Self {
+        Self {
+            master,
+            buffer: vec![0; READ_BUFFER_SIZE].into_boxed_slice(),
+            position: 0,
+            filled: 0,
+        }
+    }
+}
+
+impl Read for PtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for PtyReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.position >= self.filled {
+            match self.master.read(&mut self.buffer) {
+                Ok(read) => {
+                    self.position = 0;
+                    self.filled = read;
+                    if read == 0 {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => {
+                    self.position = 0;
+                    self.filled = 0;
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(&self.buffer[self.position..self.filled])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = (self.position + amount).min(self.filled);
+    }
+
+    fn read_until(&mut self, byte: u8, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = match available.iter().position(|b| *b == byte) {
+            Some(index) => index + 1,
+            None => available.len(),
+        };
+        buf.extend_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn run_in_pty() {
+        let pty = Pty::open(WindowSize {
+            cols: 100,
+            rows: 30,
+        })
+        .unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "[ -t 0 ] && [ -t 1 ] && printf 'tty '; stty size"]);
+        pty.attach(&mut cmd).unwrap();
+        let mut child = cmd.spawn().unwrap();
+        let mut reader = pty.reader().unwrap();
+        drop(cmd);
+        drop(pty);
+
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output), "tty 30 100\r\n");
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn resize_window() {
+        let pty = Pty::open(WindowSize::default()).unwrap();
+        let writer = pty.writer().unwrap();
+        resize(
+            &writer,
+            WindowSize {
+                cols: 120,
+                rows: 40,
+            },
+        )
+        .unwrap();
+
+        let mut winsize = winsize(WindowSize::default());
+        assert_ne!(
+            unsafe { libc::ioctl(writer.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize) },
+            -1
+        );
+        assert_eq!((winsize.ws_col, winsize.ws_row), (120, 40));
+    }
+}
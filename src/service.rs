@@ -1,6 +1,7 @@
 use super::persisted_buf_reader_broadcaster::{BufferReceiver, PersistedBufReaderBroadcaster};
 use crate::kittengrid_api::KittengridApi;
 use crate::process_controller::ProcessController;
+use crate::pty::{Pty, PtyReader, WindowSize};
 use log::{debug, error, info};
 use serde::ser::SerializeStruct;
 use std::future::Future;
@@ -11,8 +12,12 @@ use serde_json::json;
 use std::{collections::HashMap, process::ExitStatus};
 
 use std::io::BufReader;
-use std::process::Command;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
 
 use crate::config;
@@ -25,6 +30,11 @@ pub struct ServiceDescription {
     env: HashMap<String, String>,
     port: u16,
     health_check: Option<config::HealthCheck>,
+    watch: Option<config::WatchConfig>,
+    stop_grace_period: Option<u64>,
+    stdin: bool,
+    tty: bool,
+    proxy: Option<config::ServiceProxyConfig>,
 }
 
 impl From<config::ServiceConfig> for ServiceDescription {
@@ -36,6 +46,11 @@ impl From<config::ServiceConfig> for ServiceDescription {
             args: config.args.unwrap_or_default(),
             cmd: config.cmd.unwrap_or(config.name),
             health_check: config.health_check,
+            watch: config.watch,
+            stop_grace_period: config.stop_grace_period,
+            stdin: config.stdin,
+            tty: config.tty,
+            proxy: config.proxy,
         }
     }
 }
@@ -55,16 +70,193 @@ impl ServiceDescription {
     pub fn health_check(&self) -> Option<config::HealthCheck> {
         self.health_check.clone()
     }
+
+    pub fn watch(&self) -> Option<config::WatchConfig> {
+        self.watch.clone()
+    }
+
+    pub fn stdin(&self) -> bool {
+        self.stdin
+    }
+
+    pub fn tty(&self) -> bool {
+        self.tty
+    }
+
+    pub fn stop_grace_period(&self) -> std::time::Duration {
+        self.stop_grace_period
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(crate::process_controller::DEFAULT_STOP_GRACE_PERIOD)
+    }
+
+    /// Host of the requests the reverse proxy routes to the service.
+    pub fn proxy_host(&self) -> Option<String> {
+        self.proxy
+            .as_ref()
+            .and_then(|proxy| proxy.host.as_ref())
+            .map(|host| host.to_lowercase())
+    }
+
+    /// Path prefix of the requests the reverse proxy routes to the service, without a
+    /// trailing slash. `/<name>` when neither a host nor a prefix are configured.
+    pub fn proxy_path_prefix(&self) -> Option<String> {
+        let proxy = self.proxy.clone().unwrap_or_default();
+        let prefix = match (proxy.path_prefix, proxy.host) {
+            (Some(prefix), _) => prefix,
+            (None, Some(_)) => return None,
+            (None, None) => self.name.clone(),
+        };
+        Some(format!("/{}", prefix.trim_matches('/')))
+    }
+
+    pub fn proxy_strip_prefix(&self) -> bool {
+        self.proxy
+            .as_ref()
+            .and_then(|proxy| proxy.strip_prefix)
+            .unwrap_or(true)
+    }
+
+    /// Capture configuration of the requests proxied to the service, None if they are not
+    /// captured.
+    pub fn proxy_capture(&self) -> Option<config::CaptureConfig> {
+        self.proxy.as_ref().and_then(|proxy| proxy.capture.clone())
+    }
+
+    /// Time without proxied requests after which the service is put to sleep, `default` is
+    /// the `proxy.idle_timeout` of the agent. None if it is kept running.
+    pub fn proxy_idle_timeout(&self, default: Option<u64>) -> Option<std::time::Duration> {
+        self.proxy
+            .as_ref()
+            .and_then(|proxy| proxy.idle_timeout)
+            .or(default)
+            .filter(|seconds| *seconds > 0)
+            .map(std::time::Duration::from_secs)
+    }
 }
 
-#[derive(Debug, Serialize, Clone, Copy)]
-#[derive(Default)]
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, Default)]
 pub enum ServiceStatus {
     Running,
     #[default]
     Stopped,
+    /// Stopped for being idle, the proxy starts it again on the next request.
+    Sleeping,
 }
 
+impl std::fmt::Display for ServiceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ServiceStatus::Running => write!(f, "Running"),
+            ServiceStatus::Stopped => write!(f, "Stopped"),
+            ServiceStatus::Sleeping => write!(f, "Sleeping"),
+        }
+    }
+}
+
+// Runtime information about the service process. It is shared with the
+// process controller callbacks, so it is updated when the process exits
+// or its health changes.
+#[derive(Default, Debug)]
+struct ServiceRuntime {
+    pid: Option<u32>,
+    started_at: Option<Instant>,
+    health: Option<crate::HealthStatus>,
+    starts: u64,
+    last_exit_code: Option<i32>,
+}
+
+/// Per stream (stdout/stderr) counters.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StreamsInfo {
+    pub stdout: usize,
+    pub stderr: usize,
+}
+
+/// Snapshot of a service, its configuration and its runtime information.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceInfo {
+    pub id: uuid::Uuid,
+    pub description: ServiceDescription,
+    pub status: ServiceStatus,
+    pub pid: Option<u32>,
+    pub public_url: Option<String>,
+    pub health: Option<crate::HealthStatus>,
+    /// Seconds since the process was spawned, None if it is not running.
+    pub uptime: Option<u64>,
+    pub restart_count: u64,
+    pub last_exit_code: Option<i32>,
+    /// Bytes of output kept in memory for new subscribers.
+    pub log_history_bytes: StreamsInfo,
+    /// Number of clients currently following the output.
+    pub subscribers: StreamsInfo,
+    /// Whether a client is attached to the stdin of the service.
+    pub stdin_attached: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum AttachError {
+    #[error("Service '{0}' does not accept input, neither stdin nor tty are enabled")]
+    StdinDisabled(String),
+
+    #[error("Another client is already attached to service '{0}'")]
+    AlreadyAttached(String),
+}
+
+// Where the input of the running process goes.
+#[derive(Debug)]
+enum ServiceInput {
+    Pipe(tokio::process::ChildStdin),
+    // Master side of the terminal the process runs in.
+    Pty(tokio::fs::File),
+}
+
+/// Exclusive handle to write into the stdin of a service (see `Service::attach_stdin`),
+/// the service can be attached to again once it is dropped.
+///
+/// Writes go to the current process of the service, so the handle survives restarts.
+#[derive(Debug)]
+pub struct StdinWriter {
+    stdin: Arc<Mutex<Option<ServiceInput>>>,
+    attached: Arc<AtomicBool>,
+}
+
+impl StdinWriter {
+    pub async fn write(&self, data: &[u8]) -> std::io::Result<()> {
+        match self.stdin.lock().await.as_mut() {
+            Some(ServiceInput::Pipe(stdin)) => {
+                stdin.write_all(data).await?;
+                stdin.flush().await
+            }
+            Some(ServiceInput::Pty(master)) => {
+                master.write_all(data).await?;
+                master.flush().await
+            }
+            None => Err(not_running()),
+        }
+    }
+
+    /// Resizes the terminal of the service, only services running in a tty can be resized.
+    pub async fn resize(&self, size: WindowSize) -> std::io::Result<()> {
+        match self.stdin.lock().await.as_ref() {
+            Some(ServiceInput::Pty(master)) => crate::pty::resize(master, size),
+            Some(ServiceInput::Pipe(_)) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Service is not running in a tty",
+            )),
+            None => Err(not_running()),
+        }
+    }
+}
+
+fn not_running() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Service is not running")
+}
+
+impl Drop for StdinWriter {
+    fn drop(&mut self) {
+        self.attached.store(false, Ordering::SeqCst);
+    }
+}
 
 #[derive(Default, Debug)]
 pub struct Service {
@@ -74,8 +266,13 @@ pub struct Service {
     process_controller: Option<ProcessController>,
     stdout: PersistedBufReaderBroadcaster,
     stderr: PersistedBufReaderBroadcaster,
+    // Requests proxied to the service, see `proxy`.
+    access_log: PersistedBufReaderBroadcaster,
     status: ServiceStatus,
     kittengrid_api: Arc<Mutex<Option<KittengridApi>>>,
+    runtime: Arc<std::sync::Mutex<ServiceRuntime>>,
+    stdin: Arc<Mutex<Option<ServiceInput>>>,
+    stdin_attached: Arc<AtomicBool>,
 }
 
 impl Serialize for Service {
@@ -100,10 +297,27 @@ impl From<config::ServiceConfig> for Service {
     }
 }
 
+// Namespace used to derive the service ids, see `Service::stable_id`.
+const SERVICE_ID_NAMESPACE: uuid::Uuid = uuid::uuid!("5f0e6a52-3c8b-4d0e-9a51-8c2b1d7e4f63");
+
+/// Returns an id for the service that only depends on the project, the pull request
+/// and the service name, so it is the same every time the agent boots.
+pub fn stable_id(config: &config::Config, name: &str) -> uuid::Uuid {
+    uuid::Uuid::new_v5(
+        &SERVICE_ID_NAMESPACE,
+        format!(
+            "{}/{}/{}/{}",
+            config.vcs_provider, config.project_vcs_path, config.pull_request_vcs_id, name
+        )
+        .as_bytes(),
+    )
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum ServiceStream {
     Stdout,
     Stderr,
+    AccessLog,
 }
 
 impl std::fmt::Display for ServiceStream {
@@ -111,11 +325,21 @@ impl std::fmt::Display for ServiceStream {
         match self {
             ServiceStream::Stdout => write!(f, "stdout"),
             ServiceStream::Stderr => write!(f, "stderr"),
+            ServiceStream::AccessLog => write!(f, "access_log"),
         }
     }
 }
 
 impl Service {
+    /// Creates a service with a stable id (see `stable_id`) from its configuration.
+    pub fn new(config: &config::Config, service_config: config::ServiceConfig) -> Self {
+        let id = stable_id(config, &service_config.name);
+        Self {
+            id,
+            ..service_config.into()
+        }
+    }
+
     pub fn set_kittengrid_api_handle(&mut self, kittengrid_api: Arc<Mutex<Option<KittengridApi>>>) {
         self.kittengrid_api = kittengrid_api;
     }
@@ -125,10 +349,7 @@ impl Service {
     }
 
     pub async fn subscribe_to_stream(&self, stream: ServiceStream) -> BufferReceiver {
-        match stream {
-            ServiceStream::Stdout => self.stdout.subscribe().await,
-            ServiceStream::Stderr => self.stderr.subscribe().await,
-        }
+        self.stream(stream).subscribe().await
     }
 
     pub fn set_public_url(&mut self, public_url: String) {
@@ -151,9 +372,14 @@ impl Service {
         receiver: BufferReceiver,
         stream: ServiceStream,
     ) {
+        self.stream(stream).unsubscribe(receiver).await
+    }
+
+    fn stream(&self, stream: ServiceStream) -> &PersistedBufReaderBroadcaster {
         match stream {
-            ServiceStream::Stdout => self.stdout.unsubscribe(receiver).await,
-            ServiceStream::Stderr => self.stderr.unsubscribe(receiver).await,
+            ServiceStream::Stdout => &self.stdout,
+            ServiceStream::Stderr => &self.stderr,
+            ServiceStream::AccessLog => &self.access_log,
         }
     }
 
@@ -171,6 +397,12 @@ impl Service {
         self.stderr.clone()
     }
 
+    /// Returns the broadcaster of the access log, a JSON line per request proxied to the
+    /// service.
+    pub fn access_log(&self) -> PersistedBufReaderBroadcaster {
+        self.access_log.clone()
+    }
+
     /// Returns the service description.
     pub fn description(&self) -> &ServiceDescription {
         &self.description
@@ -190,11 +422,43 @@ impl Service {
         self.id
     }
 
+    pub fn status(&self) -> ServiceStatus {
+        self.status
+    }
+
     /// Syntax sugar for getting the health check of the service.
     pub fn health_check(&self) -> Option<config::HealthCheck> {
         self.description.health_check.clone()
     }
 
+    /// Returns the configuration along with the runtime information of the service.
+    pub fn info(&self) -> ServiceInfo {
+        let runtime = self.runtime.lock().unwrap();
+
+        ServiceInfo {
+            id: self.id,
+            description: self.description.clone(),
+            status: self.status,
+            pid: runtime.pid,
+            public_url: self.public_url.clone(),
+            health: runtime.health,
+            uptime: runtime
+                .started_at
+                .map(|started_at| started_at.elapsed().as_secs()),
+            restart_count: runtime.starts.saturating_sub(1),
+            last_exit_code: runtime.last_exit_code,
+            log_history_bytes: StreamsInfo {
+                stdout: self.stdout.history_size(),
+                stderr: self.stderr.history_size(),
+            },
+            subscribers: StreamsInfo {
+                stdout: self.stdout.subscribers(),
+                stderr: self.stderr.subscribers(),
+            },
+            stdin_attached: self.stdin_attached.load(Ordering::SeqCst),
+        }
+    }
+
     /// Stops the service
     /// It will stop the service sending a TERM signal, note that stdout/stderr channels will be kept open.
     pub async fn stop(&mut self) -> std::io::Result<()> {
@@ -234,9 +498,27 @@ impl Service {
                 info!("Service {} was not running", self.description.name);
             }
         }
+        // Clients attached to the service get an error until it is started again.
+        *self.stdin.lock().await = None;
         Ok(())
     }
 
+    /// Returns an exclusive handle to write into the stdin (or the terminal) of the service,
+    /// only one client can be attached at a time.
+    pub fn attach_stdin(&self) -> Result<StdinWriter, AttachError> {
+        if !self.description.stdin && !self.description.tty {
+            return Err(AttachError::StdinDisabled(self.description.name.clone()));
+        }
+        if self.stdin_attached.swap(true, Ordering::SeqCst) {
+            return Err(AttachError::AlreadyAttached(self.description.name.clone()));
+        }
+
+        Ok(StdinWriter {
+            stdin: Arc::clone(&self.stdin),
+            attached: Arc::clone(&self.stdin_attached),
+        })
+    }
+
     pub fn injected_env(&self) -> HashMap<String, String> {
         let mut env = HashMap::new();
         if let Some(public_url) = self.public_url.as_ref() {
@@ -246,8 +528,37 @@ impl Service {
         env
     }
 
+    // Spawns the process, in a terminal if tty is enabled. Returns the terminal
+    // output reader and input handle along with the child in that case.
+    fn spawn(&self) -> std::io::Result<(Child, Option<(PtyReader, std::fs::File)>)> {
+        let mut cmd = Command::new(&self.description.cmd);
+
+        if self.description.tty {
+            // Colors and TUIs need a capable terminal, unless the service sets its own.
+            cmd.env("TERM", "xterm-256color");
+        }
+        cmd.args(&self.description.args)
+            .envs(&self.description.env)
+            .envs(self.injected_env());
+
+        if self.description.tty {
+            let pty = Pty::open(WindowSize::default())?;
+            pty.attach(&mut cmd)?;
+            let child = cmd.spawn()?;
+            return Ok((child, Some((pty.reader()?, pty.writer()?))));
+        }
+
+        cmd.stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+        if self.description.stdin {
+            cmd.stdin(std::process::Stdio::piped());
+        }
+        Ok((cmd.spawn()?, None))
+    }
+
     /// Starts the service
     /// It will spawn the service and start broadcasting the stdout and stderr to the subscribers.
+    /// Services running in a tty only have output on stdout.
     pub async fn start(&mut self) -> std::io::Result<()> {
         debug!("Starting service '{}'", self.description.name);
         let kittengrid_api = self.kittengrid_api().await;
@@ -265,16 +576,8 @@ impl Service {
             }
         }
 
-        let mut cmd = Command::new(&self.description.cmd);
-
-        cmd.args(&self.description.args)
-            .envs(&self.description.env)
-            .envs(self.injected_env())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped());
-
-        let mut child = match cmd.spawn() {
-            Ok(child) => child,
+        let (mut child, pty) = match self.spawn() {
+            Ok(spawned) => spawned,
             Err(e) => {
                 self.status = ServiceStatus::Stopped;
                 if let Some(kittengrid_api) = &kittengrid_api {
@@ -296,13 +599,40 @@ impl Service {
                 return Err(e);
             }
         };
-        let stdout = BufReader::new(child.stdout.take().expect("stdout is None"));
-        self.stdout.watch(stdout).await;
+        match pty {
+            Some((reader, master)) => {
+                self.stdout.watch(reader).await;
+                *self.stdin.lock().await =
+                    Some(ServiceInput::Pty(tokio::fs::File::from_std(master)));
+            }
+            None => {
+                let stdout = BufReader::new(child.stdout.take().expect("stdout is None"));
+                self.stdout.watch(stdout).await;
 
-        let stderr = BufReader::new(child.stderr.take().expect("stderr is None"));
-        self.stderr.watch(stderr).await;
+                let stderr = BufReader::new(child.stderr.take().expect("stderr is None"));
+                self.stderr.watch(stderr).await;
+
+                if let Some(stdin) = child.stdin.take() {
+                    match tokio::process::ChildStdin::from_std(stdin) {
+                        Ok(stdin) => *self.stdin.lock().await = Some(ServiceInput::Pipe(stdin)),
+                        Err(e) => error!(
+                            "Error piping stdin of service '{}': {}",
+                            self.description.name, e
+                        ),
+                    }
+                }
+            }
+        }
         self.status = ServiceStatus::Running;
 
+        {
+            let mut runtime = self.runtime.lock().unwrap();
+            runtime.pid = Some(child.id());
+            runtime.started_at = Some(Instant::now());
+            runtime.health = None;
+            runtime.starts += 1;
+        }
+
         if let Some(kittengrid_api) = &kittengrid_api {
             if let Err(e) = kittengrid_api
                 .services_update_status(
@@ -321,6 +651,7 @@ impl Service {
             self.description.name.clone(),
             self.id,
             Arc::clone(&self.kittengrid_api),
+            Arc::clone(&self.runtime),
         ));
 
         let health_check = self.health_check().map(|health_check| {
@@ -331,6 +662,7 @@ impl Service {
             self.description.name.clone(),
             self.id,
             Arc::clone(&self.kittengrid_api),
+            Arc::clone(&self.runtime),
         ));
 
         let process_controller = ProcessController::new(
@@ -338,6 +670,7 @@ impl Service {
             on_stop_callback,
             health_check,
             Some(on_health_status_change_callback),
+            self.description.stop_grace_period(),
         )
         .await;
         self.process_controller = Some(process_controller);
@@ -346,11 +679,12 @@ impl Service {
     }
 
     // Returns the callback that will be called when the service stops.
-    // It simply makes a call to kittengrid api to update the service status.
+    // It records the exit code and makes a call to kittengrid api to update the service status.
     fn create_on_exit_callback(
         service_name: String,
         service_id: uuid::Uuid,
         kittengrid_api: Arc<Mutex<Option<KittengridApi>>>,
+        runtime: Arc<std::sync::Mutex<ServiceRuntime>>,
     ) -> impl Fn(ExitStatus) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync {
         move |status: ExitStatus| {
             let description = service_name.clone();
@@ -359,6 +693,14 @@ impl Service {
             let kittengrid_api = Arc::clone(&kittengrid_api);
             let exit_status = status.code();
 
+            {
+                let mut runtime = runtime.lock().unwrap();
+                runtime.pid = None;
+                runtime.started_at = None;
+                runtime.health = None;
+                runtime.last_exit_code = exit_status;
+            }
+
             Box::pin(async move {
                 if let Some(kittengrid_api) = kittengrid_api.lock().await.clone() {
                     match kittengrid_api
@@ -380,17 +722,19 @@ impl Service {
 
     // Returns the callback that will be called when the service health status
     // changes.
-    // It simply makes a call to kittengrid api to update the service status.
+    // It records the new status and makes a call to kittengrid api to update the service status.
     fn create_health_status_callback(
         service_name: String,
         service_id: uuid::Uuid,
         kittengrid_api: Arc<Mutex<Option<KittengridApi>>>,
+        runtime: Arc<std::sync::Mutex<ServiceRuntime>>,
     ) -> impl Fn(crate::HealthStatus) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync
     {
         move |status: crate::HealthStatus| {
             let description = service_name.clone();
             let id = service_id;
             let kittengrid_api = Arc::clone(&kittengrid_api);
+            runtime.lock().unwrap().health = Some(status);
 
             Box::pin(async move {
                 if let Some(kittengrid_api) = kittengrid_api.lock().await.clone() {
@@ -418,6 +762,8 @@ impl Service {
 #[derive(Default, Debug)]
 pub struct Services {
     services: Mutex<HashMap<uuid::Uuid, Arc<Mutex<Service>>>>,
+    // Ids of the services in the order they were added.
+    order: Mutex<Vec<uuid::Uuid>>,
     kittengrid_api: Arc<Mutex<Option<KittengridApi>>>,
 }
 
@@ -425,6 +771,7 @@ impl Services {
     pub fn new() -> Self {
         Self {
             services: Mutex::new(HashMap::new()),
+            order: Mutex::new(Vec::new()),
             kittengrid_api: Arc::new(Mutex::new(None)),
         }
     }
@@ -433,10 +780,18 @@ impl Services {
         *self.kittengrid_api.lock().await = kittengrid_api;
     }
 
+    pub async fn kittengrid_api(&self) -> Option<KittengridApi> {
+        self.kittengrid_api.lock().await.clone()
+    }
+
     /// Adds a service to the services list.
     pub async fn insert(&self, mut service: Service) {
         debug!("Adding service '{}' to services.", service.description.name);
         service.set_kittengrid_api_handle(Arc::clone(&self.kittengrid_api));
+        let mut order = self.order.lock().await;
+        if !order.contains(&service.id) {
+            order.push(service.id);
+        }
         self.services
             .lock()
             .await
@@ -457,11 +812,23 @@ impl Services {
         }
     }
 
-    /// Returns a service by its name.
+    /// Returns a service by its id.
     pub async fn fetch(&self, id: uuid::Uuid) -> Option<Arc<Mutex<Service>>> {
         self.services.lock().await.get(&id).cloned()
     }
 
+    /// Returns the ids of the services with the given name.
+    /// Names are not required to be unique, so there can be more than one.
+    pub async fn ids_by_name(&self, name: &str) -> Vec<uuid::Uuid> {
+        let mut ids = Vec::new();
+        for (id, service) in self.services.lock().await.iter() {
+            if service.lock().await.description.name == name {
+                ids.push(*id);
+            }
+        }
+        ids
+    }
+
     /// Returns the description of a service by its name.
     pub async fn description(&self, id: uuid::Uuid) -> Option<ServiceDescription> {
         match self.services.lock().await.get(&id) {
@@ -489,34 +856,46 @@ impl Services {
         service.stop().await
     }
 
-    pub async fn to_json(&self) -> serde_json::Value {
-        #[derive(Serialize)]
-        struct InnerService {
-            id: uuid::Uuid,
-            description: ServiceDescription,
-            status: ServiceStatus,
+    /// Stops an idle service by its id (see `stop_service`), it is marked as sleeping so
+    /// the proxy starts it again on the next request for it.
+    pub async fn sleep_service(&self, id: uuid::Uuid) -> std::io::Result<()> {
+        self.stop_service(id).await?;
+        if let Some(service) = self.fetch(id).await {
+            let mut service = service.lock().await;
+            if service.status == ServiceStatus::Stopped {
+                service.status = ServiceStatus::Sleeping;
+            }
         }
+        Ok(())
+    }
 
+    pub async fn to_json(&self) -> serde_json::Value {
         #[derive(Serialize)]
         struct ServicesSerializer {
-            services: Vec<InnerService>,
+            services: Vec<ServiceInfo>,
         }
-        let mut services: ServicesSerializer = ServicesSerializer {
-            services: Vec::new(),
+        let services: ServicesSerializer = ServicesSerializer {
+            services: self.infos().await,
         };
 
-        for service in self.services.lock().await.values() {
-            let service = service.lock().await;
-            let inner = InnerService {
-                id: service.id(),
-                description: service.description().clone(),
-                status: service.status,
-            };
+        json!(services)
+    }
 
-            services.services.push(inner);
+    /// Returns the info (configuration and runtime information) of a service by its id.
+    pub async fn info(&self, id: uuid::Uuid) -> Option<ServiceInfo> {
+        match self.services.lock().await.get(&id) {
+            Some(service) => Some(service.lock().await.info()),
+            None => None,
         }
+    }
 
-        json!(services)
+    /// Returns the info of every service.
+    pub async fn infos(&self) -> Vec<ServiceInfo> {
+        let mut infos = Vec::new();
+        for service in self.services.lock().await.values() {
+            infos.push(service.lock().await.info());
+        }
+        infos
     }
 
     /// Starts a service by its id.
@@ -543,10 +922,7 @@ impl Services {
     ) -> Option<BufferReceiver> {
         debug!("Subscribing to stdout for service {}", id);
         let stream = match self.services.lock().await.get(&id).cloned() {
-            Some(service) => match stream {
-                ServiceStream::Stdout => service.lock().await.stdout(),
-                ServiceStream::Stderr => service.lock().await.stderr(),
-            },
+            Some(service) => service.lock().await.stream(stream).clone(),
             None => return None,
         };
 
@@ -562,10 +938,7 @@ impl Services {
     ) -> Result<(), std::io::Error> {
         debug!("Subscribing to stdout for service {}", id);
         let stream = match self.services.lock().await.get(&id).cloned() {
-            Some(service) => match stream {
-                ServiceStream::Stdout => service.lock().await.stdout(),
-                ServiceStream::Stderr => service.lock().await.stderr(),
-            },
+            Some(service) => service.lock().await.stream(stream).clone(),
             None => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::NotFound,
@@ -587,12 +960,21 @@ impl Services {
         descriptions
     }
 
-    /// Stops every service.
+    /// Returns the ids of the services in the order they were added, the one they are
+    /// started in.
+    pub async fn start_order(&self) -> Vec<uuid::Uuid> {
+        self.order.lock().await.clone()
+    }
+
+    /// Stops every service, in the reverse order they were added.
     pub async fn stop(&self) -> std::io::Result<()> {
         debug!("Stopping all services");
-        for taken_service in self.services.lock().await.values() {
-            debug!("Stopping service '{}'", taken_service.lock().await.name());
-            taken_service.lock().await.stop().await?;
+        for id in self.start_order().await.into_iter().rev() {
+            if let Some(service) = self.fetch(id).await {
+                let mut service = service.lock().await;
+                debug!("Stopping service '{}'", service.name());
+                service.stop().await?;
+            }
         }
         Ok(())
     }
@@ -629,6 +1011,124 @@ mod test {
         assert_eq!(service.description.cmd, "test");
     }
 
+    #[test]
+    fn stable_ids() {
+        let mut config = crate::config::get_config().clone();
+        let service_config = config::ServiceConfig {
+            name: "test".to_string(),
+            ..Default::default()
+        };
+        let id = Service::new(&config, service_config.clone()).id();
+        assert_eq!(id, Service::new(&config, service_config.clone()).id());
+        assert_eq!(id, stable_id(&config, "test"));
+        assert_ne!(id, stable_id(&config, "other"));
+
+        config.pull_request_vcs_id = "other-pull-request".to_string();
+        assert_ne!(id, Service::new(&config, service_config).id());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn attach_stdin() {
+        initialize_tests();
+        let config = config::ServiceConfig {
+            name: "/bin/cat".to_string(),
+            stdin: true,
+            ..Default::default()
+        };
+        let mut service = Service::from(config);
+        let mut receiver = service.subscribe_to_stream(ServiceStream::Stdout).await;
+        service.start().await.unwrap();
+
+        let writer = service.attach_stdin().unwrap();
+        assert!(matches!(
+            service.attach_stdin(),
+            Err(AttachError::AlreadyAttached(_))
+        ));
+        assert!(service.info().stdin_attached);
+
+        writer.write(b"hello\n").await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), "hello\n");
+
+        drop(writer);
+        assert!(!service.info().stdin_attached);
+        let writer = service.attach_stdin().unwrap();
+        service.stop().await.unwrap();
+        assert!(writer.write(b"hello\n").await.is_err());
+
+        let service = Service::from(config::ServiceConfig {
+            name: "/bin/cat".to_string(),
+            ..Default::default()
+        });
+        assert!(matches!(
+            service.attach_stdin(),
+            Err(AttachError::StdinDisabled(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn tty() {
+        initialize_tests();
+        let config = config::ServiceConfig {
+            name: "/bin/bash".to_string(),
+            args: Some(vec![
+                "-c".to_string(),
+                "[ -t 1 ] && echo $TERM; read line; stty size".to_string(),
+            ]),
+            tty: true,
+            ..Default::default()
+        };
+        let mut service = Service::from(config);
+        let mut receiver = service.subscribe_to_stream(ServiceStream::Stdout).await;
+        service.start().await.unwrap();
+
+        // Output comes in chunks, not lines.
+        let mut output = String::new();
+        let mut read_until = async |expected: &str| {
+            while !output.contains(expected) {
+                let data = receiver.recv().await.unwrap();
+                output.push_str(&String::from_utf8_lossy(&data));
+            }
+        };
+        read_until("xterm-256color\r\n").await;
+
+        let writer = service.attach_stdin().unwrap();
+        writer
+            .resize(WindowSize {
+                cols: 100,
+                rows: 30,
+            })
+            .await
+            .unwrap();
+        writer.write(b"\n").await.unwrap();
+        read_until("30 100\r\n").await;
+
+        service.stop().await.unwrap();
+        assert!(writer.resize(WindowSize::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn start_order() {
+        initialize_tests();
+        let services = Services::new();
+        for name in ["web", "api", "db"] {
+            services
+                .insert(Service::from(config::ServiceConfig {
+                    name: name.to_string(),
+                    ..Default::default()
+                }))
+                .await;
+        }
+
+        let descriptions = services.descriptions().await;
+        let names: Vec<String> = services
+            .start_order()
+            .await
+            .iter()
+            .map(|id| descriptions[id].name())
+            .collect();
+        assert_eq!(names, vec!["web", "api", "db"]);
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
     async fn spawn() {
         initialize_tests();
@@ -656,6 +1156,32 @@ mod test {
         service.stop().await.unwrap();
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn subscribe_after_start() {
+        initialize_tests();
+        let config = config::ServiceConfig {
+            name: "/bin/bash".to_string(),
+            args: Some(vec!["-c".to_string(), "echo 1; echo 2;".to_string()]),
+            ..Default::default()
+        };
+        let mut service = Service::from(config);
+        service.start().await.unwrap();
+
+        // Whether they were read before subscribing or after, no line is lost.
+        let mut receiver = service.subscribe_to_stream(ServiceStream::Stdout).await;
+        let mut data = Vec::new();
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while data.len() < "1\n2\n".len() {
+                data.extend_from_slice(&receiver.recv().await.unwrap());
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(data, b"1\n2\n");
+
+        service.stop().await.unwrap();
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
     async fn spawn_log() {
         initialize_tests();
@@ -697,6 +1223,49 @@ mod test {
         service.stop().await.unwrap();
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn info() {
+        initialize_tests();
+        let config = config::ServiceConfig {
+            name: "/bin/bash".to_string(),
+            args: Some(vec!["-c".to_string(), "echo 1; exit 3".to_string()]),
+            ..Default::default()
+        };
+        let mut service = Service::from(config);
+        let info = service.info();
+        assert!(info.pid.is_none());
+        assert!(info.uptime.is_none());
+        assert_eq!(info.restart_count, 0);
+
+        let mut receiver = service.subscribe_to_stream(ServiceStream::Stdout).await;
+        service.start().await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), Bytes::from("1\n"));
+        let info = service.info();
+        assert_eq!(info.log_history_bytes.stdout, 2);
+        assert_eq!(info.subscribers.stdout, 1);
+        assert_eq!(info.subscribers.stderr, 0);
+
+        service
+            .process_controller
+            .as_mut()
+            .unwrap()
+            .wait()
+            .await
+            .unwrap();
+        let info = service.info();
+        assert!(info.pid.is_none());
+        assert_eq!(info.last_exit_code, Some(3));
+
+        service.stop().await.unwrap();
+        service.start().await.unwrap();
+        let info = service.info();
+        assert!(info.pid.is_some());
+        assert!(info.uptime.is_some());
+        assert_eq!(info.restart_count, 1);
+
+        service.stop().await.unwrap();
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
     async fn test_spawn_inherits_env_vars() {
         initialize_tests();
@@ -0,0 +1,78 @@
+use log::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+/// Handle used to ask the agent to shut down, and to wait until someone does.
+///
+/// It is cheap to clone, every clone triggers (and waits for) the same shutdown. The
+/// server stops accepting connections once it is triggered (see `crate::launch`), open
+/// websockets are closed and the agent then stops services and tunnels.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts the shutdown, triggering it more than once is harmless.
+    pub fn trigger(&self, reason: &str) {
+        if !self.token.is_cancelled() {
+            info!("Shutting down: {}", reason);
+        }
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Waits until the shutdown is triggered.
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Triggers the shutdown when the process receives a TERM or INT signal.
+    pub fn listen_for_signals(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            let mut terminate = match signal(SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(e) => {
+                    error!("Could not listen for TERM signals: {}", e);
+                    return;
+                }
+            };
+
+            tokio::select! {
+                _ = terminate.recv() => shutdown.trigger("received TERM signal"),
+                _ = tokio::signal::ctrl_c() => shutdown.trigger("received INT signal"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::{timeout, Duration};
+
+    #[tokio::test]
+    async fn trigger() {
+        let shutdown = Shutdown::new();
+        let clone = shutdown.clone();
+        assert!(!clone.is_triggered());
+        assert!(timeout(Duration::from_millis(50), clone.triggered())
+            .await
+            .is_err());
+
+        shutdown.trigger("test");
+        shutdown.trigger("test again");
+        assert!(clone.is_triggered());
+        assert!(timeout(Duration::from_millis(50), clone.triggered())
+            .await
+            .is_ok());
+    }
+}
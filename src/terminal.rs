@@ -0,0 +1,198 @@
+use crate::config::TerminalConfig;
+use crate::pty::{Pty, WindowSize};
+use bytes::Bytes;
+use log::{debug, error};
+use recording::{Recording, Recordings};
+use std::io::Read;
+use std::process::{Child, Command};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+// Time the shell has to exit after the hangup before it is killed.
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+const OUTPUT_CHANNEL_SIZE: usize = 32;
+
+pub mod recording;
+
+/// Interactive shell running in its own pseudo terminal, one per client of the
+/// `GET /public/terminal` websocket.
+///
+/// The shell is started in a new session, so closing the terminal hangs up every
+/// process started from it. Sessions are recorded (see `Recordings`).
+#[derive(Debug)]
+pub struct TerminalSession {
+    id: uuid::Uuid,
+    child: Child,
+    input: tokio::fs::File,
+    output: mpsc::Receiver<Bytes>,
+    recording: Recording,
+}
+
+impl TerminalSession {
+    /// Starts the configured shell (`$SHELL` or `/bin/bash` by default) in a terminal
+    /// of the given size, for the given bearer. Sessions that can't be recorded are not started.
+    pub fn spawn(
+        config: &TerminalConfig,
+        size: WindowSize,
+        bearer_id: &str,
+        recordings: &Recordings,
+    ) -> std::io::Result<Self> {
+        let shell = config.shell();
+        let id = uuid::Uuid::new_v4();
+        let recording = recordings.start(id, bearer_id, size, &shell)?;
+
+        let pty = Pty::open(size)?;
+        let mut cmd = Command::new(&shell);
+        cmd.args(config.args.clone().unwrap_or_default())
+            .env("TERM", "xterm-256color")
+            .envs(config.env.clone().unwrap_or_default());
+        if let Some(cwd) = config.cwd() {
+            cmd.current_dir(cwd);
+        }
+        pty.attach(&mut cmd)?;
+        let child = cmd.spawn()?;
+        let mut reader = pty.reader()?;
+        let input = tokio::fs::File::from_std(pty.writer()?);
+
+        debug!("Started terminal session {id} running '{shell}'");
+
+        // The reader blocks, so it gets its own thread. It stops once the shell exits
+        // (EOF) or the session is dropped.
+        let (sender, output) = mpsc::channel(OUTPUT_CHANNEL_SIZE);
+        tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0; 8192];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        if sender
+                            .blocking_send(Bytes::copy_from_slice(&buf[..read]))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error reading from terminal session {id}: {e}");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            id,
+            child,
+            input,
+            output,
+            recording,
+        })
+    }
+
+    pub fn id(&self) -> uuid::Uuid {
+        self.id
+    }
+
+    /// Returns the next chunk of output, None once the shell exits.
+    pub async fn read(&mut self) -> Option<Bytes> {
+        let data = self.output.recv().await?;
+        self.recording.output(&data);
+        Some(data)
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.input.write_all(data).await?;
+        self.input.flush().await
+    }
+
+    pub fn resize(&mut self, size: WindowSize) -> std::io::Result<()> {
+        crate::pty::resize(&self.input, size)?;
+        self.recording.resize(size);
+        Ok(())
+    }
+
+    /// Hangs up the terminal and waits for the shell to exit, killing it if it does not.
+    pub async fn close(mut self) {
+        let pid = self.child.id() as libc::pid_t;
+        // The shell leads its own process group, hang up all of it.
+        unsafe {
+            libc::kill(-pid, libc::SIGHUP);
+        }
+
+        let id = self.id;
+        // Dropping the session in the blocking pool also waits for the recording.
+        let result = tokio::task::spawn_blocking(move || {
+            let deadline = std::time::Instant::now() + CLOSE_GRACE_PERIOD;
+            loop {
+                match self.child.try_wait() {
+                    Ok(Some(status)) => return Ok(status),
+                    Ok(None) if std::time::Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(50))
+                    }
+                    Ok(None) => {
+                        self.child.kill()?;
+                        return self.child.wait();
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+        .await;
+
+        match result {
+            Ok(Ok(status)) => debug!("Terminal session {id} closed: {status}"),
+            Ok(Err(e)) => error!("Error closing terminal session {id}: {e}"),
+            Err(e) => error!("Error closing terminal session {id}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn session() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = TerminalConfig {
+            command: Some("/bin/sh".to_string()),
+            cwd: Some(dir.path().to_string_lossy().to_string()),
+            env: Some(HashMap::from([("FOO".to_string(), "bar".to_string())])),
+            ..Default::default()
+        };
+        let recordings = Recordings::new(dir.path().to_path_buf(), Duration::from_secs(60), 10);
+        let mut session =
+            TerminalSession::spawn(&config, WindowSize::default(), "someone", &recordings).unwrap();
+        session
+            .resize(WindowSize {
+                cols: 100,
+                rows: 30,
+            })
+            .unwrap();
+        session
+            .write(b"stty size; echo $TERM $FOO; pwd\n")
+            .await
+            .unwrap();
+
+        let expected = format!(
+            "30 100\r\nxterm-256color bar\r\n{}\r\n",
+            dir.path().display()
+        );
+        let mut output = String::new();
+        while !output.contains(&expected) {
+            output.push_str(&String::from_utf8_lossy(&session.read().await.unwrap()));
+        }
+
+        // Exiting the shell ends the output.
+        session.write(b"exit\n").await.unwrap();
+        while session.read().await.is_some() {}
+        let id = session.id();
+        session.close().await;
+
+        let recording = std::fs::read_to_string(recordings.find(id).unwrap()).unwrap();
+        assert!(recording.contains(r#""100x30""#));
+        assert!(recording.contains("30 100"));
+    }
+}
@@ -0,0 +1,402 @@
+use crate::pty::WindowSize;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_RETENTION_DAYS: u64 = 30;
+const DEFAULT_MAX_RECORDINGS: usize = 100;
+const EXTENSION: &str = "cast";
+
+// First line of an asciicast v2 file, see https://docs.asciinema.org/manual/asciicast/v2/
+// Players ignore the fields they don't know about (bearer_id).
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u8,
+    width: u16,
+    height: u16,
+    timestamp: u64,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    bearer_id: String,
+}
+
+/// A recording, as listed by `Recordings::list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordingInfo {
+    /// Id of the terminal session.
+    pub id: uuid::Uuid,
+    /// Who opened the session (`bearer_id` of the token).
+    pub bearer_id: String,
+    /// Unix timestamp of the start of the session.
+    pub started_at: u64,
+    /// Whether the session is still open.
+    pub active: bool,
+    /// Size of the recording in bytes.
+    pub size: u64,
+}
+
+/// Recordings of the terminal sessions, kept as asciicast v2 files (`<session id>.cast`)
+/// so they can be played with asciinema.
+///
+/// Recordings older than the retention period are deleted, along with the oldest ones
+/// when there are more than the maximum, every time a new one is started.
+#[derive(Debug, Clone)]
+pub struct Recordings {
+    path: PathBuf,
+    retention: Duration,
+    max_recordings: usize,
+    active: Arc<Mutex<HashSet<uuid::Uuid>>>,
+}
+
+impl Recordings {
+    pub fn new(path: PathBuf, retention: Duration, max_recordings: usize) -> Self {
+        Self {
+            path,
+            retention,
+            max_recordings,
+            active: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Keeps the recordings in the DataDir, with the retention from the terminal configuration.
+    pub fn from_config(
+        config: &crate::config::TerminalConfig,
+    ) -> Result<Self, crate::data_dir::DataDirError> {
+        let path = crate::data_dir::get_data_dir().recordings_path()?;
+        let retention_days = config
+            .recordings_retention_days
+            .unwrap_or(DEFAULT_RETENTION_DAYS);
+        Ok(Self::new(
+            path,
+            Duration::from_secs(retention_days * 24 * 60 * 60),
+            config.max_recordings.unwrap_or(DEFAULT_MAX_RECORDINGS),
+        ))
+    }
+
+    /// Starts recording a session, writing the header of the file.
+    pub fn start(
+        &self,
+        id: uuid::Uuid,
+        bearer_id: &str,
+        size: WindowSize,
+        shell: &str,
+    ) -> std::io::Result<Recording> {
+        self.prune();
+
+        let header = Header {
+            version: 2,
+            width: size.cols,
+            height: size.rows,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            title: Some(format!("Terminal session opened by {bearer_id}")),
+            env: HashMap::from([
+                ("SHELL".to_string(), shell.to_string()),
+                ("TERM".to_string(), "xterm-256color".to_string()),
+            ]),
+            bearer_id: bearer_id.to_string(),
+        };
+
+        fs::create_dir_all(&self.path)?;
+        let mut file = File::create(self.path_for(id))?;
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        self.active.lock().unwrap().insert(id);
+
+        // Events are written by their own thread, output is recorded from async code.
+        let (events, receiver) = mpsc::channel::<serde_json::Value>();
+        let active = Arc::clone(&self.active);
+        let writer = std::thread::spawn(move || {
+            for event in receiver {
+                if let Err(e) = writeln!(file, "{}", event) {
+                    error!("Could not write recording of session {}: {}", id, e);
+                }
+            }
+            active.lock().unwrap().remove(&id);
+        });
+
+        Ok(Recording {
+            id,
+            started_at: Instant::now(),
+            pending: Vec::new(),
+            events: Some(events),
+            writer: Some(writer),
+        })
+    }
+
+    /// Returns the recordings, newest first.
+    pub fn list(&self) -> std::io::Result<Vec<RecordingInfo>> {
+        let active = self.active.lock().unwrap().clone();
+        let mut recordings = Vec::new();
+        for (id, path) in self.files()? {
+            match read_header(&path) {
+                Ok(header) => recordings.push(RecordingInfo {
+                    id,
+                    bearer_id: header.bearer_id,
+                    started_at: header.timestamp,
+                    active: active.contains(&id),
+                    size: fs::metadata(&path).map(|metadata| metadata.len())?,
+                }),
+                Err(e) => warn!("Skipping invalid recording {}: {}", path.display(), e),
+            }
+        }
+        recordings.sort_by_key(|recording| Reverse((recording.started_at, recording.id)));
+        Ok(recordings)
+    }
+
+    /// Returns the path of the recording of the session, if there is one.
+    pub fn find(&self, id: uuid::Uuid) -> Option<PathBuf> {
+        let path = self.path_for(id);
+        path.is_file().then_some(path)
+    }
+
+    /// Returns who opened the session of the recording.
+    pub fn bearer_id(&self, id: uuid::Uuid) -> std::io::Result<String> {
+        Ok(read_header(&self.path_for(id))?.bearer_id)
+    }
+
+    fn path_for(&self, id: uuid::Uuid) -> PathBuf {
+        self.path.join(format!("{id}.{EXTENSION}"))
+    }
+
+    // Recordings in the directory, files not named after a session are ignored.
+    fn files(&self) -> std::io::Result<Vec<(uuid::Uuid, PathBuf)>> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| uuid::Uuid::parse_str(stem).ok());
+            if let Some(id) = id {
+                files.push((id, path));
+            }
+        }
+        Ok(files)
+    }
+
+    // Deletes the expired recordings and the oldest ones over the limit, the ones of
+    // open sessions are kept.
+    fn prune(&self) {
+        let files = match self.files() {
+            Ok(files) => files,
+            Err(e) => {
+                error!("Could not list recordings: {}", e);
+                return;
+            }
+        };
+        let active = self.active.lock().unwrap().clone();
+        let now = SystemTime::now();
+
+        let mut recordings: Vec<(SystemTime, PathBuf)> = files
+            .into_iter()
+            .filter(|(id, _)| !active.contains(id))
+            .filter_map(|(_, path)| {
+                let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+                Some((modified, path))
+            })
+            .collect();
+        // Newest first, the ones past the limit get deleted.
+        recordings.sort_by_key(|(modified, _)| Reverse(*modified));
+        let keep = self.max_recordings.saturating_sub(active.len() + 1);
+
+        for (index, (modified, path)) in recordings.into_iter().enumerate() {
+            let expired = now
+                .duration_since(modified)
+                .is_ok_and(|age| age > self.retention);
+            if expired || index >= keep {
+                debug!("Deleting recording {}", path.display());
+                if let Err(e) = fs::remove_file(&path) {
+                    error!("Could not delete recording {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+fn read_header(path: &Path) -> std::io::Result<Header> {
+    let mut line = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Recording of an open session, events are written as they happen so the recording
+/// can be downloaded while the session is still open. Dropping it waits for the events
+/// to be written.
+#[derive(Debug)]
+pub struct Recording {
+    id: uuid::Uuid,
+    started_at: Instant,
+    // Output that ends in the middle of an UTF-8 character, events must be valid strings.
+    pending: Vec<u8>,
+    events: Option<mpsc::Sender<serde_json::Value>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Recording {
+    /// Records output of the terminal.
+    pub fn output(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        let data = take_utf8(&mut self.pending);
+        if !data.is_empty() {
+            self.event("o", &data);
+        }
+    }
+
+    /// Records a resize of the terminal.
+    pub fn resize(&mut self, size: WindowSize) {
+        self.event("r", &format!("{}x{}", size.cols, size.rows));
+    }
+
+    fn event(&mut self, code: &str, data: &str) {
+        let elapsed = self.started_at.elapsed().as_secs_f64();
+        let event = serde_json::json!([elapsed, code, data]);
+        if let Some(events) = &self.events {
+            if events.send(event).is_err() {
+                error!("Recording of session {} stopped.", self.id);
+            }
+        }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        if !self.pending.is_empty() {
+            let data = String::from_utf8_lossy(&self.pending).into_owned();
+            self.event("o", &data);
+        }
+        self.events.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                error!("Recording of session {} failed.", self.id);
+            }
+        }
+    }
+}
+
+// Takes the longest valid UTF-8 prefix of the data, leaving an incomplete character at
+// the end (if any) for the next call. Invalid sequences are replaced.
+fn take_utf8(data: &mut Vec<u8>) -> String {
+    match std::str::from_utf8(data) {
+        Ok(valid) => {
+            let valid = valid.to_string();
+            data.clear();
+            valid
+        }
+        Err(e) if e.error_len().is_none() => {
+            let valid = String::from_utf8_lossy(&data[..e.valid_up_to()]).into_owned();
+            data.drain(..e.valid_up_to());
+            valid
+        }
+        Err(_) => {
+            let valid = String::from_utf8_lossy(data).into_owned();
+            data.clear();
+            valid
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let dir = tempfile::tempdir().unwrap();
+        let recordings = Recordings::new(dir.path().to_path_buf(), Duration::from_secs(60), 10);
+        let id = uuid::Uuid::new_v4();
+
+        let mut recording = recordings
+            .start(id, "someone", WindowSize::default(), "/bin/sh")
+            .unwrap();
+        // "é" split between two chunks.
+        recording.output(b"caf\xc3");
+        recording.output(b"\xa9\r\n");
+        recording.resize(WindowSize {
+            cols: 100,
+            rows: 30,
+        });
+
+        let list = recordings.list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, id);
+        assert_eq!(list[0].bearer_id, "someone");
+        assert!(list[0].active);
+        drop(recording);
+        assert!(!recordings.list().unwrap()[0].active);
+
+        let contents = fs::read_to_string(recordings.find(id).unwrap()).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[0]["bearer_id"], "someone");
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "caf");
+        assert_eq!(lines[2][1], "o");
+        assert_eq!(lines[2][2], "é\r\n");
+        assert_eq!(lines[3][1], "r");
+        assert_eq!(lines[3][2], "100x30");
+
+        assert!(recordings.find(uuid::Uuid::new_v4()).is_none());
+    }
+
+    #[test]
+    fn retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let recordings = Recordings::new(dir.path().to_path_buf(), Duration::from_secs(60), 2);
+        let start = |id| {
+            recordings
+                .start(id, "someone", WindowSize::default(), "/bin/sh")
+                .unwrap()
+        };
+
+        let expired = uuid::Uuid::new_v4();
+        drop(start(expired));
+        File::options()
+            .write(true)
+            .open(recordings.path_for(expired))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(120))
+            .unwrap();
+        let first = uuid::Uuid::new_v4();
+        drop(start(first));
+        assert!(recordings.find(expired).is_none());
+
+        // Only two are kept, the open one always is.
+        let open = start(uuid::Uuid::new_v4());
+        let last = uuid::Uuid::new_v4();
+        drop(start(last));
+        assert!(recordings.find(first).is_none());
+        assert!(recordings.find(open.id).is_some());
+        assert!(recordings.find(last).is_some());
+
+        // Files that are not recordings are left alone.
+        fs::write(dir.path().join("notes.txt"), "notes").unwrap();
+        drop(start(uuid::Uuid::new_v4()));
+        assert!(dir.path().join("notes.txt").exists());
+    }
+}
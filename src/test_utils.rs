@@ -10,7 +10,7 @@ use log::debug;
 use std::env;
 use std::io::BufReader;
 
-use crate::endpoints::public::services::Claims;
+use crate::endpoints::auth::Claims;
 use std::process::Command;
 use std::process::Output;
 use std::sync::Arc;
@@ -58,12 +58,22 @@ pub fn log_generator_service() -> Service {
     Service::from(config)
 }
 
+pub const ALL_SCOPES: &[&str] = &[
+    "services:read",
+    "services:control",
+    "logs:read",
+    "sys:admin",
+    "terminal",
+    "recordings:read",
+];
+
 pub struct ServerTest {
     guard: tokio::task::JoinHandle<()>,
     pub client: reqwest::Client,
     addr: String,
     port: u16,
     services: Arc<crate::service::Services>,
+    events: crate::events::Events,
 }
 
 impl Drop for ServerTest {
@@ -77,6 +87,10 @@ impl ServerTest {
         self.services.clone()
     }
 
+    pub fn events(&self) -> crate::events::Events {
+        self.events.clone()
+    }
+
     pub fn url_for(&self, path: &str) -> String {
         format!("http://{}:{}{}", self.addr, self.port, path)
     }
@@ -97,23 +111,42 @@ impl ServerTest {
             .unwrap();
     }
 
+    /// Returns a token with every scope that expires in an hour.
     pub fn valid_token(&self) -> String {
+        self.token(Self::an_hour_from_now(), ALL_SCOPES, None)
+    }
+
+    pub fn invalid_token(&self) -> String {
+        self.token(0, ALL_SCOPES, None)
+    }
+
+    pub fn an_hour_from_now() -> u64 {
         let current_time_in_seconds = match SystemTime::now().duration_since(UNIX_EPOCH) {
             Ok(n) => n.as_secs(),
             Err(_) => panic!("SystemTime before UNIX EPOCH!"),
         };
-        self.token(current_time_in_seconds + 3600)
+        current_time_in_seconds + 3600
     }
 
-    pub fn invalid_token(&self) -> String {
-        self.token(0)
+    /// Returns a token with the given scopes, optionally restricted to the given services.
+    pub fn token(&self, expires_at: u64, scopes: &[&str], services: Option<Vec<String>>) -> String {
+        self.bearer_token("test", expires_at, scopes, services)
     }
 
-    fn token(&self, expires_at: u64) -> String {
+    /// Like `token`, for the given bearer.
+    pub fn bearer_token(
+        &self,
+        bearer_id: &str,
+        expires_at: u64,
+        scopes: &[&str],
+        services: Option<Vec<String>>,
+    ) -> String {
         let claims = Claims {
-            bearer_id: "test".to_string(),
+            bearer_id: bearer_id.to_string(),
             bearer_type: "test".to_string(),
             exp: expires_at,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            services,
         };
         encode(
             &Header::default(),
@@ -127,13 +160,14 @@ impl ServerTest {
         let agent = KittengridAgent::new(crate::config::get_config().clone());
 
         // In tests, we only set up the logger when the KITTENGRID_LOG_LEVEL is found
-        agent.init().await;
+        agent.init().await.unwrap();
 
         let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap().ip().to_string();
         let port = listener.local_addr().unwrap().port();
         let client = reqwest::Client::new();
         let services = agent.services();
+        let events = agent.events();
 
         if spawn_services {
             // We need to compile the log generator before we can spawn the services
@@ -155,6 +189,7 @@ impl ServerTest {
             addr,
             port,
             services,
+            events,
         }
     }
 }
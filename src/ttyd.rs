@@ -1,9 +1,34 @@
+use crate::config::TerminalConfig;
+use crate::kittengrid_api::{KittengridApi, ServiceStatus};
+use crate::process_controller::{HealthCheck, ProcessController, DEFAULT_STOP_GRACE_PERIOD};
+use crate::HealthStatus;
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocketUpgrade},
+        ConnectInfo, FromRequestParts, Request,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
 use regex::Regex;
-use std::env;
-use std::fs;
 use std::io::{BufRead, BufReader};
-use std::process::Command; // Needed for chmod on Unix
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+/// Time to wait before starting ttyd again after it exits, doubled every time it fails to
+/// start up to `MAX_RESTART_DELAY`.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
 
 #[derive(Error)]
 pub enum Error {
@@ -12,6 +37,9 @@ pub enum Error {
 
     #[error("IoError: {0}")]
     IoError(#[from] std::io::Error),
+
+    #[error("Binary error: {0}")]
+    BinaryError(#[from] crate::binary_utils::BinaryError),
 }
 
 // This is so we can use ? in main without having to unwrap the error
@@ -22,34 +50,29 @@ impl std::fmt::Debug for Error {
 }
 
 pub struct Executable {
-    bin_path: String,
-}
-
-impl Drop for Executable {
-    fn drop(&mut self) {
-        fs::remove_file(&self.bin_path).expect("Failed to delete temporary binary");
-    }
+    bin_path: PathBuf,
 }
 
-impl Default for Executable {
-    fn default() -> Self {
-        let bin_path = crate::binary_utils::install_binary(include_bytes!(env!("TTYD")));
-        Self { bin_path }
+impl Executable {
+    /// Installs the embedded ttyd binary (see `binary_utils::install_binary`).
+    pub fn install() -> Result<Self, Error> {
+        let bin_path = crate::binary_utils::install_binary(&crate::binary_utils::TTYD)?;
+        Ok(Self { bin_path })
     }
-}
 
-impl Executable {
-    /// Starts the ttyd server with the given base path.
-    pub async fn start(&self, base_path: &str) -> Result<u16, Error> {
-        let mut port: u16 = 0;
-        let mut child = Command::new(&self.bin_path)
-            .arg("-W")
-            .arg("-p")
-            .arg("0")
-            .arg("-b")
-            .arg(base_path)
-            .arg("bash")
-            .stderr(std::process::Stdio::piped())
+    /// Starts the ttyd server with the given base path, serving the shell of the `terminal`
+    /// configuration. It listens on `port`, or on a random one when it is 0.
+    ///
+    /// Returns the process and the port it listens on.
+    pub fn spawn(
+        &self,
+        base_path: &str,
+        port: u16,
+        config: &TerminalConfig,
+    ) -> Result<(Child, u16), Error> {
+        let mut child = self
+            .command(base_path, port, config)
+            .stderr(Stdio::piped())
             .spawn()?;
 
         // Get the stdout handle
@@ -58,40 +81,647 @@ impl Executable {
             None => return Err(Error::ExecError("Failed to get stderr".to_string())),
         };
 
-        let reader = BufReader::new(stderr);
+        let mut lines = BufReader::new(stderr).lines();
         let re = Regex::new(r".*istening on port:\s*(\d+)").unwrap();
 
-        for line in reader.lines() {
+        let mut port = None;
+        for line in lines.by_ref() {
             match line {
                 Ok(line) => {
                     if let Some(caps) = re.captures(&line) {
-                        port = caps[1].parse().unwrap_or(0);
+                        port = caps[1].parse().ok();
                         break;
                     }
                 }
                 Err(e) => {
-                    eprintln!("Error reading stderr: {}", e);
+                    error!("Error reading ttyd stderr: {}", e);
+                    let _ = child.kill();
+                    let _ = child.wait();
                     return Err(Error::IoError(e));
                 }
             }
         }
 
+        let port = match port {
+            Some(port) => port,
+            None => {
+                let _ = child.kill();
+                let status = child.wait()?;
+                return Err(Error::ExecError(format!(
+                    "ttyd exited before listening ({status})"
+                )));
+            }
+        };
+
+        // ttyd keeps logging (every connection), the pipe must be drained or it would block.
+        std::thread::spawn(move || {
+            for line in lines.map_while(Result::ok) {
+                debug!("ttyd: {}", line);
+            }
+        });
+
+        Ok((child, port))
+    }
+
+    fn command(&self, base_path: &str, port: u16, config: &TerminalConfig) -> Command {
+        let mut cmd = Command::new(&self.bin_path);
+        cmd.arg("-p").arg(port.to_string()).arg("-b").arg(base_path);
+        if config.writable() {
+            cmd.arg("-W");
+        }
+        if let Some(cwd) = config.cwd() {
+            cmd.arg("-w").arg(cwd);
+        }
+        // The credential is checked by the `Supervisor` in front of ttyd, it would be visible
+        // to every user on the command line. Idle sessions are closed by the front too.
+        if has_front(config) {
+            cmd.arg("-i").arg("lo");
+        }
+        // Only a fallback, bash and zsh exit after TMOUT seconds without input but other
+        // shells ignore it.
+        if let Some(idle_timeout) = config.idle_timeout {
+            cmd.env("TMOUT", idle_timeout.to_string());
+        }
+        cmd.envs(config.env.clone().unwrap_or_default())
+            .arg(config.shell())
+            .args(config.args.clone().unwrap_or_default());
+        cmd
+    }
+}
+
+/// Keeps ttyd running: it is monitored by a `ProcessController` and started again, on the
+/// same port so its registration stays valid, whenever it exits. The status of the
+/// registered service is kept up to date in the api.
+///
+/// With a `credential` or an `idle_timeout`, ttyd only listens on loopback and requests go
+/// through a front checking the basic authentication and closing idle sessions (see
+/// `serve_front`).
+#[derive(Debug)]
+pub struct Supervisor {
+    port: u16,
+    stopping: Arc<AtomicBool>,
+    controller: Arc<Mutex<Option<ProcessController>>>,
+    front: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Supervisor {
+    /// Starts ttyd under `base_path` (see `Executable::spawn`), `id` is the id it is
+    /// registered with in the api.
+    pub async fn start(
+        executable: Executable,
+        id: uuid::Uuid,
+        base_path: String,
+        config: TerminalConfig,
+        kittengrid_api: Option<KittengridApi>,
+    ) -> Result<Self, Error> {
+        let executable = Arc::new(executable);
+        let (child, port) = spawn(&executable, &base_path, 0, &config).await?;
+        info!("ttyd listening on port {}", port);
+        let front = if has_front(&config) {
+            let (front_port, front) =
+                serve_front(config.credential.as_deref(), config.idle_timeout(), port).await?;
+            info!("ttyd front listening on port {}", front_port);
+            Some((front_port, front))
+        } else {
+            None
+        };
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let (exits_tx, mut exits) = mpsc::unbounded_channel();
+        let controller = Arc::new(Mutex::new(Some(
+            controller(child, &exits_tx, &base_path, port, &kittengrid_api, id).await,
+        )));
+
+        // The process controller can't be replaced from its own callback (dropping it aborts
+        // the callback), so exits are handled here.
+        let supervisor_stopping = stopping.clone();
+        let supervisor_controller = controller.clone();
         tokio::spawn(async move {
-            let _ = child.wait();
+            while let Some(status) = exits.recv().await {
+                if supervisor_stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                warn!("ttyd exited ({}), restarting it.", status);
+                update_status(&kittengrid_api, id, ServiceStatus::Exited, status.code()).await;
+
+                let mut delay = RESTART_DELAY;
+                loop {
+                    tokio::time::sleep(delay).await;
+                    if supervisor_stopping.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let mut child = match spawn(&executable, &base_path, port, &config).await {
+                        Ok((child, _)) => child,
+                        Err(e) => {
+                            delay = (delay * 2).min(MAX_RESTART_DELAY);
+                            error!(
+                                "Failed to restart ttyd: {}, retrying in {}s.",
+                                e,
+                                delay.as_secs()
+                            );
+                            continue;
+                        }
+                    };
+
+                    // Checked with the lock held, so a concurrent stop can't miss the new process.
+                    let mut controller = supervisor_controller.lock().await;
+                    if supervisor_stopping.load(Ordering::SeqCst) {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return;
+                    }
+                    *controller = Some(
+                        self::controller(child, &exits_tx, &base_path, port, &kittengrid_api, id)
+                            .await,
+                    );
+                    break;
+                }
+                info!("ttyd restarted.");
+                update_status(&kittengrid_api, id, ServiceStatus::Running, None).await;
+            }
         });
 
-        Ok(port)
+        Ok(Self {
+            port: front.as_ref().map_or(port, |(front_port, _)| *front_port),
+            stopping,
+            controller,
+            front: front.map(|(_, front)| front),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Stops ttyd, it is not restarted anymore.
+    pub async fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Some(front) = &self.front {
+            front.abort();
+        }
+        if let Some(mut controller) = self.controller.lock().await.take() {
+            if let Err(e) = controller.stop().await {
+                error!("Failed to stop ttyd: {}.", e);
+            }
+        }
+    }
+}
+
+fn has_front(config: &TerminalConfig) -> bool {
+    config.credential.is_some() || config.idle_timeout.is_some()
+}
+
+/// Serves the requests for ttyd (listening on `ttyd_port`) on a random port, forwarding
+/// the ones with the `user:password` credential in their basic authentication (all of
+/// them without one). With an `idle_timeout`, the websockets of the sessions are relayed
+/// and closed once the client sent nothing for that long, which makes ttyd stop the shell.
+async fn serve_front(
+    credential: Option<&str>,
+    idle_timeout: Option<Duration>,
+    ttyd_port: u16,
+) -> Result<(u16, tokio::task::JoinHandle<()>), Error> {
+    let expected =
+        credential.map(|credential| format!("Basic {}", BASE64_STANDARD.encode(credential)));
+    let app = axum::Router::new().fallback(
+        move |ConnectInfo(client): ConnectInfo<SocketAddr>, request: Request| {
+            let authorized = expected.as_ref().is_none_or(|expected| {
+                request
+                    .headers()
+                    .get(header::AUTHORIZATION)
+                    .is_some_and(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()))
+            });
+            async move {
+                if !authorized {
+                    return unauthorized_response();
+                }
+                let websocket = request
+                    .headers()
+                    .get(header::UPGRADE)
+                    .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"websocket"));
+                if let Some(idle_timeout) = idle_timeout.filter(|_| websocket) {
+                    let (mut parts, _) = request.into_parts();
+                    return match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+                        Ok(ws) => relay_websocket(ws, &parts, ttyd_port, idle_timeout).await,
+                        Err(rejection) => rejection.into_response(),
+                    };
+                }
+                match crate::proxy::forward_to_port(ttyd_port, client, request, ()).await {
+                    Ok(response) => response,
+                    Err(e) => e.into_response(),
+                }
+            }
+        },
+    );
+
+    let listener = match tokio::net::TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)).await {
+        Ok(listener) => listener,
+        Err(_) => tokio::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+    };
+    let port = listener.local_addr()?.port();
+    let front = tokio::spawn(async move {
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("ttyd authentication stopped: {}.", e);
+        }
+    });
+    Ok((port, front))
+}
+
+/// Opens the websocket of the request to ttyd, then relays the frames until either side
+/// closes it, or the client sent no data for `idle_timeout`.
+async fn relay_websocket(
+    ws: WebSocketUpgrade,
+    parts: &axum::http::request::Parts,
+    ttyd_port: u16,
+    idle_timeout: Duration,
+) -> Response {
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    let mut request = match format!("ws://127.0.0.1:{ttyd_port}{path}").into_client_request() {
+        Ok(request) => request,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    // ttyd only accepts its own protocol.
+    if let Some(protocol) = parts.headers.get(header::SEC_WEBSOCKET_PROTOCOL) {
+        request
+            .headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+    }
+    let (upstream, response) = match tokio_tungstenite::connect_async(request).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            error!("Could not open ttyd websocket: {}.", e);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    let ws = match response
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(protocol) => ws.protocols([protocol.to_string()]),
+        None => ws,
+    };
+
+    ws.on_upgrade(move |mut client| async move {
+        let (mut upstream_tx, mut upstream_rx) = upstream.split();
+        let mut last_input = tokio::time::Instant::now();
+        let mut close = None;
+        loop {
+            tokio::select! {
+                message = client.recv() => {
+                    let message = match message {
+                        Some(Ok(Message::Text(text))) => tungstenite::Message::text(text.as_str()),
+                        Some(Ok(Message::Binary(data))) => tungstenite::Message::Binary(data),
+                        // Pings are answered by axum, they don't keep the session open.
+                        Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                        _ => break,
+                    };
+                    last_input = tokio::time::Instant::now();
+                    if upstream_tx.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                message = upstream_rx.next() => {
+                    let message = match message {
+                        Some(Ok(tungstenite::Message::Text(text))) => {
+                            Message::Text(text.as_str().into())
+                        }
+                        Some(Ok(tungstenite::Message::Binary(data))) => Message::Binary(data),
+                        Some(Ok(tungstenite::Message::Ping(_)))
+                        | Some(Ok(tungstenite::Message::Pong(_)))
+                        | Some(Ok(tungstenite::Message::Frame(_))) => continue,
+                        _ => break,
+                    };
+                    if client.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep_until(last_input + idle_timeout) => {
+                    info!("Closing idle ttyd session.");
+                    close = Some(CloseFrame {
+                        code: close_code::NORMAL,
+                        reason: "Idle timeout".into(),
+                    });
+                    break;
+                }
+            }
+        }
+        // Either side may be closed already.
+        let _ = client.send(Message::Close(close)).await;
+        let _ = upstream_tx.send(tungstenite::Message::Close(None)).await;
+    })
+}
+
+fn unauthorized_response() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"ttyd\"")],
+    )
+        .into_response()
+}
+
+// Compares the whole values, so the time taken doesn't tell how much of them matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Runs `Executable::spawn` out of the runtime, it blocks until ttyd listens.
+async fn spawn(
+    executable: &Arc<Executable>,
+    base_path: &str,
+    port: u16,
+    config: &TerminalConfig,
+) -> Result<(Child, u16), Error> {
+    let executable = executable.clone();
+    let base_path = base_path.to_string();
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || executable.spawn(&base_path, port, &config))
+        .await
+        .map_err(|e| Error::ExecError(e.to_string()))?
+}
+
+/// Monitors ttyd, its exits are sent to `exits` and its health (the same check the api
+/// does) is reported to the api.
+async fn controller(
+    child: Child,
+    exits: &mpsc::UnboundedSender<ExitStatus>,
+    base_path: &str,
+    port: u16,
+    kittengrid_api: &Option<KittengridApi>,
+    id: uuid::Uuid,
+) -> ProcessController {
+    let exits = exits.clone();
+    let on_stop = move |status: ExitStatus| {
+        let exits = exits.clone();
+        Box::pin(async move {
+            let _ = exits.send(status);
+        }) as std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>
+    };
+
+    let kittengrid_api = kittengrid_api.clone();
+    let on_health_changed = move |health: HealthStatus| {
+        let kittengrid_api = kittengrid_api.clone();
+        Box::pin(async move {
+            if let Some(api) = kittengrid_api {
+                if let Err(e) = api
+                    .services_update_status(id, None, Some(health), None)
+                    .await
+                {
+                    error!("Failed to update ttyd health status: {}.", e);
+                }
+            }
+        }) as std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>
+    };
+
+    let health_check = HealthCheck {
+        interval: 10,
+        timeout: 5,
+        retries: 3,
+        path: format!("{}/token", base_path.trim_start_matches('/')),
+        port,
+    };
+
+    ProcessController::new(
+        child,
+        Arc::new(on_stop),
+        Some(health_check),
+        Some(Arc::new(on_health_changed)),
+        DEFAULT_STOP_GRACE_PERIOD,
+    )
+    .await
+}
+
+async fn update_status(
+    kittengrid_api: &Option<KittengridApi>,
+    id: uuid::Uuid,
+    status: ServiceStatus,
+    exit_code: Option<i32>,
+) {
+    if let Some(api) = kittengrid_api {
+        if let Err(e) = api
+            .services_update_status(id, Some(status), None, exit_code)
+            .await
+        {
+            error!("Failed to update ttyd status: {}.", e);
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt; // Needed for chmod on Unix
 
     #[tokio::test]
     async fn test_ttyd_start() {
-        let ttyd = Executable::default();
-        let port = ttyd.start("/test").await.unwrap();
+        let ttyd = Executable::install().unwrap();
+        let (mut child, port) = ttyd.spawn("/test", 0, &TerminalConfig::default()).unwrap();
         assert!(port > 0, "TTYD should start on a valid port");
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn command() {
+        let ttyd = Executable {
+            bin_path: "/nonexistent/ttyd".into(),
+        };
+        let config = TerminalConfig {
+            command: Some("/bin/zsh".to_string()),
+            args: Some(vec!["--login".to_string()]),
+            writable: Some(false),
+            cwd: Some("/srv".to_string()),
+            env: Some([("FOO".to_string(), "bar".to_string())].into()),
+            idle_timeout: Some(600),
+            credential: Some("user:password".to_string()),
+            ..Default::default()
+        };
+        let cmd = ttyd.command("/base", 7681, &config);
+        let args: Vec<_> = cmd.get_args().map(|arg| arg.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            ["-p", "7681", "-b", "/base", "-w", "/srv", "-i", "lo", "/bin/zsh", "--login"]
+        );
+        let envs: Vec<_> = cmd
+            .get_envs()
+            .map(|(key, value)| (key.to_str().unwrap(), value.unwrap().to_str().unwrap()))
+            .collect();
+        assert!(envs.contains(&("TMOUT", "600")));
+        assert!(envs.contains(&("FOO", "bar")));
+
+        // Writable by default.
+        let cmd = ttyd.command("/base", 0, &TerminalConfig::default());
+        assert!(cmd.get_args().any(|arg| arg == "-W"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("starts");
+        let bin_path = dir.path().join("ttyd");
+        // Exits shortly after listening, logging the arguments of every start.
+        fs::write(
+            &bin_path,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}\necho 'Listening on port: 7681' >&2\nsleep 1\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&bin_path, fs::Permissions::from_mode(0o755)).unwrap();
+        let executable = Executable { bin_path };
+
+        let supervisor = Supervisor::start(
+            executable,
+            uuid::Uuid::new_v4(),
+            "/test".to_string(),
+            TerminalConfig::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(supervisor.port(), 7681);
+
+        let starts = || {
+            fs::read_to_string(&log)
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(20);
+        while starts().len() < 2 {
+            assert!(tokio::time::Instant::now() < deadline, "ttyd not restarted");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        // Started on a random port, restarted on the one it got.
+        let starts = starts();
+        assert!(starts[0].starts_with("-p 0 "));
+        assert!(starts[1].starts_with("-p 7681 "));
+
+        // Not restarted once stopped.
+        supervisor.stop().await;
+        let count = fs::read_to_string(&log).unwrap().lines().count();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(fs::read_to_string(&log).unwrap().lines().count(), count);
+    }
+
+    #[tokio::test]
+    async fn auth_front() {
+        let app = axum::Router::new().route("/base/token", axum::routing::get(|| async { "ok" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ttyd_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let (port, front) = serve_front(Some("user:password"), None, ttyd_port)
+            .await
+            .unwrap();
+        let client = reqwest::Client::new();
+        let url = format!("http://127.0.0.1:{port}/base/token");
+        for credential in [None, Some("user:wrong"), Some("user:password")] {
+            let mut request = client.get(&url);
+            if let Some(credential) = credential {
+                let (user, password) = credential.split_once(':').unwrap();
+                request = request.basic_auth(user, Some(password));
+            }
+            let response = request.send().await.unwrap();
+            match credential {
+                Some("user:password") => {
+                    assert_eq!(response.status(), StatusCode::OK);
+                    assert_eq!(response.text().await.unwrap(), "ok");
+                }
+                _ => {
+                    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+                    assert!(response.headers().contains_key("www-authenticate"));
+                }
+            }
+        }
+        front.abort();
+    }
+
+    #[tokio::test]
+    async fn front_closes_idle_sessions() {
+        // Echoes like ttyd, and tells when its websocket is closed (ttyd then stops the shell).
+        let (closed_tx, closed) = tokio::sync::oneshot::channel();
+        let closed_tx = Arc::new(std::sync::Mutex::new(Some(closed_tx)));
+        let app = axum::Router::new().route(
+            "/base/ws",
+            axum::routing::get(move |ws: WebSocketUpgrade| async move {
+                ws.protocols(["tty"])
+                    .on_upgrade(move |mut socket| async move {
+                        while let Some(Ok(message)) = socket.recv().await {
+                            if matches!(message, Message::Close(_))
+                                || socket.send(message).await.is_err()
+                            {
+                                break;
+                            }
+                        }
+                        if let Some(closed_tx) = closed_tx.lock().unwrap().take() {
+                            let _ = closed_tx.send(());
+                        }
+                    })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ttyd_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let idle_timeout = Duration::from_millis(500);
+        let (port, front) = serve_front(None, Some(idle_timeout), ttyd_port)
+            .await
+            .unwrap();
+        let mut request = format!("ws://127.0.0.1:{port}/base/ws")
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, "tty".parse().unwrap());
+        let (mut socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(response.headers()[header::SEC_WEBSOCKET_PROTOCOL], "tty");
+
+        // Kept open while there is input, pings don't count.
+        let started = tokio::time::Instant::now();
+        for input in ["l", "s", "\r"] {
+            tokio::time::sleep(idle_timeout / 2).await;
+            socket
+                .send(tungstenite::Message::text(input))
+                .await
+                .unwrap();
+            socket
+                .send(tungstenite::Message::Ping(Default::default()))
+                .await
+                .unwrap();
+            let echo = loop {
+                match socket.next().await.unwrap().unwrap() {
+                    tungstenite::Message::Pong(_) => continue,
+                    message => break message,
+                }
+            };
+            assert_eq!(echo, tungstenite::Message::text(input));
+        }
+        assert!(started.elapsed() > idle_timeout);
+
+        loop {
+            socket
+                .send(tungstenite::Message::Ping(Default::default()))
+                .await
+                .ok();
+            match socket.next().await {
+                Some(Ok(tungstenite::Message::Pong(_))) => {
+                    tokio::time::sleep(Duration::from_millis(100)).await
+                }
+                Some(Ok(tungstenite::Message::Close(frame))) => {
+                    assert_eq!(frame.unwrap().reason, "Idle timeout");
+                    break;
+                }
+                message => panic!("Unexpected message {message:?}"),
+            }
+        }
+        tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .expect("ttyd websocket not closed")
+            .unwrap();
+        front.abort();
     }
 }
@@ -0,0 +1,184 @@
+use crate::kittengrid_api::{Endpoint, Peer};
+use crate::wireguard::{InterfaceSettings, WireGuard, WireguardError};
+use defguard_wireguard_rs::{Kernel, Userspace};
+use log::info;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+pub mod monitor;
+
+/// Connects the agent with a kittengrid peer, so its services can be reached from it.
+pub trait Tunnel: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> String;
+
+    /// Configures the tunnel to the peer through its endpoint.
+    fn set_config(&self, peer: &Peer, endpoint: &Endpoint) -> Result<(), WireguardError>;
+
+    /// Points the configured tunnel to a new endpoint of the peer.
+    fn update_endpoint(&self, peer: &Peer, endpoint: &Endpoint) -> Result<(), WireguardError>;
+
+    /// Returns the traffic stats of the tunnel, None if the backend has none.
+    fn stats(&self) -> Result<Option<TunnelStats>, WireguardError>;
+
+    /// Removes the tunnel.
+    fn teardown(&self) -> Result<(), WireguardError>;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunnelStats {
+    /// Time of the last handshake with the peer, None if there was none yet.
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// State of a tunnel, as seen by the `TunnelMonitor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelState {
+    /// Configured (or re-established) recently, waiting for the first handshake.
+    Connecting,
+    /// Handshakes are happening.
+    Up,
+    /// No handshakes for a while, it is re-established.
+    Stale,
+}
+
+impl fmt::Display for TunnelState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TunnelState::Connecting => write!(f, "connecting"),
+            TunnelState::Up => write!(f, "up"),
+            TunnelState::Stale => write!(f, "stale"),
+        }
+    }
+}
+
+/// How tunnels are implemented, selected with the `tunnel` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelBackend {
+    /// WireGuard kernel module, needs `CAP_NET_ADMIN`.
+    Kernel,
+    /// WireGuard implemented in the agent (BoringTun) over a TUN device, for hosts
+    /// without the kernel module.
+    Userspace,
+    /// No tunnels at all, for local development.
+    None,
+}
+
+impl FromStr for TunnelBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kernel" => Ok(Self::Kernel),
+            "userspace" => Ok(Self::Userspace),
+            "none" => Ok(Self::None),
+            other => Err(format!(
+                "Unknown tunnel backend '{}' (kernel, userspace or none)",
+                other
+            )),
+        }
+    }
+}
+
+impl TunnelBackend {
+    /// Removes the tunnels left behind by a previous run of the agent (e.g. if it crashed),
+    /// the ones with the interface `prefix` recorded in the data dir.
+    pub fn remove_stale(&self, prefix: &str) {
+        match self {
+            Self::Kernel | Self::Userspace => {
+                let owned = crate::wireguard::OwnedInterfaces::from_data_dir();
+                let removed = crate::wireguard::remove_stale_interfaces(prefix, &owned);
+                if !removed.is_empty() {
+                    info!("Removed stale tunnels: {}.", removed.join(", "));
+                }
+            }
+            Self::None => {}
+        }
+    }
+
+    /// Checks that the tunnels can be created (see `wireguard::check_conflicts`).
+    pub fn check_conflicts(&self, settings: &[InterfaceSettings]) -> Result<(), WireguardError> {
+        match self {
+            Self::Kernel | Self::Userspace => crate::wireguard::check_conflicts(settings),
+            Self::None => Ok(()),
+        }
+    }
+
+    /// Creates the `index`th tunnel of the agent.
+    pub fn create(
+        &self,
+        index: usize,
+        settings: &InterfaceSettings,
+    ) -> Result<Box<dyn Tunnel>, WireguardError> {
+        Ok(match self {
+            Self::Kernel => Box::new(WireGuard::<Kernel>::new(settings)?),
+            Self::Userspace => Box::new(WireGuard::<Userspace>::new(settings)?),
+            Self::None => Box::new(NoTunnel { index }),
+        })
+    }
+}
+
+/// Tunnel of the `none` backend, it does nothing: services are only reachable locally.
+#[derive(Debug)]
+pub struct NoTunnel {
+    index: usize,
+}
+
+impl Tunnel for NoTunnel {
+    fn name(&self) -> String {
+        format!("none{}", self.index)
+    }
+
+    fn set_config(&self, peer: &Peer, _endpoint: &Endpoint) -> Result<(), WireguardError> {
+        info!(
+            "Tunnels are disabled, not connecting to network {}.",
+            peer.network()
+        );
+        Ok(())
+    }
+
+    fn update_endpoint(&self, _peer: &Peer, _endpoint: &Endpoint) -> Result<(), WireguardError> {
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<Option<TunnelStats>, WireguardError> {
+        Ok(None)
+    }
+
+    fn teardown(&self) -> Result<(), WireguardError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backends() {
+        assert_eq!("kernel".parse(), Ok(TunnelBackend::Kernel));
+        assert_eq!("userspace".parse(), Ok(TunnelBackend::Userspace));
+        assert_eq!("none".parse(), Ok(TunnelBackend::None));
+        assert!("boringtun".parse::<TunnelBackend>().is_err());
+
+        let settings = InterfaceSettings {
+            name: "kgwg1".to_string(),
+            listen_port: 51821,
+            mtu: 1384,
+            persistent_keepalive: None,
+            fwmark: None,
+        };
+        // Nothing is checked
+        TunnelBackend::None
+            .check_conflicts(&[settings.clone(), settings.clone()])
+            .unwrap();
+        let tunnel = TunnelBackend::None.create(1, &settings).unwrap();
+        assert_eq!(tunnel.name(), "none1");
+        assert_eq!(tunnel.stats().unwrap(), None);
+        tunnel.teardown().unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use super::{Tunnel, TunnelState, TunnelStats};
+use crate::events::{Event, Events};
+use crate::kittengrid_api::{KittengridApi, Peer};
+use crate::shutdown::Shutdown;
+use log::{debug, error, info, warn};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// WireGuard handshakes every 2 minutes while a tunnel is up, tunnels without one for
+/// longer than this are stale.
+const STALE_AFTER: Duration = Duration::from_secs(180);
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Minimum time between two attempts to re-establish a stale tunnel.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Watches the tunnels of the agent. Stale tunnels (without recent handshakes) are pointed
+/// to the endpoint the api returns for their network, which may have changed, and state
+/// changes are reported to the api and sent to the event stream.
+pub struct TunnelMonitor {
+    tunnels: Vec<MonitoredTunnel>,
+    kittengrid_api: Option<KittengridApi>,
+    events: Events,
+    check_interval: Duration,
+    stale_after: Duration,
+    reconnect_interval: Duration,
+}
+
+struct MonitoredTunnel {
+    tunnel: Arc<dyn Tunnel>,
+    peer: Peer,
+    state: Option<TunnelState>,
+    // When it was configured, or last re-established.
+    since: SystemTime,
+    last_reconnect: Option<SystemTime>,
+}
+
+impl TunnelMonitor {
+    pub fn new(
+        tunnels: Vec<(Arc<dyn Tunnel>, Peer)>,
+        kittengrid_api: Option<KittengridApi>,
+        events: Events,
+    ) -> Self {
+        let now = SystemTime::now();
+        Self {
+            tunnels: tunnels
+                .into_iter()
+                .map(|(tunnel, peer)| MonitoredTunnel {
+                    tunnel,
+                    peer,
+                    state: None,
+                    since: now,
+                    last_reconnect: None,
+                })
+                .collect(),
+            kittengrid_api,
+            events,
+            check_interval: CHECK_INTERVAL,
+            stale_after: STALE_AFTER,
+            reconnect_interval: RECONNECT_INTERVAL,
+        }
+    }
+
+    /// Monitors the tunnels until the shutdown is triggered.
+    pub fn spawn(mut self, shutdown: Shutdown) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                self.check().await;
+                tokio::select! {
+                    _ = tokio::time::sleep(self.check_interval) => {}
+                    _ = shutdown.triggered() => break,
+                }
+            }
+            debug!("Tunnel monitor stopped.");
+        })
+    }
+
+    async fn check(&mut self) {
+        let now = SystemTime::now();
+        for monitored in self.tunnels.iter_mut() {
+            let stats = match monitored.tunnel.stats() {
+                Ok(Some(stats)) => stats,
+                // Nothing to monitor
+                Ok(None) => continue,
+                Err(e) => {
+                    error!(
+                        "Could not read the stats of tunnel {}: {}",
+                        monitored.tunnel.name(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            debug!("Tunnel {} stats: {:?}", monitored.tunnel.name(), stats);
+
+            let state = tunnel_state(&stats, monitored.since, now, self.stale_after);
+            if monitored.state != Some(state) {
+                monitored.state = Some(state);
+                report(&self.kittengrid_api, &self.events, monitored, &stats).await;
+            }
+
+            let reconnect_due = monitored.last_reconnect.is_none_or(|last| {
+                now.duration_since(last).unwrap_or_default() >= self.reconnect_interval
+            });
+            if state == TunnelState::Stale && reconnect_due {
+                monitored.last_reconnect = Some(now);
+                if reconnect(&self.kittengrid_api, monitored).await {
+                    monitored.since = now;
+                }
+            }
+        }
+    }
+}
+
+/// State of a tunnel given its stats, `since` is when it was configured.
+fn tunnel_state(
+    stats: &TunnelStats,
+    since: SystemTime,
+    now: SystemTime,
+    stale_after: Duration,
+) -> TunnelState {
+    let elapsed = |time: SystemTime| now.duration_since(time).unwrap_or_default();
+    match stats.last_handshake {
+        Some(handshake) if elapsed(handshake) < stale_after => TunnelState::Up,
+        _ if elapsed(since) < stale_after => TunnelState::Connecting,
+        _ => TunnelState::Stale,
+    }
+}
+
+async fn report(
+    kittengrid_api: &Option<KittengridApi>,
+    events: &Events,
+    monitored: &MonitoredTunnel,
+    stats: &TunnelStats,
+) {
+    let name = monitored.tunnel.name();
+    let state = monitored.state.unwrap_or(TunnelState::Connecting);
+    match state {
+        TunnelState::Stale => warn!("Tunnel {} is stale.", name),
+        _ => info!("Tunnel {} is {}.", name, state),
+    }
+
+    events.send(Event::TunnelStateChanged {
+        tunnel: name.clone(),
+        network: monitored.peer.network(),
+        state,
+    });
+
+    if let Some(api) = kittengrid_api {
+        if let Err(e) = api
+            .peers_update_status(monitored.peer.network(), state, stats)
+            .await
+        {
+            error!("Failed to report the state of tunnel {}: {}.", name, e);
+        }
+    }
+}
+
+/// Fetches the endpoint of the network again and points the tunnel to it.
+async fn reconnect(kittengrid_api: &Option<KittengridApi>, monitored: &MonitoredTunnel) -> bool {
+    let name = monitored.tunnel.name();
+    let api = match kittengrid_api {
+        Some(api) => api,
+        None => {
+            warn!("Can't re-establish tunnel {}, agent not registered.", name);
+            return false;
+        }
+    };
+
+    info!("Re-establishing tunnel {}.", name);
+    let endpoint = match api.peers_get_endpoint(monitored.peer.network()).await {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Failed to fetch the endpoint of tunnel {}: {}.", name, e);
+            return false;
+        }
+    };
+    match monitored.tunnel.update_endpoint(&monitored.peer, &endpoint) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to update the endpoint of tunnel {}: {}.", name, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::kittengrid_api::Endpoint;
+    use crate::wireguard::WireguardError;
+    use std::sync::Mutex;
+
+    #[derive(Debug)]
+    struct FakeTunnel {
+        stats: Mutex<TunnelStats>,
+    }
+
+    impl Tunnel for FakeTunnel {
+        fn name(&self) -> String {
+            "fake0".to_string()
+        }
+
+        fn set_config(&self, _peer: &Peer, _endpoint: &Endpoint) -> Result<(), WireguardError> {
+            Ok(())
+        }
+
+        fn update_endpoint(
+            &self,
+            _peer: &Peer,
+            _endpoint: &Endpoint,
+        ) -> Result<(), WireguardError> {
+            Ok(())
+        }
+
+        fn stats(&self) -> Result<Option<TunnelStats>, WireguardError> {
+            Ok(Some(self.stats.lock().unwrap().clone()))
+        }
+
+        fn teardown(&self) -> Result<(), WireguardError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn state() {
+        let now = SystemTime::now();
+        let stale_after = Duration::from_secs(180);
+        let ago = |secs| now - Duration::from_secs(secs);
+        let stats = |handshake| TunnelStats {
+            last_handshake: handshake,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            tunnel_state(&stats(None), ago(10), now, stale_after),
+            TunnelState::Connecting
+        );
+        assert_eq!(
+            tunnel_state(&stats(None), ago(200), now, stale_after),
+            TunnelState::Stale
+        );
+        assert_eq!(
+            tunnel_state(&stats(Some(ago(5))), ago(200), now, stale_after),
+            TunnelState::Up
+        );
+        assert_eq!(
+            tunnel_state(&stats(Some(ago(190))), ago(200), now, stale_after),
+            TunnelState::Stale
+        );
+        // Recently re-established
+        assert_eq!(
+            tunnel_state(&stats(Some(ago(190))), ago(10), now, stale_after),
+            TunnelState::Connecting
+        );
+    }
+
+    #[tokio::test]
+    async fn state_changes() {
+        let tunnel = Arc::new(FakeTunnel {
+            stats: Mutex::new(TunnelStats {
+                last_handshake: Some(SystemTime::now()),
+                ..Default::default()
+            }),
+        });
+        let peer: Peer = serde_json::from_value(serde_json::json!({
+            "address": "10.0.0.2",
+            "public_key": "",
+            "private_key": "",
+            "network": "10.0.0.0/24",
+        }))
+        .unwrap();
+        let events = Events::new();
+        let mut receiver = events.subscribe();
+        let mut monitor = TunnelMonitor::new(vec![(tunnel.clone(), peer)], None, events);
+        monitor.check_interval = Duration::from_millis(50);
+        monitor.stale_after = Duration::from_secs(60);
+        monitor.tunnels[0].since = SystemTime::now() - Duration::from_secs(600);
+        let shutdown = Shutdown::new();
+        let handle = monitor.spawn(shutdown.clone());
+
+        let state_change = |state| Event::TunnelStateChanged {
+            tunnel: "fake0".to_string(),
+            network: "10.0.0.0/24".to_string(),
+            state,
+        };
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            state_change(TunnelState::Up)
+        );
+
+        // Handshakes stop
+        tunnel.stats.lock().unwrap().last_handshake =
+            Some(SystemTime::now() - Duration::from_secs(120));
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            state_change(TunnelState::Stale)
+        );
+
+        shutdown.trigger("test");
+        handle.await.unwrap();
+    }
+}
@@ -1,90 +1,828 @@
+use crate::config::NetworkConfig;
 use crate::kittengrid_api::{Endpoint, Peer};
-use base64::{engine::general_purpose, Engine as _};
+use crate::tunnel::{Tunnel, TunnelStats};
+use log::{debug, info, warn};
 use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{net::SocketAddr, str::FromStr};
+use thiserror::Error;
 
 use defguard_wireguard_rs::{
-    key::Key, net::IpAddrMask, peer::Peer as WgPeer, InterfaceConfiguration, Kernel, WGApi,
-    WireguardInterfaceApi,
+    error::WireguardInterfaceError, key::Key, net::IpAddrMask, peer::Peer as WgPeer,
+    InterfaceConfiguration, Kernel, WGApi, WireguardInterfaceApi,
 };
-use x25519_dalek::PublicKey;
 
-const PORT_BASE: u16 = 51820;
-const MTU: u32 = 1384;
+pub mod keys;
 
-pub struct WireGuard {
-    wgapi: WGApi,
+// Longest interface name accepted by Linux (IFNAMSIZ - 1).
+const MAX_INTERFACE_NAME_LEN: usize = 15;
+const NET_DEVICES_PATH: &str = "/sys/class/net";
+// Where BoringTun creates the control socket of userspace interfaces.
+const USERSPACE_SOCKETS_PATH: &str = "/var/run/wireguard";
+// Prefixes of the interfaces of other tools (e.g. wg-quick names them `wg<n>`).
+const RESERVED_INTERFACE_PREFIXES: &[&str] = &["wg", "tun", "tap", "utun", "eth", "lo"];
+
+#[derive(Debug, Error)]
+pub enum WireguardError {
+    #[error("Invalid {0} key: {1}")]
+    InvalidKey(&'static str, String),
+
+    #[error("Key file {0}: {1}")]
+    KeyFile(std::path::PathBuf, std::io::Error),
+
+    #[error("Invalid {0} address: {1}")]
+    InvalidAddress(&'static str, String),
+
+    #[error("Can't resolve endpoint {0}: {1}")]
+    UnresolvableEndpoint(String, String),
+
+    #[error("Failed to create interface {0}: {1}")]
+    InterfaceCreate(String, WireguardInterfaceError),
+
+    #[error("Permission denied on interface {0}, the agent needs CAP_NET_ADMIN: {1}")]
+    PermissionDenied(String, WireguardInterfaceError),
+
+    #[error("Address conflict on interface {0}: {1}")]
+    AddressConflict(String, WireguardInterfaceError),
+
+    #[error("Interface {0} error: {1}")]
+    Interface(String, WireguardInterfaceError),
+
+    #[error("Invalid interface name {0}: {1}")]
+    InvalidInterfaceName(String, String),
+
+    #[error("Interface {0} already exists")]
+    NameConflict(String),
+
+    #[error("No listen port left for interface {0}")]
+    NoListenPort(String),
+
+    #[error("Listen port {0} of interface {1} is already in use")]
+    PortConflict(u16, String),
+}
+
+impl WireguardError {
+    /// Classifies an error of the interface API. defguard only has the text of netlink
+    /// errors, so permission and address problems are told apart by it.
+    fn interface(interface_name: &str, error: WireguardInterfaceError) -> Self {
+        let name = interface_name.to_string();
+        let permission_denied = match &error {
+            WireguardInterfaceError::IoError(e) => e.kind() == std::io::ErrorKind::PermissionDenied,
+            _ => false,
+        };
+        let message = error.to_string();
+        if permission_denied
+            || message.contains("Operation not permitted")
+            || message.contains("Permission denied")
+        {
+            Self::PermissionDenied(name, error)
+        } else if message.contains("File exists") || message.contains("Address in use") {
+            Self::AddressConflict(name, error)
+        } else {
+            Self::Interface(name, error)
+        }
+    }
+}
+
+/// Parameters of an interface, from the `network` config and the overrides of its peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceSettings {
+    pub name: String,
+    pub listen_port: u16,
+    pub mtu: u32,
+    /// None disables keepalive packets.
+    pub persistent_keepalive: Option<u16>,
+    pub fwmark: Option<u32>,
+}
+
+impl InterfaceSettings {
+    /// Settings of the `index`th interface of the agent.
+    pub fn new(config: &NetworkConfig, peer: &Peer, index: usize) -> Result<Self, WireguardError> {
+        let prefix = config.interface_prefix();
+        let name = format!("{}{}", prefix, index);
+        if RESERVED_INTERFACE_PREFIXES.contains(&prefix.to_lowercase().as_str()) {
+            return Err(WireguardError::InvalidInterfaceName(
+                name,
+                format!("the '{}' prefix is used by other interfaces", prefix),
+            ));
+        }
+        if name.len() > MAX_INTERFACE_NAME_LEN {
+            return Err(WireguardError::InvalidInterfaceName(
+                name,
+                format!("longer than {} characters", MAX_INTERFACE_NAME_LEN),
+            ));
+        }
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(WireguardError::InvalidInterfaceName(
+                name,
+                "only letters, digits, '-' and '_' are allowed".to_string(),
+            ));
+        }
+
+        let listen_port = match peer.listen_port() {
+            Some(port) => port,
+            None => u16::try_from(index)
+                .ok()
+                .and_then(|index| config.listen_port().checked_add(index))
+                .ok_or_else(|| WireguardError::NoListenPort(name.clone()))?,
+        };
+        let persistent_keepalive = peer
+            .persistent_keepalive()
+            .unwrap_or(config.persistent_keepalive());
+
+        Ok(Self {
+            name,
+            listen_port,
+            mtu: peer.mtu().unwrap_or(config.mtu()),
+            persistent_keepalive: (persistent_keepalive > 0).then_some(persistent_keepalive),
+            fwmark: peer.fwmark().or(config.fwmark),
+        })
+    }
+}
+
+/// Fails if two interfaces share a name or a listen port, or if they are already taken on
+/// the host (e.g. by another WireGuard). Meant to be called once the stale interfaces of
+/// the agent are removed and before creating the new ones.
+pub fn check_conflicts(settings: &[InterfaceSettings]) -> Result<(), WireguardError> {
+    check_conflicts_in(settings, Path::new(NET_DEVICES_PATH))
+}
+
+fn check_conflicts_in(
+    settings: &[InterfaceSettings],
+    net_devices: &Path,
+) -> Result<(), WireguardError> {
+    for (i, interface) in settings.iter().enumerate() {
+        let previous = &settings[..i];
+        if previous.iter().any(|other| other.name == interface.name)
+            || net_devices.join(&interface.name).exists()
+        {
+            return Err(WireguardError::NameConflict(interface.name.clone()));
+        }
+        let port_conflict =
+            || WireguardError::PortConflict(interface.listen_port, interface.name.clone());
+        if previous
+            .iter()
+            .any(|other| other.listen_port == interface.listen_port)
+        {
+            return Err(port_conflict());
+        }
+        // WireGuard listens on every address.
+        if std::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, interface.listen_port))
+            .is_err()
+        {
+            return Err(port_conflict());
+        }
+    }
+    Ok(())
+}
+
+/// WireGuard tunnel, `API` is either `Kernel` (kernel module) or `Userspace` (BoringTun).
+pub struct WireGuard<API = Kernel> {
+    wgapi: WGApi<API>,
     interface_name: String,
-    index: usize,
+    settings: InterfaceSettings,
+    // Endpoint of the peer, its routing is removed on teardown.
+    endpoint: Mutex<Option<SocketAddr>>,
+    owned: OwnedInterfaces,
 }
 
-impl WireGuard {
-    pub fn name(&self) -> String {
-        format!("wg{}", self.index).to_string()
+impl<API> std::fmt::Debug for WireGuard<API> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WireGuard({})", self.interface_name)
     }
+}
+
+impl<API> WireGuard<API>
+where
+    WGApi<API>: WireguardInterfaceApi,
+{
+    pub fn new(settings: &InterfaceSettings) -> Result<WireGuard<API>, WireguardError> {
+        let interface_name = settings.name.clone();
 
-    pub async fn new(index: usize) -> Result<WireGuard, Box<dyn std::error::Error>> {
-        let interface_name = format!("wg{}", index);
+        let create = |error| match WireguardError::interface(&interface_name, error) {
+            WireguardError::Interface(name, error) => WireguardError::InterfaceCreate(name, error),
+            other => other,
+        };
+        let mut wgapi = WGApi::<API>::new(interface_name.clone()).map_err(create)?;
 
-        let mut wgapi = WGApi::<Kernel>::new(interface_name.clone())?;
+        // Recorded first, so the interface is removed on the next start even if the agent
+        // dies while it is being created.
+        let owned = OwnedInterfaces::from_data_dir();
+        owned.add(&interface_name);
 
         // create interface
-        wgapi.create_interface()?;
+        wgapi.create_interface().map_err(create)?;
 
         Ok(WireGuard {
             wgapi,
-            index,
             interface_name,
+            settings: settings.clone(),
+            endpoint: Mutex::new(None),
+            owned,
         })
     }
 
-    pub async fn set_config(
+    fn interface_error(&self, error: WireguardInterfaceError) -> WireguardError {
+        WireguardError::interface(&self.interface_name, error)
+    }
+}
+
+impl<API> Tunnel for WireGuard<API>
+where
+    WGApi<API>: WireguardInterfaceApi + Send + Sync,
+{
+    fn name(&self) -> String {
+        self.interface_name.clone()
+    }
+
+    fn set_config(&self, peer_config: &Peer, endpoint: &Endpoint) -> Result<(), WireguardError> {
+        let interface_config = interface_config(&self.settings, peer_config, endpoint)?;
+        *self.endpoint.lock().unwrap() = interface_config.peers[0].endpoint;
+
+        self.wgapi
+            .configure_interface(&interface_config)
+            .map_err(|e| self.interface_error(e))?;
+        self.wgapi
+            .configure_peer_routing(&interface_config.peers)
+            .map_err(|e| self.interface_error(e))?;
+
+        Ok(())
+    }
+
+    /// Only the peer is updated, the interface keeps its addresses and routes. Peers with
+    /// another key (the endpoint key changed) are removed.
+    fn update_endpoint(
         &self,
         peer_config: &Peer,
         endpoint: &Endpoint,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let pubkey_bytes = general_purpose::STANDARD
-            .decode(endpoint.public_key())
-            .unwrap();
-        let bytes: [u8; 32] = pubkey_bytes.as_slice().try_into().unwrap();
+    ) -> Result<(), WireguardError> {
+        let peer = wg_peer(&self.settings, peer_config, endpoint)?;
+        let host = self
+            .wgapi
+            .read_interface_data()
+            .map_err(|e| self.interface_error(e))?;
+        for key in host.peers.keys().filter(|key| **key != peer.public_key) {
+            self.wgapi
+                .remove_peer(key)
+                .map_err(|e| self.interface_error(e))?;
+        }
+        self.wgapi
+            .configure_peer(&peer)
+            .map_err(|e| self.interface_error(e))?;
+        *self.endpoint.lock().unwrap() = peer.endpoint;
+        Ok(())
+    }
 
-        // Peer configuration
-        let key = PublicKey::from(bytes);
+    fn stats(&self) -> Result<Option<TunnelStats>, WireguardError> {
+        let host = self
+            .wgapi
+            .read_interface_data()
+            .map_err(|e| self.interface_error(e))?;
+        // There is a single peer per interface.
+        let stats = host
+            .peers
+            .values()
+            .next()
+            .map(|peer| TunnelStats {
+                last_handshake: peer.last_handshake,
+                rx_bytes: peer.rx_bytes,
+                tx_bytes: peer.tx_bytes,
+            })
+            .unwrap_or_default();
+        Ok(Some(stats))
+    }
+
+    /// Removes the peer, its endpoint routing and the interface. The routes to the peer
+    /// network go with the interface (they are bound to it), defguard cleans up its fwmark
+    /// rules.
+    // Best effort: every step is attempted, so half configured interfaces (e.g. after
+    // `set_config` failed) are removed too. Failed steps are logged, and the interface
+    // removal error is returned.
+    fn teardown(&self) -> Result<(), WireguardError> {
+        match self.wgapi.read_interface_data() {
+            Ok(host) => {
+                for key in host.peers.keys() {
+                    if let Err(e) = self.wgapi.remove_peer(key) {
+                        warn!("{}", self.interface_error(e));
+                    }
+                }
+            }
+            Err(e) => warn!("{}", self.interface_error(e)),
+        }
+        if let Some(endpoint) = *self.endpoint.lock().unwrap() {
+            if let Err(e) = self.wgapi.remove_endpoint_routing(&endpoint.to_string()) {
+                warn!("{}", self.interface_error(e));
+            }
+        }
+        self.wgapi
+            .remove_interface()
+            .map_err(|e| self.interface_error(e))?;
+        self.owned.remove(&self.interface_name);
+        Ok(())
+    }
+}
 
-        // Peer secret key
-        let peer_key: Key = key.to_bytes().as_slice().try_into().unwrap();
+/// Configuration of the interface for the peer, the api responses are validated here so
+/// malformed ones are rejected before touching the interface.
+fn interface_config(
+    settings: &InterfaceSettings,
+    peer_config: &Peer,
+    endpoint: &Endpoint,
+) -> Result<InterfaceConfiguration, WireguardError> {
+    let peer = wg_peer(settings, peer_config, endpoint)?;
 
-        let mut peer = WgPeer::new(peer_key.clone());
+    let prvkey = peer_config
+        .private_key()
+        .ok_or_else(|| WireguardError::InvalidKey("peer private", "missing".to_string()))?;
+    Key::try_from(prvkey.as_str())
+        .map_err(|e| WireguardError::InvalidKey("peer private", e.to_string()))?;
+    let addresses = peer_config
+        .addresses()
+        .into_iter()
+        .map(IpAddrMask::host)
+        .collect();
 
-        // Your WireGuard server endpoint which client connects to
-        let endpoint: SocketAddr = endpoint
-            .public_url()
-            .as_str()
-            .to_socket_addrs()?
-            .next()
-            .ok_or("Invalid endpoint address")?;
-
-        // Peer endpoint and interval
-        peer.endpoint = Some(endpoint);
-        peer.persistent_keepalive_interval = Some(5);
-
-        peer.allowed_ips
-            .push(IpAddrMask::from_str(&peer_config.network())?);
-
-        // interface configuration
-        let interface_config = InterfaceConfiguration {
-            name: self.interface_name.clone(),
-            prvkey: peer_config.private_key(),
-            addresses: vec![peer_config.address().to_string().parse()?],
-            port: PORT_BASE + self.index as u16,
-            peers: vec![peer],
-            mtu: MTU.into(),
-            fwmark: None,
+    Ok(InterfaceConfiguration {
+        name: settings.name.clone(),
+        prvkey,
+        addresses,
+        port: settings.listen_port,
+        peers: vec![peer],
+        mtu: Some(settings.mtu),
+        fwmark: settings.fwmark,
+    })
+}
+
+/// WireGuard peer for the kittengrid endpoint.
+fn wg_peer(
+    settings: &InterfaceSettings,
+    peer_config: &Peer,
+    endpoint: &Endpoint,
+) -> Result<WgPeer, WireguardError> {
+    let public_key = endpoint.public_key();
+    let peer_key = Key::try_from(public_key.as_str())
+        .map_err(|e| WireguardError::InvalidKey("endpoint public", e.to_string()))?;
+
+    let mut peer = WgPeer::new(peer_key);
+
+    // Your WireGuard server endpoint which client connects to
+    let public_url = endpoint.public_url();
+    let endpoint: SocketAddr = public_url
+        .as_str()
+        .to_socket_addrs()
+        .map_err(|e| WireguardError::UnresolvableEndpoint(public_url.clone(), e.to_string()))?
+        .next()
+        .ok_or_else(|| {
+            WireguardError::UnresolvableEndpoint(public_url.clone(), "no addresses".to_string())
+        })?;
+
+    // Peer endpoint and interval
+    peer.endpoint = Some(endpoint);
+    peer.persistent_keepalive_interval = settings.persistent_keepalive;
+
+    // IPv4 and IPv6 networks are routed alike.
+    for network in peer_config.networks() {
+        peer.allowed_ips.push(
+            IpAddrMask::from_str(&network)
+                .map_err(|_| WireguardError::InvalidAddress("network", network.clone()))?,
+        );
+    }
+
+    Ok(peer)
+}
+
+/// Interfaces created by the agent, recorded in the data dir (an empty file named after
+/// each of them) so only those are removed as stale, and not the ones of other tools that
+/// happen to share the prefix. Nothing is recorded when the data dir is not initialized.
+#[derive(Debug, Clone)]
+pub struct OwnedInterfaces {
+    path: Option<PathBuf>,
+}
+
+impl OwnedInterfaces {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path: Some(path) }
+    }
+
+    pub fn from_data_dir() -> Self {
+        Self {
+            path: crate::data_dir::get_data_dir().interfaces_path().ok(),
+        }
+    }
+
+    fn add(&self, name: &str) {
+        if let Some(path) = &self.path {
+            if let Err(e) = std::fs::write(path.join(name), b"") {
+                warn!("Failed to record interface {}: {}.", name, e);
+            }
+        }
+    }
+
+    fn remove(&self, name: &str) {
+        if let Some(path) = &self.path {
+            match std::fs::remove_file(path.join(name)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    warn!("Failed to forget interface {}: {}.", name, e)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.path
+            .as_ref()
+            .is_some_and(|path| path.join(name).is_file())
+    }
+}
+
+/// Removes the interfaces (and userspace control sockets) left behind by previous runs of
+/// the agent (named `<prefix><index>` and recorded in `owned`), which would collide with
+/// the new ones. Returns the names of the removed ones.
+pub fn remove_stale_interfaces(prefix: &str, owned: &OwnedInterfaces) -> Vec<String> {
+    let mut removed = Vec::new();
+
+    for name in own_entries(Path::new(NET_DEVICES_PATH), prefix, "") {
+        if !owned.contains(&name) {
+            debug!(
+                "Keeping interface {}, it was not created by the agent.",
+                name
+            );
+            continue;
+        }
+        info!("Removing stale interface {}.", name);
+        let result = WGApi::<Kernel>::new(name.clone())
+            .map_err(|e| e.to_string())
+            .and_then(|wgapi| wgapi.remove_interface().map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => removed.push(name),
+            Err(e) => warn!("Failed to remove stale interface {}: {}.", name, e),
+        }
+    }
+
+    // The TUN devices of userspace interfaces go away with the agent, their sockets don't.
+    for name in own_entries(Path::new(USERSPACE_SOCKETS_PATH), prefix, ".sock") {
+        if !owned.contains(&name) {
+            continue;
+        }
+        let path = Path::new(USERSPACE_SOCKETS_PATH).join(format!("{}.sock", name));
+        debug!("Removing stale socket {}.", path.display());
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                if !removed.contains(&name) {
+                    removed.push(name)
+                }
+            }
+            Err(e) => warn!("Failed to remove stale socket {}: {}.", path.display(), e),
+        }
+    }
+
+    for name in &removed {
+        owned.remove(name);
+    }
+    removed
+}
+
+/// Names of the agent interfaces among the entries of `dir` ending in `suffix`.
+fn own_entries(dir: &Path, prefix: &str, suffix: &str) -> Vec<String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| name.strip_suffix(suffix).map(String::from))
+        .filter(|name| is_own_interface(name, prefix))
+        .collect()
+}
+
+fn is_own_interface(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix)
+        .is_some_and(|index| !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn own_interfaces() {
+        assert!(is_own_interface("kgwg0", "kgwg"));
+        assert!(is_own_interface("kgwg12", "kgwg"));
+        assert!(is_own_interface("wg0", "wg"));
+        assert!(!is_own_interface("kgwg", "kgwg"));
+        assert!(!is_own_interface("kgwg0a", "kgwg"));
+        assert!(!is_own_interface("wg0", "kgwg"));
+        assert!(!is_own_interface("eth0", "kgwg"));
+
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["kgwg0.sock", "kgwg1.sock", "wg0.sock", "kgwg2"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let mut entries = own_entries(dir.path(), "kgwg", ".sock");
+        entries.sort();
+        assert_eq!(entries, ["kgwg0", "kgwg1"]);
+        assert!(own_entries(&dir.path().join("missing"), "kgwg", "").is_empty());
+    }
+
+    #[test]
+    fn owned_interfaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let owned = OwnedInterfaces::new(dir.path().to_path_buf());
+        owned.add("kgwg0");
+        owned.add("kgwg1");
+        assert!(owned.contains("kgwg0"));
+        assert!(!owned.contains("wg0"));
+
+        owned.remove("kgwg0");
+        owned.remove("kgwg7");
+        assert!(!owned.contains("kgwg0"));
+        assert!(owned.contains("kgwg1"));
+
+        // Nothing is recorded without a data dir
+        let untracked = OwnedInterfaces { path: None };
+        untracked.add("kgwg0");
+        assert!(!untracked.contains("kgwg0"));
+        assert!(remove_stale_interfaces("kgwg", &untracked).is_empty());
+    }
+
+    // base64 of 32 zero bytes
+    const KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    fn peer(private_key: &str, network: &str) -> Peer {
+        serde_json::from_value(serde_json::json!({
+            "address": "10.0.0.2",
+            "public_key": KEY,
+            "private_key": private_key,
+            "network": network,
+        }))
+        .unwrap()
+    }
+
+    fn endpoint(public_key: &str, public_url: &str) -> Endpoint {
+        serde_json::from_value(serde_json::json!({
+            "public_url": public_url,
+            "address": "10.0.0.1",
+            "public_key": public_key,
+            "network": "10.0.0.0/24",
+        }))
+        .unwrap()
+    }
+
+    fn settings(index: usize) -> InterfaceSettings {
+        InterfaceSettings::new(&NetworkConfig::default(), &peer(KEY, "10.0.0.0/24"), index).unwrap()
+    }
+
+    #[test]
+    fn config() {
+        let config = interface_config(
+            &settings(1),
+            &peer(KEY, "10.0.0.0/24"),
+            &endpoint(KEY, "127.0.0.1:51820"),
+        )
+        .unwrap();
+        assert_eq!(config.name, "kgwg1");
+        assert_eq!(config.port, 51821);
+        assert_eq!(config.mtu, Some(1384));
+        assert_eq!(config.fwmark, None);
+        assert_eq!(config.addresses[0].to_string(), "10.0.0.2/32");
+        let peer = &config.peers[0];
+        assert_eq!(peer.endpoint, Some("127.0.0.1:51820".parse().unwrap()));
+        assert_eq!(peer.allowed_ips[0].to_string(), "10.0.0.0/24");
+        assert_eq!(peer.persistent_keepalive_interval, Some(5));
+    }
+
+    #[test]
+    fn dual_stack_config() {
+        let peer: Peer = serde_json::from_value(serde_json::json!({
+            "address": "10.0.0.2",
+            "addresses": ["fd00::2", "10.0.0.2"],
+            "public_key": KEY,
+            "private_key": KEY,
+            "network": "10.0.0.0/24",
+            "networks": ["fd00::/64"],
+        }))
+        .unwrap();
+        let config = interface_config(&settings(0), &peer, &endpoint(KEY, "[::1]:51820")).unwrap();
+        let to_strings = |masks: &[IpAddrMask]| -> Vec<String> {
+            masks.iter().map(ToString::to_string).collect()
         };
+        assert_eq!(
+            to_strings(&config.addresses),
+            ["10.0.0.2/32", "fd00::2/128"]
+        );
+        let wg_peer = &config.peers[0];
+        assert_eq!(wg_peer.endpoint, Some("[::1]:51820".parse().unwrap()));
+        assert_eq!(
+            to_strings(&wg_peer.allowed_ips),
+            ["10.0.0.0/24", "fd00::/64"]
+        );
 
-        self.wgapi.configure_interface(&interface_config)?;
-        self.wgapi.configure_peer_routing(&interface_config.peers)?;
+        // IPv6 only
+        let peer: Peer = serde_json::from_value(serde_json::json!({
+            "address": "fd00::2",
+            "public_key": KEY,
+            "private_key": KEY,
+            "network": "fd00::/64",
+        }))
+        .unwrap();
+        let config =
+            interface_config(&settings(0), &peer, &endpoint(KEY, "127.0.0.1:51820")).unwrap();
+        assert_eq!(to_strings(&config.addresses), ["fd00::2/128"]);
+        assert_eq!(to_strings(&config.peers[0].allowed_ips), ["fd00::/64"]);
+    }
 
-        Ok(())
+    #[test]
+    fn network_settings() {
+        let config: NetworkConfig = serde_yaml::from_str(
+            "mtu: 1280\nlisten_port: 41000\npersistent_keepalive: 0\nfwmark: 7\ninterface_prefix: kg",
+        )
+        .unwrap();
+        assert_eq!(
+            InterfaceSettings::new(&config, &peer(KEY, "10.0.0.0/24"), 2).unwrap(),
+            InterfaceSettings {
+                name: "kg2".to_string(),
+                listen_port: 41002,
+                mtu: 1280,
+                persistent_keepalive: None,
+                fwmark: Some(7),
+            }
+        );
+
+        // Overridden by the peer
+        let mut overrides = serde_json::json!({
+            "address": "10.0.0.2",
+            "public_key": KEY,
+            "private_key": KEY,
+            "network": "10.0.0.0/24",
+            "mtu": 1420,
+            "listen_port": 42000,
+            "persistent_keepalive": 25,
+        });
+        let settings = InterfaceSettings::new(
+            &config,
+            &serde_json::from_value(overrides.clone()).unwrap(),
+            2,
+        )
+        .unwrap();
+        assert_eq!(settings.listen_port, 42000);
+        assert_eq!(settings.mtu, 1420);
+        assert_eq!(settings.persistent_keepalive, Some(25));
+        overrides["fwmark"] = 9.into();
+        let settings =
+            InterfaceSettings::new(&config, &serde_json::from_value(overrides).unwrap(), 2)
+                .unwrap();
+        assert_eq!(settings.fwmark, Some(9));
+
+        let prefix = |prefix: &str| NetworkConfig {
+            interface_prefix: Some(prefix.to_string()),
+            ..Default::default()
+        };
+        let valid_peer = peer(KEY, "10.0.0.0/24");
+        assert!(matches!(
+            InterfaceSettings::new(&prefix("kittengrid-wg"), &valid_peer, 100),
+            Err(WireguardError::InvalidInterfaceName(..))
+        ));
+        assert!(matches!(
+            InterfaceSettings::new(&prefix("kg/wg"), &valid_peer, 0),
+            Err(WireguardError::InvalidInterfaceName(..))
+        ));
+        for reserved in ["wg", "WG", "tun", "eth"] {
+            assert!(matches!(
+                InterfaceSettings::new(&prefix(reserved), &valid_peer, 0),
+                Err(WireguardError::InvalidInterfaceName(..))
+            ));
+        }
+        let last_port = NetworkConfig {
+            listen_port: Some(u16::MAX),
+            ..Default::default()
+        };
+        assert!(InterfaceSettings::new(&last_port, &valid_peer, 0).is_ok());
+        assert!(matches!(
+            InterfaceSettings::new(&last_port, &valid_peer, 1),
+            Err(WireguardError::NoListenPort(_))
+        ));
+    }
+
+    #[test]
+    fn conflicts() {
+        let net_devices = tempfile::tempdir().unwrap();
+        let free_port = || {
+            std::net::UdpSocket::bind("0.0.0.0:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let interface = |name: &str, listen_port| InterfaceSettings {
+            name: name.to_string(),
+            listen_port,
+            ..settings(0)
+        };
+        let check =
+            |settings: &[InterfaceSettings]| check_conflicts_in(settings, net_devices.path());
+
+        let (port_a, port_b) = (free_port(), free_port());
+        check(&[interface("kgwg0", port_a), interface("kgwg1", port_b)]).unwrap();
+
+        assert!(matches!(
+            check(&[interface("kgwg0", port_a), interface("kgwg0", port_b)]),
+            Err(WireguardError::NameConflict(name)) if name == "kgwg0"
+        ));
+        assert!(matches!(
+            check(&[interface("kgwg0", port_a), interface("kgwg1", port_a)]),
+            Err(WireguardError::PortConflict(port, name)) if port == port_a && name == "kgwg1"
+        ));
+
+        // Taken on the host
+        std::fs::create_dir(net_devices.path().join("kgwg1")).unwrap();
+        assert!(matches!(
+            check(&[interface("kgwg1", port_a)]),
+            Err(WireguardError::NameConflict(_))
+        ));
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let used_port = socket.local_addr().unwrap().port();
+        assert!(matches!(
+            check(&[interface("kgwg0", used_port)]),
+            Err(WireguardError::PortConflict(..))
+        ));
+    }
+
+    #[test]
+    fn malformed_config() {
+        let config = |peer, endpoint| interface_config(&settings(0), &peer, &endpoint);
+        let valid_peer = || peer(KEY, "10.0.0.0/24");
+        let valid_endpoint = || endpoint(KEY, "127.0.0.1:51820");
+
+        assert!(matches!(
+            config(valid_peer(), endpoint("not base64!", "127.0.0.1:51820")),
+            Err(WireguardError::InvalidKey("endpoint public", _))
+        ));
+        // Valid base64, but too short
+        assert!(matches!(
+            config(valid_peer(), endpoint("AAAA", "127.0.0.1:51820")),
+            Err(WireguardError::InvalidKey("endpoint public", _))
+        ));
+        assert!(matches!(
+            config(peer("", "10.0.0.0/24"), valid_endpoint()),
+            Err(WireguardError::InvalidKey("peer private", _))
+        ));
+        // Not sent by the api, nor set by the agent
+        let no_private_key = serde_json::from_value(serde_json::json!({
+            "address": "10.0.0.2",
+            "public_key": KEY,
+            "network": "10.0.0.0/24",
+        }))
+        .unwrap();
+        assert!(matches!(
+            config(no_private_key, valid_endpoint()),
+            Err(WireguardError::InvalidKey("peer private", _))
+        ));
+        // No port
+        assert!(matches!(
+            config(valid_peer(), endpoint(KEY, "127.0.0.1")),
+            Err(WireguardError::UnresolvableEndpoint(..))
+        ));
+        assert!(matches!(
+            config(peer(KEY, "10.0.0.0/33"), valid_endpoint()),
+            Err(WireguardError::InvalidAddress("network", _))
+        ));
+        assert!(matches!(
+            config(peer(KEY, "not a network"), valid_endpoint()),
+            Err(WireguardError::InvalidAddress("network", _))
+        ));
+    }
+
+    #[test]
+    fn interface_errors() {
+        let error = |message: &str| {
+            WireguardError::interface(
+                "kgwg0",
+                WireguardInterfaceError::NetlinkError(message.to_string()),
+            )
+        };
+        assert!(matches!(
+            error("Operation not permitted (os error 1)"),
+            WireguardError::PermissionDenied(..)
+        ));
+        assert!(matches!(
+            error("File exists (os error 17)"),
+            WireguardError::AddressConflict(..)
+        ));
+        assert!(matches!(
+            error("No such device"),
+            WireguardError::Interface(..)
+        ));
+        assert!(matches!(
+            WireguardError::interface(
+                "kgwg0",
+                std::io::Error::from(std::io::ErrorKind::PermissionDenied).into()
+            ),
+            WireguardError::PermissionDenied(..)
+        ));
     }
 }
@@ -0,0 +1,134 @@
+use super::WireguardError;
+use base64::{engine::general_purpose, Engine as _};
+use rand::RngExt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// WireGuard keypair of the agent, generated locally so the api only gets the public key.
+pub struct KeyPair {
+    secret: StaticSecret,
+}
+
+// The private key is never printed.
+impl std::fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "KeyPair({})", self.public_key())
+    }
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        rand::rng().fill(&mut bytes);
+        Self {
+            secret: StaticSecret::from(bytes),
+        }
+    }
+
+    /// Loads the keypair from `path`, generating (and saving) a new one if it does not exist.
+    /// The file holds the base64 private key and is only readable by the agent user.
+    pub fn load_or_generate(path: &Path) -> Result<Self, WireguardError> {
+        let key_file_error = |e: io::Error| WireguardError::KeyFile(path.to_path_buf(), e);
+
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                let mode = fs::metadata(path)
+                    .map_err(key_file_error)?
+                    .permissions()
+                    .mode();
+                if mode & 0o077 != 0 {
+                    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+                        .map_err(key_file_error)?;
+                }
+                Self::from_private_key(contents.trim())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let keypair = Self::generate();
+                let mut file = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)
+                    .map_err(key_file_error)?;
+                writeln!(file, "{}", keypair.private_key()).map_err(key_file_error)?;
+                Ok(keypair)
+            }
+            Err(e) => Err(key_file_error(e)),
+        }
+    }
+
+    pub fn from_private_key(private_key: &str) -> Result<Self, WireguardError> {
+        let invalid = |reason: String| WireguardError::InvalidKey("agent private", reason);
+        let bytes: [u8; 32] = general_purpose::STANDARD
+            .decode(private_key)
+            .map_err(|e| invalid(e.to_string()))?
+            .try_into()
+            .map_err(|bytes: Vec<u8>| invalid(format!("{} bytes long", bytes.len())))?;
+        Ok(Self {
+            secret: StaticSecret::from(bytes),
+        })
+    }
+
+    /// Base64 encoded, as WireGuard (and the api) expects it.
+    pub fn public_key(&self) -> String {
+        general_purpose::STANDARD.encode(PublicKey::from(&self.secret).as_bytes())
+    }
+
+    pub fn private_key(&self) -> String {
+        general_purpose::STANDARD.encode(self.secret.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generate() {
+        let keypair = KeyPair::generate();
+        assert_ne!(keypair.private_key(), KeyPair::generate().private_key());
+
+        // WireGuard derives the same public key
+        let private_key =
+            defguard_wireguard_rs::key::Key::try_from(keypair.private_key().as_str()).unwrap();
+        assert_eq!(private_key.public_key().to_string(), keypair.public_key());
+        assert!(!format!("{:?}", keypair).contains(&keypair.private_key()));
+    }
+
+    #[test]
+    fn load_or_generate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wireguard.key");
+
+        let keypair = KeyPair::load_or_generate(&path).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            KeyPair::load_or_generate(&path).unwrap().public_key(),
+            keypair.public_key()
+        );
+
+        // Permissions are fixed
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        KeyPair::load_or_generate(&path).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        fs::write(&path, "not a key").unwrap();
+        assert!(matches!(
+            KeyPair::load_or_generate(&path),
+            Err(WireguardError::InvalidKey(..))
+        ));
+        assert!(matches!(
+            KeyPair::load_or_generate(&dir.path().join("missing/wireguard.key")),
+            Err(WireguardError::KeyFile(..))
+        ));
+    }
+}